@@ -0,0 +1,248 @@
+// #[derive(Lattice)] for quartz::lattice::Lattice.
+//
+// Structs become product lattices: each field is joined with its counterpart,
+// the struct grew if any field grew, and a conflict in any field is a conflict.
+//
+// Enums become one of two sum lattices, chosen with #[lattice(...)]:
+// #[lattice(tagged)] (the default) joins payloads of the same variant field-wise,
+// and treats differing variants as a conflict, just as OnceCell treats differing writes.
+// #[lattice(lexicographic)] orders variants by declaration, later variants sitting
+// above earlier ones; joining a later variant replaces the value outright (requiring Clone),
+// and payloads of the same variant are joined field-wise.
+//
+// Every field must itself implement Lattice, and the type must be Clone:
+// the join works on a copy, which only replaces self once every field has joined,
+// so a conflict part way leaves self as it was.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Fields, Ident, Index,
+};
+
+#[proc_macro_derive(Lattice, attributes(lattice))]
+pub fn derive_lattice(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(ts) => ts.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+enum SumKind {
+    Tagged,
+    Lexicographic,
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream2, Error> {
+    let name = input.ident.clone();
+
+    let kind = sum_kind(&input.attrs)?;
+    let body = match &input.data {
+        Data::Struct(data) => join_struct(&data.fields),
+        Data::Enum(data) => {
+            if data.variants.is_empty() {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "Lattice cannot be derived for an enum without variants",
+                ));
+            }
+            match kind {
+                SumKind::Tagged => join_tagged(&name, data),
+                SumKind::Lexicographic => join_lexicographic(&name, data),
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new(
+                Span::call_site(),
+                "Lattice cannot be derived for a union",
+            ))
+        }
+    };
+
+    // Every type parameter must be a lattice, and the type cloneable.
+    let params: Vec<Ident> = input
+        .generics
+        .type_params()
+        .map(|p| p.ident.clone())
+        .collect();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let self_ty: syn::Type = parse_quote!(#name #ty_generics);
+    let where_clause = input.generics.make_where_clause();
+    for p in params.iter() {
+        where_clause
+            .predicates
+            .push(parse_quote!(#p: ::quartz::lattice::Lattice));
+    }
+    where_clause
+        .predicates
+        .push(parse_quote!(#self_ty: ::std::clone::Clone));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::quartz::lattice::Lattice for #name #ty_generics #where_clause {
+            fn join(
+                &mut self,
+                other: &Self,
+            ) -> ::std::result::Result<bool, ::quartz::lattice::LatticeError> {
+                let join = |this: &mut Self| -> ::std::result::Result<bool, ::quartz::lattice::LatticeError> {
+                    #body
+                };
+                let mut next = ::std::clone::Clone::clone(self);
+                let grew = join(&mut next)?;
+                *self = next;
+                ::std::result::Result::Ok(grew)
+            }
+        }
+    })
+}
+
+fn sum_kind(attrs: &[Attribute]) -> Result<SumKind, Error> {
+    let mut kind = SumKind::Tagged;
+    for attr in attrs.iter().filter(|a| a.path().is_ident("lattice")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tagged") {
+                kind = SumKind::Tagged;
+                Ok(())
+            } else if meta.path.is_ident("lexicographic") {
+                kind = SumKind::Lexicographic;
+                Ok(())
+            } else {
+                Err(meta.error("expected `tagged` or `lexicographic`"))
+            }
+        })?;
+    }
+    Ok(kind)
+}
+
+// Field names on each side of a pattern, a_0.. and b_0...
+fn bindings(fields: &Fields) -> (Vec<Ident>, Vec<Ident>) {
+    let n = fields.iter().count();
+    (
+        (0..n).map(|i| format_ident!("a_{}", i)).collect(),
+        (0..n).map(|i| format_ident!("b_{}", i)).collect(),
+    )
+}
+
+// A pattern binding every field of a variant, e.g. Name::V { x: a_0, y: a_1 }.
+fn pattern(path: TokenStream2, fields: &Fields, names: &[Ident]) -> TokenStream2 {
+    match fields {
+        Fields::Named(named) => {
+            let members = named.named.iter().map(|f| f.ident.clone().unwrap());
+            quote!(#path { #(#members: #names),* })
+        }
+        Fields::Unnamed(_) => quote!(#path ( #(#names),* )),
+        Fields::Unit => quote!(#path),
+    }
+}
+
+fn join_all(a: &[Ident], b: &[Ident]) -> TokenStream2 {
+    quote! {
+        #[allow(unused_mut)]
+        let mut grew = false;
+        #(grew |= ::quartz::lattice::Lattice::join(#a, #b)?;)*
+        ::std::result::Result::Ok(grew)
+    }
+}
+
+fn join_struct(fields: &Fields) -> TokenStream2 {
+    let members: Vec<TokenStream2> = match fields {
+        Fields::Named(named) => named
+            .named
+            .iter()
+            .map(|f| {
+                let id = f.ident.clone().unwrap();
+                quote!(#id)
+            })
+            .collect(),
+        _ => (0..fields.iter().count())
+            .map(|i| {
+                let idx = Index::from(i);
+                quote!(#idx)
+            })
+            .collect(),
+    };
+
+    quote! {
+        #[allow(unused_mut)]
+        let mut grew = false;
+        #(grew |= ::quartz::lattice::Lattice::join(&mut this.#members, &other.#members)?;)*
+        ::std::result::Result::Ok(grew)
+    }
+}
+
+// Arms joining two values of the same variant.
+fn same_variant_arms(name: &Ident, data: &syn::DataEnum) -> Vec<TokenStream2> {
+    data.variants
+        .iter()
+        .map(|v| {
+            let vname = &v.ident;
+            let (a, b) = bindings(&v.fields);
+            let pa = pattern(quote!(#name::#vname), &v.fields, &a);
+            let pb = pattern(quote!(#name::#vname), &v.fields, &b);
+            let body = join_all(&a, &b);
+            quote!((#pa, #pb) => { #body })
+        })
+        .collect()
+}
+
+fn join_tagged(name: &Ident, data: &syn::DataEnum) -> TokenStream2 {
+    let arms = same_variant_arms(name, data);
+    let fallback = match data.variants.len() {
+        1 => quote!(),
+        _ => quote!(_ => ::std::result::Result::Err(::quartz::lattice::LatticeError::Conflict),),
+    };
+
+    quote! {
+        match (this, other) {
+            #(#arms)*
+            #fallback
+        }
+    }
+}
+
+fn join_lexicographic(name: &Ident, data: &syn::DataEnum) -> TokenStream2 {
+    let arms = same_variant_arms(name, data);
+    let ranks: Vec<TokenStream2> = data
+        .variants
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let vname = &v.ident;
+            let pat = match &v.fields {
+                Fields::Named(_) => quote!(#name::#vname { .. }),
+                Fields::Unnamed(_) => quote!(#name::#vname(..)),
+                Fields::Unit => quote!(#name::#vname),
+            };
+            quote!(#pat => #i,)
+        })
+        .collect();
+    let fallback = match data.variants.len() {
+        1 => quote!(),
+        _ => quote!(_ => ::std::unreachable!(),),
+    };
+
+    quote! {
+        let rank = |v: &Self| -> usize {
+            match v {
+                #(#ranks)*
+            }
+        };
+        match rank(this).cmp(&rank(other)) {
+            ::std::cmp::Ordering::Less => {
+                *this = ::std::clone::Clone::clone(other);
+                ::std::result::Result::Ok(true)
+            }
+            ::std::cmp::Ordering::Greater => ::std::result::Result::Ok(false),
+            ::std::cmp::Ordering::Equal => {
+                match (this, other) {
+                    #(#arms)*
+                    #fallback
+                }
+            }
+        }
+    }
+}
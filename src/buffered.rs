@@ -0,0 +1,515 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::monitor::{Monitor, Wakers};
+use crate::select::{self, Attempt, Selectable, Token};
+
+// Buffered channels, for a producer that should not wait on its consumer.
+// Any number of senders and recievers share one queue, bounded or not. A bounded send blocks
+// while the queue is full, and a recv blocks while it is empty.
+// Once every reciever is dropped sends fail, and once every sender is dropped recievers
+// drain what is left and then fail, both with Disconnected.
+// Space or a value in the queue is enough for an arm to be ready, so selects never leave offers.
+// A queue with no room could never be sent into, so bounded(0) is refused with ZeroCapacity:
+// a Chan is the channel for a rendezvous.
+pub fn bounded<T>(cap: usize) -> Result<(BufSender<T>, BufReceiver<T>), BufError> {
+    match cap {
+        0 => Err(BufError::ZeroCapacity),
+        _ => Ok(channel(Some(cap))),
+    }
+}
+
+pub fn unbounded<T>() -> (BufSender<T>, BufReceiver<T>) {
+    channel(None)
+}
+
+fn channel<T>(cap: Option<usize>) -> (BufSender<T>, BufReceiver<T>) {
+    let inner = Arc::new(Monitor::new(BufState {
+        queue: VecDeque::new(),
+        cap,
+        senders: 1,
+        receivers: 1,
+        wakers: Vec::new(),
+    }));
+    (BufSender(inner.clone()), BufReceiver(inner))
+}
+
+type BufInner<T> = Monitor<BufState<T>>;
+
+struct BufState<T> {
+    queue: VecDeque<T>,
+    cap: Option<usize>,
+    // Live handles on each side.
+    senders: usize,
+    receivers: usize,
+    wakers: Vec<Waker>,
+}
+
+impl<T> Wakers for BufState<T> {
+    fn wakers(&mut self) -> &mut Vec<Waker> {
+        &mut self.wakers
+    }
+}
+
+impl<T> BufState<T> {
+    fn full(&self) -> bool {
+        self.cap.is_some_and(|c| self.queue.len() >= c)
+    }
+}
+
+impl<T> BufInner<T> {
+    fn try_push(&self, st: &mut BufState<T>, t: T) -> Result<(), BufSendError<T>> {
+        if st.receivers == 0 {
+            return Err(BufSendError(BufError::Disconnected, t));
+        }
+        if st.full() {
+            return Err(BufSendError(BufError::Full, t));
+        }
+        st.queue.push_back(t);
+        self.notify(st);
+        Ok(())
+    }
+
+    fn try_pop(&self, st: &mut BufState<T>) -> Result<T, BufError> {
+        match st.queue.pop_front() {
+            Some(t) => {
+                self.notify(st);
+                Ok(t)
+            }
+            None if st.senders == 0 => Err(BufError::Disconnected),
+            None => Err(BufError::Empty),
+        }
+    }
+
+    fn send_until(&self, t: T, deadline: Option<Instant>) -> Result<(), BufSendError<T>> {
+        let mut st = self.lock();
+        let mut t = t;
+        let mut waiting = true;
+        loop {
+            match self.try_push(&mut st, t) {
+                Err(BufSendError(BufError::Full, x)) if waiting => t = x,
+                Err(BufSendError(BufError::Full, x)) => {
+                    return Err(BufSendError(BufError::Timeout, x))
+                }
+                res => return res,
+            }
+            let (s, w) = self.wait(st, deadline);
+            st = s;
+            waiting = w;
+        }
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, BufError> {
+        let mut st = self.lock();
+        let mut waiting = true;
+        loop {
+            match self.try_pop(&mut st) {
+                Err(BufError::Empty) if waiting => {}
+                Err(BufError::Empty) => return Err(BufError::Timeout),
+                res => return res,
+            }
+            let (s, w) = self.wait(st, deadline);
+            st = s;
+            waiting = w;
+        }
+    }
+}
+
+pub struct BufSender<T>(Arc<BufInner<T>>);
+
+impl<T> Clone for BufSender<T> {
+    fn clone(&self) -> BufSender<T> {
+        self.0.lock().senders += 1;
+        BufSender(self.0.clone())
+    }
+}
+
+impl<T> Drop for BufSender<T> {
+    fn drop(&mut self) {
+        let mut st = self.0.lock();
+        st.senders -= 1;
+        if st.senders == 0 {
+            self.0.notify(&mut st);
+        }
+    }
+}
+
+impl<T> BufSender<T> {
+    // Block while the queue is full.
+    pub fn send(&self, t: T) -> Result<(), BufSendError<T>> {
+        self.0.send_until(t, None)
+    }
+
+    pub fn send_timeout(&self, t: T, timeout: Duration) -> Result<(), BufSendError<T>> {
+        self.0.send_until(t, Some(Instant::now() + timeout))
+    }
+
+    pub fn send_deadline(&self, t: T, deadline: Instant) -> Result<(), BufSendError<T>> {
+        self.0.send_until(t, Some(deadline))
+    }
+
+    pub fn try_send(&self, t: T) -> Result<(), BufSendError<T>> {
+        self.0.try_push(&mut self.0.lock(), t)
+    }
+
+    pub fn send_async(&self, t: T) -> BufSendFuture<'_, T> {
+        BufSendFuture {
+            inner: &self.0,
+            val: Some(t),
+        }
+    }
+
+    // A send for a Select to choose.
+    pub fn select_send(&self, t: T) -> BufSelectSend<'_, T> {
+        BufSelectSend {
+            inner: &self.0,
+            val: Some(t),
+        }
+    }
+}
+
+pub struct BufReceiver<T>(Arc<BufInner<T>>);
+
+impl<T> Clone for BufReceiver<T> {
+    fn clone(&self) -> BufReceiver<T> {
+        self.0.lock().receivers += 1;
+        BufReceiver(self.0.clone())
+    }
+}
+
+impl<T> Drop for BufReceiver<T> {
+    fn drop(&mut self) {
+        let mut st = self.0.lock();
+        st.receivers -= 1;
+        if st.receivers == 0 {
+            self.0.notify(&mut st);
+        }
+    }
+}
+
+impl<T> BufReceiver<T> {
+    // Block while the queue is empty.
+    pub fn recv(&self) -> Result<T, BufError> {
+        self.0.recv_until(None)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, BufError> {
+        self.0.recv_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<T, BufError> {
+        self.0.recv_until(Some(deadline))
+    }
+
+    pub fn try_recv(&self) -> Result<T, BufError> {
+        self.0.try_pop(&mut self.0.lock())
+    }
+
+    pub fn recv_async(&self) -> BufRecvFuture<'_, T> {
+        BufRecvFuture { inner: &self.0 }
+    }
+
+    // A recv for a Select to choose.
+    pub fn select_recv(&self) -> BufSelectRecv<'_, T> {
+        BufSelectRecv { inner: &self.0 }
+    }
+
+    // Recieve until every sender is gone and the queue drained.
+    pub fn iter(&self) -> BufIter<'_, T> {
+        BufIter(self)
+    }
+}
+
+pub struct BufIter<'a, T>(&'a BufReceiver<T>);
+
+impl<'a, T> Iterator for BufIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.0.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a BufReceiver<T> {
+    type Item = T;
+    type IntoIter = BufIter<'a, T>;
+
+    fn into_iter(self) -> BufIter<'a, T> {
+        self.iter()
+    }
+}
+
+// The future of send_async.
+pub struct BufSendFuture<'a, T> {
+    inner: &'a BufInner<T>,
+    val: Option<T>,
+}
+
+// The value is moved, never pinned.
+impl<'a, T> Unpin for BufSendFuture<'a, T> {}
+
+impl<'a, T> Future for BufSendFuture<'a, T> {
+    type Output = Result<(), BufSendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let t = match this.val.take() {
+            Some(t) => t,
+            None => panic!("BufSendFuture polled after completion"),
+        };
+        let mut st = this.inner.lock();
+        match this.inner.try_push(&mut st, t) {
+            Err(BufSendError(BufError::Full, t)) => {
+                this.val = Some(t);
+                this.inner.register(&mut st, cx.waker());
+                Poll::Pending
+            }
+            res => Poll::Ready(res),
+        }
+    }
+}
+
+// The future of recv_async.
+pub struct BufRecvFuture<'a, T> {
+    inner: &'a BufInner<T>,
+}
+
+impl<'a, T> Future for BufRecvFuture<'a, T> {
+    type Output = Result<T, BufError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut st = self.inner.lock();
+        match self.inner.try_pop(&mut st) {
+            Err(BufError::Empty) => {
+                self.inner.register(&mut st, cx.waker());
+                Poll::Pending
+            }
+            res => Poll::Ready(res),
+        }
+    }
+}
+
+// The send arm of a Select.
+pub struct BufSelectSend<'a, T> {
+    inner: &'a BufInner<T>,
+    val: Option<T>,
+}
+
+impl<'a, T> select::private::Sealed for BufSelectSend<'a, T> {}
+
+impl<'a, T> Selectable for BufSelectSend<'a, T> {
+    type Output = Result<(), BufSendError<T>>;
+
+    fn attempt(&mut self, token: &Token, _offer: bool) -> Attempt<Self::Output> {
+        let mut st = self.inner.lock();
+        if st.receivers > 0 && st.full() {
+            self.inner.register(&mut st, &token.waker());
+            return Attempt::Pending;
+        }
+        if !token.claim() {
+            return Attempt::Lost;
+        }
+        match self.val.take() {
+            Some(t) => Attempt::Ready(self.inner.try_push(&mut st, t)),
+            None => unreachable!("A select completes an arm once"),
+        }
+    }
+
+    // Only the select itself claims an arm with no offers.
+    fn finish(&mut self, _token: &Token) -> Self::Output {
+        unreachable!("No peer claims a buffered arm")
+    }
+
+    fn withdraw(&mut self, _token: &Token) {}
+
+    fn poke(&self) {}
+}
+
+// The recv arm of a Select.
+pub struct BufSelectRecv<'a, T> {
+    inner: &'a BufInner<T>,
+}
+
+impl<'a, T> select::private::Sealed for BufSelectRecv<'a, T> {}
+
+impl<'a, T> Selectable for BufSelectRecv<'a, T> {
+    type Output = Result<T, BufError>;
+
+    fn attempt(&mut self, token: &Token, _offer: bool) -> Attempt<Self::Output> {
+        let mut st = self.inner.lock();
+        if st.senders > 0 && st.queue.is_empty() {
+            self.inner.register(&mut st, &token.waker());
+            return Attempt::Pending;
+        }
+        match token.claim() {
+            true => Attempt::Ready(self.inner.try_pop(&mut st)),
+            false => Attempt::Lost,
+        }
+    }
+
+    fn finish(&mut self, _token: &Token) -> Self::Output {
+        unreachable!("No peer claims a buffered arm")
+    }
+
+    fn withdraw(&mut self, _token: &Token) {}
+
+    fn poke(&self) {}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BufError {
+    Disconnected,
+    Timeout,
+    Full,
+    Empty,
+    ZeroCapacity,
+}
+
+impl fmt::Display for BufError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BufError::Disconnected => write!(f, "Every handle on the other side was dropped"),
+            BufError::Timeout => write!(f, "The deadline passed first"),
+            BufError::Full => write!(f, "The channel is full"),
+            BufError::Empty => write!(f, "The channel is empty"),
+            BufError::ZeroCapacity => write!(f, "A bounded channel needs room for a value"),
+        }
+    }
+}
+
+impl Error for BufError {
+    fn description(&self) -> &str {
+        match self {
+            BufError::Disconnected => "Every handle on the other side was dropped",
+            BufError::Timeout => "The deadline passed first",
+            BufError::Full => "The channel is full",
+            BufError::Empty => "The channel is empty",
+            BufError::ZeroCapacity => "A bounded channel needs room for a value",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+// A failed send, handing back the value.
+#[derive(Debug)]
+pub struct BufSendError<T>(pub BufError, pub T);
+
+impl<T> BufSendError<T> {
+    pub fn into_inner(self) -> T {
+        self.1
+    }
+}
+
+impl<T> fmt::Display for BufSendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Debug> Error for BufSendError<T> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::select::Select;
+    use crate::test_util::block_on;
+    use std::thread;
+
+    #[test]
+    fn test_buffered() {
+        let (tx, rx) = bounded::<u32>(2).unwrap();
+        tx.send(1).unwrap();
+        tx.try_send(2).unwrap();
+        match tx.try_send(3) {
+            Err(BufSendError(BufError::Full, 3)) => println!(),
+            _ => panic!("Sent past capacity"),
+        }
+        match tx.send_timeout(3, Duration::from_millis(5)) {
+            Err(BufSendError(BufError::Timeout, 3)) => println!(),
+            _ => panic!("Sent past capacity"),
+        }
+
+        // A blocked sender resumes once there is room.
+        let tx2 = tx.clone();
+        let h = thread::spawn(move || tx2.send(3));
+        assert_eq!(Ok(1), rx.recv());
+        h.join().expect("Failed to Join Threads!").unwrap();
+
+        // Recievers drain after the senders are gone.
+        drop(tx);
+        assert_eq!(vec![2, 3], rx.iter().collect::<Vec<u32>>());
+        assert_eq!(Err(BufError::Disconnected), rx.try_recv());
+
+        assert_eq!(Some(BufError::ZeroCapacity), bounded::<u32>(0).err());
+
+        let (tx, rx) = unbounded::<u32>();
+        for i in 0..1000 {
+            tx.send(i).unwrap();
+        }
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let rx = rx.clone();
+                thread::spawn(move || rx.iter().map(u64::from).sum::<u64>())
+            })
+            .collect();
+        drop(rx);
+        drop(tx);
+        let total: u64 = workers
+            .into_iter()
+            .map(|w| w.join().expect("Failed to Join Threads!"))
+            .sum();
+        assert_eq!(499500, total);
+    }
+
+    #[test]
+    fn test_buffered_async_select() {
+        let (tx, rx) = bounded::<u32>(1).unwrap();
+        let h = thread::spawn(move || {
+            for i in 0..10 {
+                block_on(tx.send_async(i)).unwrap();
+            }
+        });
+        let got: Vec<u32> = (0..10)
+            .map(|_| block_on(rx.recv_async()).unwrap())
+            .collect();
+        assert_eq!((0..10).collect::<Vec<u32>>(), got);
+        h.join().expect("Failed to Join Threads!");
+        assert_eq!(Err(BufError::Disconnected), block_on(rx.recv_async()));
+
+        // A select waits on a full and an empty channel until one moves.
+        let (atx, arx) = bounded::<u32>(1).unwrap();
+        let (btx, brx) = unbounded::<u32>();
+        atx.send(0).unwrap();
+        let h = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(5));
+            btx.send(7).unwrap();
+            btx
+        });
+        let got = crate::select! {
+            send(atx, 1) -> r => { r.unwrap(); 1 },
+            recv(brx) -> r => r.unwrap(),
+        };
+        assert_eq!(7, got);
+        assert_eq!(Ok(0), arx.try_recv());
+        drop(h.join().expect("Failed to Join Threads!"));
+
+        let got = Select::new()
+            .arm(brx.select_recv(), |r| r.err())
+            .timeout(Duration::from_secs(1), || None)
+            .wait();
+        assert_eq!(Some(BufError::Disconnected), got);
+    }
+}
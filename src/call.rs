@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::chan::{Chan, ChanError, ChanSendError};
+use crate::ping::{Ping, PingSender};
+
+// Request and response between threads, the reply-channel pattern written once.
+// Each call sends its request over a Chan, along with the sending half of a fresh Ping
+// for the answer, and waits on the other half. The server answers through that half,
+// so a Responder dropped unanswered disconnects the Ping, and the caller is not left waiting.
+pub struct Call<Req, Resp>(Chan<(Req, PingSender<Resp>)>);
+
+impl<Req, Resp> Clone for Call<Req, Resp> {
+    fn clone(&self) -> Call<Req, Resp> {
+        Call(self.0.clone())
+    }
+}
+
+impl<Req, Resp> Default for Call<Req, Resp> {
+    fn default() -> Call<Req, Resp> {
+        Call::new()
+    }
+}
+
+impl<Req, Resp> Call<Req, Resp> {
+    pub fn new() -> Call<Req, Resp> {
+        Call(Chan::new())
+    }
+
+    // Block until a server accepts the request and answers it.
+    pub fn call(&self, req: Req) -> Result<Resp, CallError> {
+        let (tx, mut rx) = Ping::pair();
+        if let Err(ChanSendError(err, _)) = self.0.send((req, tx)) {
+            return Err(err.into());
+        }
+        rx.recv().map_err(|_| CallError::NoReply)
+    }
+
+    // Block until a caller arrives, or the Call is closed.
+    pub fn accept(&self) -> Result<(Req, Responder<Resp>), CallError> {
+        let (req, tx) = self.0.recv()?;
+        Ok((req, Responder(tx)))
+    }
+
+    // Refuse later calls, and wake waiting servers and callers with Closed.
+    pub fn close(&self) {
+        self.0.close()
+    }
+}
+
+// The answer owed to one caller. Dropping it unanswered fails the call with NoReply.
+pub struct Responder<Resp>(PingSender<Resp>);
+
+impl<Resp> Responder<Resp> {
+    // A caller that has since gone away simply never sees the response.
+    pub fn respond(mut self, resp: Resp) {
+        let _ = self.0.send(resp);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CallError {
+    Closed,
+    NoReply,
+}
+
+impl From<ChanError> for CallError {
+    // A Chan only fails a blocking send or recv once closed.
+    fn from(_: ChanError) -> CallError {
+        CallError::Closed
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::Closed => write!(f, "The Call is closed"),
+            CallError::NoReply => write!(f, "The server dropped the request without responding"),
+        }
+    }
+}
+
+impl Error for CallError {
+    fn description(&self) -> &str {
+        match self {
+            CallError::Closed => "The Call is closed",
+            CallError::NoReply => "The server dropped the request without responding",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_call() {
+        let c = Call::<u32, u32>::new();
+        let servers: Vec<_> = (0..2)
+            .map(|_| {
+                let c = c.clone();
+                thread::spawn(move || {
+                    let mut served = 0;
+                    while let Ok((req, r)) = c.accept() {
+                        served += 1;
+                        // Odd requests go unanswered.
+                        if req % 2 == 0 {
+                            r.respond(req * 10);
+                        }
+                    }
+                    served
+                })
+            })
+            .collect();
+
+        let clients: Vec<_> = (0..4)
+            .map(|i| {
+                let c = c.clone();
+                thread::spawn(move || (i, c.call(i)))
+            })
+            .collect();
+        for h in clients {
+            match h.join().expect("Failed to Join Threads!") {
+                (i, Ok(x)) if i % 2 == 0 => assert_eq!(i * 10, x),
+                (i, Err(CallError::NoReply)) if i % 2 == 1 => println!(),
+                (i, res) => panic!("Call {} got {:?}", i, res),
+            }
+        }
+
+        c.close();
+        let served: u32 = servers
+            .into_iter()
+            .map(|h| h.join().expect("Failed to Join Threads!"))
+            .sum();
+        assert_eq!(4, served);
+        assert_eq!(Err(CallError::Closed), c.call(5));
+    }
+}
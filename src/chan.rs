@@ -0,0 +1,594 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use crate::monitor::{Monitor, Wakers};
+use crate::select::{self, Attempt, Claim, Selectable, Token};
+
+// A reusable rendezvous channel, as Go's unbuffered channels: any number of senders and
+// recievers, and every send meets exactly one recv.
+// Parties that find no peer wait in a queue, as an entry a peer completes by removing it.
+// A sender completing a waiting reciever hands it the value, and a reciever completing
+// a waiting sender takes its value; either way the waiting party learns of it through done.
+// Selects queue entries too, carrying their Token, and a peer must claim it to complete them,
+// just as with the offers on a Ping.
+pub struct Chan<T>(Arc<ChanInner<T>>);
+
+impl<T> Clone for Chan<T> {
+    fn clone(&self) -> Chan<T> {
+        Chan(self.0.clone())
+    }
+}
+
+impl<T> Default for Chan<T> {
+    fn default() -> Chan<T> {
+        Chan::new()
+    }
+}
+
+type ChanInner<T> = Monitor<ChanState<T>>;
+
+struct ChanState<T> {
+    closed: bool,
+    next_id: u64,
+    // Waiting senders, holding their values, and waiting recievers.
+    senders: VecDeque<Entry<T>>,
+    receivers: VecDeque<Entry<T>>,
+    // Completed entries, with the value for a reciever.
+    done: HashMap<u64, Option<T>>,
+    wakers: Vec<Waker>,
+}
+
+impl<T> Wakers for ChanState<T> {
+    fn wakers(&mut self) -> &mut Vec<Waker> {
+        &mut self.wakers
+    }
+}
+
+struct Entry<T> {
+    id: u64,
+    token: Option<Token>,
+    val: Option<T>,
+}
+
+impl<T> Chan<T> {
+    pub fn new() -> Chan<T> {
+        Chan(Arc::new(Monitor::new(ChanState {
+            closed: false,
+            next_id: 0,
+            senders: VecDeque::new(),
+            receivers: VecDeque::new(),
+            done: HashMap::new(),
+            wakers: Vec::new(),
+        })))
+    }
+
+    // Block until a reciever takes the value.
+    pub fn send(&self, t: T) -> Result<(), ChanSendError<T>> {
+        self.0.send_until(t, None)
+    }
+
+    pub fn send_timeout(&self, t: T, timeout: Duration) -> Result<(), ChanSendError<T>> {
+        self.0.send_until(t, Some(Instant::now() + timeout))
+    }
+
+    // Send only to a reciever already waiting.
+    pub fn try_send(&self, t: T) -> Result<(), ChanSendError<T>> {
+        let mut st = self.0.lock();
+        if st.closed {
+            return Err(ChanSendError(ChanError::Closed, t));
+        }
+        match st.pop_receiver(None) {
+            Ok(Some(e)) => {
+                st.done.insert(e.id, Some(t));
+                self.0.notify(&mut st);
+                Ok(())
+            }
+            _ => Err(ChanSendError(ChanError::NotReady, t)),
+        }
+    }
+
+    // Block until a sender arrives, or the channel is closed.
+    pub fn recv(&self) -> Result<T, ChanError> {
+        self.0.recv_until(None)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, ChanError> {
+        self.0.recv_until(Some(Instant::now() + timeout))
+    }
+
+    // Recv only from a sender already waiting.
+    pub fn try_recv(&self) -> Result<T, ChanError> {
+        let mut st = self.0.lock();
+        match st.pop_sender(None) {
+            Ok(Some(e)) => {
+                st.done.insert(e.id, None);
+                self.0.notify(&mut st);
+                Ok(e.val.expect("A waiting sender holds its value"))
+            }
+            _ => match st.closed {
+                true => Err(ChanError::Closed),
+                false => Err(ChanError::NotReady),
+            },
+        }
+    }
+
+    // Refuse every later send, and wake everyone waiting with Closed.
+    // Waiting senders get their values back.
+    pub fn close(&self) {
+        let mut st = self.0.lock();
+        st.closed = true;
+        self.0.notify(&mut st);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.lock().closed
+    }
+
+    // Recieve until the channel is closed.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter(self)
+    }
+
+    // A send for a Select to choose.
+    pub fn select_send(&self, t: T) -> ChanSelectSend<'_, T> {
+        ChanSelectSend {
+            chan: &self.0,
+            val: Some(t),
+            id: None,
+        }
+    }
+
+    // A recv for a Select to choose.
+    pub fn select_recv(&self) -> ChanSelectRecv<'_, T> {
+        ChanSelectRecv {
+            chan: &self.0,
+            id: None,
+        }
+    }
+}
+
+impl<T> ChanInner<T> {
+    fn send_until(&self, t: T, deadline: Option<Instant>) -> Result<(), ChanSendError<T>> {
+        let mut st = self.lock();
+        if st.closed {
+            return Err(ChanSendError(ChanError::Closed, t));
+        }
+        if let Ok(Some(e)) = st.pop_receiver(None) {
+            st.done.insert(e.id, Some(t));
+            self.notify(&mut st);
+            return Ok(());
+        }
+
+        let id = st.enqueue(true, None, Some(t));
+        self.notify(&mut st);
+        let mut waiting = true;
+        loop {
+            if st.done.remove(&id).is_some() {
+                return Ok(());
+            }
+            if st.closed || !waiting {
+                let t = st
+                    .dequeue(true, id)
+                    .expect("A waiting sender holds its value");
+                let err = match st.closed {
+                    true => ChanError::Closed,
+                    false => ChanError::Timeout,
+                };
+                return Err(ChanSendError(err, t));
+            }
+            let (s, w) = self.wait(st, deadline);
+            st = s;
+            waiting = w;
+        }
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, ChanError> {
+        let mut st = self.lock();
+        if let Ok(Some(e)) = st.pop_sender(None) {
+            st.done.insert(e.id, None);
+            self.notify(&mut st);
+            return Ok(e.val.expect("A waiting sender holds its value"));
+        }
+        if st.closed {
+            return Err(ChanError::Closed);
+        }
+
+        let id = st.enqueue(false, None, None);
+        self.notify(&mut st);
+        let mut waiting = true;
+        loop {
+            if let Some(t) = st.done.remove(&id) {
+                return Ok(t.expect("A completed reciever is handed a value"));
+            }
+            if st.closed || !waiting {
+                st.dequeue(false, id);
+                return match st.closed {
+                    true => Err(ChanError::Closed),
+                    false => Err(ChanError::Timeout),
+                };
+            }
+            let (s, w) = self.wait(st, deadline);
+            st = s;
+            waiting = w;
+        }
+    }
+}
+
+impl<T> ChanState<T> {
+    fn enqueue(&mut self, sender: bool, token: Option<Token>, val: Option<T>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let e = Entry { id, token, val };
+        match sender {
+            true => self.senders.push_back(e),
+            false => self.receivers.push_back(e),
+        }
+        id
+    }
+
+    // Remove a waiting entry, handing back its value.
+    fn dequeue(&mut self, sender: bool, id: u64) -> Option<T> {
+        let q = match sender {
+            true => &mut self.senders,
+            false => &mut self.receivers,
+        };
+        let i = q.iter().position(|e| e.id == id)?;
+        q.remove(i).and_then(|e| e.val)
+    }
+
+    // The first waiting reciever that can be claimed, if we can claim our own token too.
+    fn pop_receiver(&mut self, own: Option<&Token>) -> Result<Option<Entry<T>>, ()> {
+        pop(&mut self.receivers, own)
+    }
+
+    // The first waiting sender that can be claimed. A closed channel offers none.
+    fn pop_sender(&mut self, own: Option<&Token>) -> Result<Option<Entry<T>>, ()> {
+        match self.closed {
+            true => Ok(None),
+            false => pop(&mut self.senders, own),
+        }
+    }
+}
+
+// Err(()) when our own select has chosen another arm.
+fn pop<T>(q: &mut VecDeque<Entry<T>>, own: Option<&Token>) -> Result<Option<Entry<T>>, ()> {
+    let mut i = 0;
+    while i < q.len() {
+        let claim = match (&q[i].token, own) {
+            (Some(peer), Some(o)) if peer.same(o) => Claim::Taken,
+            (Some(peer), _) => select::claim_pair(own, peer),
+            (None, Some(o)) => match o.claim() {
+                true => Claim::Won,
+                false => Claim::Lost,
+            },
+            (None, None) => Claim::Won,
+        };
+        match claim {
+            Claim::Won => return Ok(q.remove(i)),
+            Claim::Lost => return Err(()),
+            Claim::Taken => i += 1,
+        }
+    }
+    Ok(None)
+}
+
+pub struct Iter<'a, T>(&'a Chan<T>);
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.0.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Chan<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+// The send arm of a Select.
+pub struct ChanSelectSend<'a, T> {
+    chan: &'a ChanInner<T>,
+    // None while the value waits in the queue, as entry id.
+    val: Option<T>,
+    id: Option<u64>,
+}
+
+impl<'a, T> ChanSelectSend<'a, T> {
+    fn reclaim(&mut self, st: &mut ChanState<T>) -> T {
+        if let Some(t) = self.val.take() {
+            return t;
+        }
+        match self.id.take().and_then(|id| st.dequeue(true, id)) {
+            Some(t) => t,
+            None => unreachable!("An unsent value is in the select or its entry"),
+        }
+    }
+}
+
+impl<'a, T> select::private::Sealed for ChanSelectSend<'a, T> {}
+
+impl<'a, T> Selectable for ChanSelectSend<'a, T> {
+    type Output = Result<(), ChanSendError<T>>;
+
+    fn attempt(&mut self, token: &Token, offer: bool) -> Attempt<Self::Output> {
+        let mut st = self.chan.lock();
+        if st.closed {
+            return match token.claim() {
+                true => {
+                    Attempt::Ready(Err(ChanSendError(ChanError::Closed, self.reclaim(&mut st))))
+                }
+                false => Attempt::Lost,
+            };
+        }
+        match st.pop_receiver(Some(token)) {
+            Ok(Some(e)) => {
+                let t = self.reclaim(&mut st);
+                st.done.insert(e.id, Some(t));
+                self.chan.notify(&mut st);
+                Attempt::Ready(Ok(()))
+            }
+            Err(()) => Attempt::Lost,
+            Ok(None) => {
+                if offer && self.id.is_none() {
+                    let t = self.val.take();
+                    self.id = Some(st.enqueue(true, Some(token.clone()), t));
+                    self.chan.notify(&mut st);
+                }
+                st.wakers.push(token.waker());
+                Attempt::Pending
+            }
+        }
+    }
+
+    // A reciever took the queued value.
+    fn finish(&mut self, _token: &Token) -> Self::Output {
+        let mut st = self.chan.lock();
+        if let Some(id) = self.id.take() {
+            st.done.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn withdraw(&mut self, _token: &Token) {
+        if let Some(id) = self.id.take() {
+            let mut st = self.chan.lock();
+            self.val = st.dequeue(true, id);
+            self.chan.notify(&mut st);
+        }
+    }
+
+    fn poke(&self) {
+        let mut st = self.chan.lock();
+        self.chan.notify(&mut st);
+    }
+}
+
+// The recv arm of a Select.
+pub struct ChanSelectRecv<'a, T> {
+    chan: &'a ChanInner<T>,
+    id: Option<u64>,
+}
+
+impl<'a, T> select::private::Sealed for ChanSelectRecv<'a, T> {}
+
+impl<'a, T> Selectable for ChanSelectRecv<'a, T> {
+    type Output = Result<T, ChanError>;
+
+    fn attempt(&mut self, token: &Token, offer: bool) -> Attempt<Self::Output> {
+        let mut st = self.chan.lock();
+        match st.pop_sender(Some(token)) {
+            Ok(Some(e)) => {
+                if let Some(id) = self.id.take() {
+                    st.dequeue(false, id);
+                }
+                st.done.insert(e.id, None);
+                self.chan.notify(&mut st);
+                Attempt::Ready(Ok(e.val.expect("A waiting sender holds its value")))
+            }
+            Err(()) => Attempt::Lost,
+            Ok(None) if st.closed => match token.claim() {
+                true => {
+                    if let Some(id) = self.id.take() {
+                        st.dequeue(false, id);
+                    }
+                    Attempt::Ready(Err(ChanError::Closed))
+                }
+                false => Attempt::Lost,
+            },
+            Ok(None) => {
+                if offer && self.id.is_none() {
+                    self.id = Some(st.enqueue(false, Some(token.clone()), None));
+                    self.chan.notify(&mut st);
+                }
+                st.wakers.push(token.waker());
+                Attempt::Pending
+            }
+        }
+    }
+
+    // A sender claimed the queued entry and handed it a value.
+    fn finish(&mut self, _token: &Token) -> Self::Output {
+        let mut st = self.chan.lock();
+        match self.id.take().and_then(|id| st.done.remove(&id)) {
+            Some(Some(t)) => Ok(t),
+            _ => unreachable!("A sender claiming a recv entry hands it a value"),
+        }
+    }
+
+    fn withdraw(&mut self, _token: &Token) {
+        if let Some(id) = self.id.take() {
+            let mut st = self.chan.lock();
+            st.dequeue(false, id);
+            self.chan.notify(&mut st);
+        }
+    }
+
+    fn poke(&self) {
+        let mut st = self.chan.lock();
+        self.chan.notify(&mut st);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChanError {
+    Closed,
+    Timeout,
+    NotReady,
+}
+
+impl fmt::Display for ChanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChanError::Closed => write!(f, "The channel is closed"),
+            ChanError::Timeout => write!(f, "No peer arrived before the deadline"),
+            ChanError::NotReady => write!(f, "No peer is waiting"),
+        }
+    }
+}
+
+impl Error for ChanError {
+    fn description(&self) -> &str {
+        match self {
+            ChanError::Closed => "The channel is closed",
+            ChanError::Timeout => "No peer arrived before the deadline",
+            ChanError::NotReady => "No peer is waiting",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+// A failed send, handing back the value.
+#[derive(Debug)]
+pub struct ChanSendError<T>(pub ChanError, pub T);
+
+impl<T> ChanSendError<T> {
+    pub fn into_inner(self) -> T {
+        self.1
+    }
+}
+
+impl<T> fmt::Display for ChanSendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Debug> Error for ChanSendError<T> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ping::Ping;
+    use crate::select::Select;
+    use std::thread;
+
+    #[test]
+    fn test_chan_mpmc() {
+        let c = Chan::<u64>::new();
+        let senders: Vec<_> = (0..4)
+            .map(|s| {
+                let c = c.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        c.send(s * 1000 + i).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let receivers: Vec<_> = (0..3)
+            .map(|_| {
+                let c = c.clone();
+                thread::spawn(move || c.iter().collect::<Vec<u64>>())
+            })
+            .collect();
+        for s in senders {
+            s.join().expect("Failed to Join Threads!");
+        }
+        c.close();
+        let mut got: Vec<u64> = receivers
+            .into_iter()
+            .flat_map(|r| r.join().expect("Failed to Join Threads!"))
+            .collect();
+        got.sort_unstable();
+        let mut want: Vec<u64> = (0..4)
+            .flat_map(|s| (0..100).map(move |i| s * 1000 + i))
+            .collect();
+        want.sort_unstable();
+        assert_eq!(want, got);
+
+        match c.send(1) {
+            Err(ChanSendError(ChanError::Closed, 1)) => println!(),
+            _ => panic!("Sent on a closed channel"),
+        }
+        assert_eq!(Err(ChanError::Closed), c.recv());
+    }
+
+    #[test]
+    fn test_chan_select() {
+        let (c, d) = (Chan::<u32>::new(), Chan::<u32>::new());
+        match c.try_send(1) {
+            Err(ChanSendError(ChanError::NotReady, 1)) => println!(),
+            _ => panic!("Sent without a reciever"),
+        }
+        assert_eq!(
+            Err(ChanError::Timeout),
+            c.recv_timeout(Duration::from_millis(5))
+        );
+
+        // A select over a Chan and a Ping, fed from another thread.
+        let mut p = Ping::<u32>::new();
+        let c2 = c.clone();
+        let h = thread::spawn(move || c2.send(5));
+        let got = crate::select! {
+            recv(c) -> r => r.unwrap(),
+            recv(p) -> r => r.unwrap() + 100,
+        };
+        assert_eq!(5, got);
+        h.join().expect("Failed to Join Threads!").unwrap();
+
+        // Selects on both ends of two channels move exactly one value.
+        for _ in 0..100 {
+            let (c2, d2) = (c.clone(), d.clone());
+            let h = thread::spawn(move || {
+                Select::new()
+                    .arm(c2.select_send(1), |r| r.map(|_| 1).ok())
+                    .arm(d2.select_send(2), |r| r.map(|_| 2).ok())
+                    .wait()
+            });
+            let got = crate::select! {
+                recv(c) -> r => r.ok(),
+                recv(d) -> r => r.ok(),
+            };
+            assert_eq!(h.join().expect("Failed to Join Threads!"), got);
+            assert_eq!(Err(ChanError::NotReady), c.try_recv());
+            assert_eq!(Err(ChanError::NotReady), d.try_recv());
+        }
+
+        // Closing wakes a waiting reciever.
+        let c2 = c.clone();
+        let h = thread::spawn(move || c2.recv());
+        thread::sleep(Duration::from_millis(5));
+        c.close();
+        assert_eq!(
+            Err(ChanError::Closed),
+            h.join().expect("Failed to Join Threads!")
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+// Numeric LVars: max and min registers, a grow-only counter and a PN-counter.
+// Each value lives in a single atomic word whose top bit marks it frozen,
+// so updates are a lock-free compare-and-swap and freezing can never lose one.
+// That leaves 63 bits for the value itself.
+// Threshold readers park on a condvar, which updaters only touch when someone is parked.
+
+// The largest value a cell or counter can hold.
+pub const LIMIT: u64 = (1 << 63) - 1;
+
+const FROZEN: u64 = 1 << 63;
+
+// The parking lot for threshold readers.
+struct Waiters {
+    lock: Mutex<()>,
+    cond: Condvar,
+    parked: AtomicUsize,
+}
+
+impl Waiters {
+    fn new() -> Waiters {
+        Waiters {
+            lock: Mutex::new(()),
+            cond: Condvar::new(),
+            parked: AtomicUsize::new(0),
+        }
+    }
+
+    // Called after every successful update.
+    // Both sides are SeqCst: either the updater sees the reader parked,
+    // or the reader's check sees the update.
+    fn notify(&self) {
+        if self.parked.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock();
+            self.cond.notify_all();
+        }
+    }
+
+    fn wait_until<F: Fn() -> bool>(&self, done: F) -> Result<(), CounterError> {
+        let mut guard = self.lock.lock().map_err(|_| CounterError::PoisonGuard)?;
+        self.parked.fetch_add(1, Ordering::SeqCst);
+        while !done() {
+            guard = match self.cond.wait(guard) {
+                Ok(g) => g,
+                Err(_) => {
+                    self.parked.fetch_sub(1, Ordering::SeqCst);
+                    return Err(CounterError::PoisonGuard);
+                }
+            };
+        }
+        self.parked.fetch_sub(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+// Moves word to f(value) unless frozen, in which case only no-op updates are allowed.
+// f returns None when the update would not change the value.
+fn update<F>(word: &AtomicU64, f: F) -> Result<bool, CounterError>
+where
+    F: Fn(u64) -> Result<Option<u64>, CounterError>,
+{
+    let mut cur = word.load(Ordering::SeqCst);
+    loop {
+        let next = match f(cur & LIMIT)? {
+            None => return Ok(false),
+            Some(_) if cur & FROZEN != 0 => return Err(CounterError::ChangeAfterFreeze),
+            Some(n) => n,
+        };
+
+        match word.compare_exchange_weak(cur, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return Ok(true),
+            Err(actual) => cur = actual,
+        }
+    }
+}
+
+fn frozen_value(word: &AtomicU64) -> Result<u64, CounterError> {
+    let cur = word.load(Ordering::SeqCst);
+    match cur & FROZEN {
+        0 => Err(CounterError::NotFrozen),
+        _ => Ok(cur & LIMIT),
+    }
+}
+
+fn add(cur: u64, n: u64) -> Result<Option<u64>, CounterError> {
+    match n {
+        0 => Ok(None),
+        _ => match cur.checked_add(n) {
+            Some(x) if x <= LIMIT => Ok(Some(x)),
+            _ => Err(CounterError::Overflow),
+        },
+    }
+}
+
+struct CellMachine {
+    word: AtomicU64,
+    waiters: Waiters,
+}
+
+impl CellMachine {
+    fn new(v: u64) -> Arc<CellMachine> {
+        Arc::new(CellMachine {
+            word: AtomicU64::new(v),
+            waiters: Waiters::new(),
+        })
+    }
+}
+
+// A register which only ever holds the largest value put into it, starting at 0.
+// Putting a value at or below the current one is a no-op, even after freeze.
+pub struct MaxCell(Arc<CellMachine>);
+
+impl Clone for MaxCell {
+    fn clone(&self) -> MaxCell {
+        MaxCell(self.0.clone())
+    }
+}
+
+impl Default for MaxCell {
+    fn default() -> MaxCell {
+        MaxCell::new()
+    }
+}
+
+impl MaxCell {
+    pub fn new() -> MaxCell {
+        MaxCell(CellMachine::new(0))
+    }
+
+    pub fn put(&mut self, v: u64) -> Result<(), CounterError> {
+        if v > LIMIT {
+            return Err(CounterError::Overflow);
+        }
+        let grew = update(&self.0.word, |cur| match v > cur {
+            true => Ok(Some(v)),
+            false => Ok(None),
+        })?;
+        if grew {
+            self.0.waiters.notify();
+        }
+        Ok(())
+    }
+
+    // Blocks until the value is at least n.
+    pub fn wait_at_least(&self, n: u64) -> Result<(), CounterError> {
+        let word = &self.0.word;
+        self.0
+            .waiters
+            .wait_until(|| word.load(Ordering::SeqCst) & LIMIT >= n)
+    }
+
+    // Forbid further growth, returning the final value.
+    pub fn freeze(&mut self) -> u64 {
+        self.0.word.fetch_or(FROZEN, Ordering::SeqCst) & LIMIT
+    }
+
+    // The exact value, only permitted once frozen.
+    pub fn get(&self) -> Result<u64, CounterError> {
+        frozen_value(&self.0.word)
+    }
+}
+
+// A register which only ever holds the smallest value put into it, starting at LIMIT.
+// Putting a value at or above the current one is a no-op, even after freeze.
+pub struct MinCell(Arc<CellMachine>);
+
+impl Clone for MinCell {
+    fn clone(&self) -> MinCell {
+        MinCell(self.0.clone())
+    }
+}
+
+impl Default for MinCell {
+    fn default() -> MinCell {
+        MinCell::new()
+    }
+}
+
+impl MinCell {
+    pub fn new() -> MinCell {
+        MinCell(CellMachine::new(LIMIT))
+    }
+
+    pub fn put(&mut self, v: u64) -> Result<(), CounterError> {
+        let shrank = update(&self.0.word, |cur| match v < cur {
+            true => Ok(Some(v)),
+            false => Ok(None),
+        })?;
+        if shrank {
+            self.0.waiters.notify();
+        }
+        Ok(())
+    }
+
+    // Blocks until the value is at most n.
+    pub fn wait_at_most(&self, n: u64) -> Result<(), CounterError> {
+        let word = &self.0.word;
+        self.0
+            .waiters
+            .wait_until(|| word.load(Ordering::SeqCst) & LIMIT <= n)
+    }
+
+    // Forbid further shrinking, returning the final value.
+    pub fn freeze(&mut self) -> u64 {
+        self.0.word.fetch_or(FROZEN, Ordering::SeqCst) & LIMIT
+    }
+
+    // The exact value, only permitted once frozen.
+    pub fn get(&self) -> Result<u64, CounterError> {
+        frozen_value(&self.0.word)
+    }
+}
+
+// A grow-only counter.
+// Increments commute, so any interleaving of them reaches the same total.
+pub struct Counter(Arc<CellMachine>);
+
+impl Clone for Counter {
+    fn clone(&self) -> Counter {
+        Counter(self.0.clone())
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Counter {
+        Counter::new()
+    }
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter(CellMachine::new(0))
+    }
+
+    // Incrementing by 0 is a no-op, even after freeze.
+    pub fn incr(&mut self, n: u64) -> Result<(), CounterError> {
+        if update(&self.0.word, |cur| add(cur, n))? {
+            self.0.waiters.notify();
+        }
+        Ok(())
+    }
+
+    // Blocks until the count is at least n.
+    pub fn wait_at_least(&self, n: u64) -> Result<(), CounterError> {
+        let word = &self.0.word;
+        self.0
+            .waiters
+            .wait_until(|| word.load(Ordering::SeqCst) & LIMIT >= n)
+    }
+
+    // Forbid further increments, returning the final count.
+    pub fn freeze(&mut self) -> u64 {
+        self.0.word.fetch_or(FROZEN, Ordering::SeqCst) & LIMIT
+    }
+
+    // The exact count, only permitted once frozen.
+    pub fn get(&self) -> Result<u64, CounterError> {
+        frozen_value(&self.0.word)
+    }
+}
+
+struct PNCounterMachine {
+    incs: AtomicU64,
+    decs: AtomicU64,
+    waiters: Waiters,
+}
+
+// A counter which may also be decremented, built from two grow-only counters.
+// Its value is not monotonic, so thresholds apply to the increments and
+// decrements separately. The net value is only available once frozen.
+pub struct PNCounter(Arc<PNCounterMachine>);
+
+impl Clone for PNCounter {
+    fn clone(&self) -> PNCounter {
+        PNCounter(self.0.clone())
+    }
+}
+
+impl Default for PNCounter {
+    fn default() -> PNCounter {
+        PNCounter::new()
+    }
+}
+
+impl PNCounter {
+    pub fn new() -> PNCounter {
+        PNCounter(Arc::new(PNCounterMachine {
+            incs: AtomicU64::new(0),
+            decs: AtomicU64::new(0),
+            waiters: Waiters::new(),
+        }))
+    }
+
+    pub fn incr(&mut self, n: u64) -> Result<(), CounterError> {
+        if update(&self.0.incs, |cur| add(cur, n))? {
+            self.0.waiters.notify();
+        }
+        Ok(())
+    }
+
+    pub fn decr(&mut self, n: u64) -> Result<(), CounterError> {
+        if update(&self.0.decs, |cur| add(cur, n))? {
+            self.0.waiters.notify();
+        }
+        Ok(())
+    }
+
+    // Blocks until at least n has been added in total.
+    pub fn wait_increments(&self, n: u64) -> Result<(), CounterError> {
+        let word = &self.0.incs;
+        self.0
+            .waiters
+            .wait_until(|| word.load(Ordering::SeqCst) & LIMIT >= n)
+    }
+
+    // Blocks until at least n has been subtracted in total.
+    pub fn wait_decrements(&self, n: u64) -> Result<(), CounterError> {
+        let word = &self.0.decs;
+        self.0
+            .waiters
+            .wait_until(|| word.load(Ordering::SeqCst) & LIMIT >= n)
+    }
+
+    // Forbid further changes, returning the final net value.
+    // Every update which succeeded is counted, every later one fails.
+    pub fn freeze(&mut self) -> i128 {
+        let incs = self.0.incs.fetch_or(FROZEN, Ordering::SeqCst) & LIMIT;
+        let decs = self.0.decs.fetch_or(FROZEN, Ordering::SeqCst) & LIMIT;
+        incs as i128 - decs as i128
+    }
+
+    // The exact net value, only permitted once frozen.
+    pub fn get(&self) -> Result<i128, CounterError> {
+        let incs = frozen_value(&self.0.incs)?;
+        let decs = frozen_value(&self.0.decs)?;
+        Ok(incs as i128 - decs as i128)
+    }
+}
+
+#[derive(Debug)]
+pub enum CounterError {
+    ChangeAfterFreeze,
+    NotFrozen,
+    Overflow,
+    PoisonGuard,
+}
+
+impl fmt::Display for CounterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CounterError::ChangeAfterFreeze => write!(f, "This value is frozen, it may no longer change"),
+            CounterError::NotFrozen => write!(f, "This value must be frozen before it is observed exactly"),
+            CounterError::Overflow => write!(f, "This value would exceed quartz::counter::LIMIT"),
+            CounterError::PoisonGuard => write!(f, "A thread has panicked while holding the waiters' guard, threshold reads are now inaccessible"),
+        }
+    }
+}
+
+impl Error for CounterError {
+    fn description(&self) -> &str {
+        match self {
+            CounterError::ChangeAfterFreeze => "This value is frozen, it may no longer change",
+            CounterError::NotFrozen => "This value must be frozen before it is observed exactly",
+            CounterError::Overflow => "This value would exceed quartz::counter::LIMIT",
+            CounterError::PoisonGuard => "A thread has panicked while holding the waiters' guard, threshold reads are now inaccessible",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_counter() {
+        let mut c1 = Counter::new();
+        let waiter = {
+            let c2 = c1.clone();
+            thread::spawn(move || c2.wait_at_least(4000).unwrap())
+        };
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let mut c = c1.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        c.incr(1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().expect("Failed to Join Threads!");
+        }
+        waiter.join().expect("Failed to Join Threads!");
+
+        match c1.get() {
+            Err(CounterError::NotFrozen) => println!(),
+            _ => panic!("Read an open counter"),
+        };
+        assert_eq!(4000, c1.freeze());
+        assert_eq!(4000, c1.get().unwrap());
+        c1.incr(0)
+            .expect("No-op increment after freeze was refused");
+        match c1.incr(1) {
+            Err(CounterError::ChangeAfterFreeze) => println!(),
+            _ => panic!("Incremented a frozen counter"),
+        };
+    }
+
+    #[test]
+    fn test_max_min_cell() {
+        let mut m1 = MaxCell::new();
+        let mut n1 = MinCell::new();
+        let (m2, n2) = (m1.clone(), n1.clone());
+
+        let h = thread::spawn(move || {
+            m2.wait_at_least(7).unwrap();
+            n2.wait_at_most(3).unwrap();
+        });
+
+        for v in [5, 9, 3, 7].iter() {
+            m1.put(*v).unwrap();
+            n1.put(*v).unwrap();
+        }
+        h.join().expect("Failed to Join Threads!");
+
+        assert_eq!(9, m1.freeze());
+        assert_eq!(3, n1.freeze());
+        m1.put(2).expect("No-op put after freeze was refused");
+        n1.put(8).expect("No-op put after freeze was refused");
+        match m1.put(10) {
+            Err(CounterError::ChangeAfterFreeze) => println!(),
+            _ => panic!("Grew a frozen MaxCell"),
+        };
+        match m1.put(LIMIT + 1) {
+            Err(CounterError::Overflow) => println!(),
+            _ => panic!("Put a value past LIMIT"),
+        };
+    }
+
+    #[test]
+    fn test_pn_counter() {
+        let mut p1 = PNCounter::new();
+        let mut p2 = p1.clone();
+
+        let h = thread::spawn(move || {
+            p2.wait_increments(10).unwrap();
+            p2.decr(15).unwrap();
+        });
+
+        p1.incr(4).unwrap();
+        p1.incr(6).unwrap();
+        p1.wait_decrements(15).unwrap();
+        h.join().expect("Failed to Join Threads!");
+
+        assert_eq!(-5, p1.freeze());
+        assert_eq!(-5, p1.get().unwrap());
+        match p1.decr(1) {
+            Err(CounterError::ChangeAfterFreeze) => println!(),
+            _ => panic!("Decremented a frozen counter"),
+        };
+    }
+}
@@ -0,0 +1,355 @@
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use crate::counter::{Counter, CounterError, MaxCell, MinCell, PNCounter};
+use crate::imap::{IMap, IMapError, IMapState};
+use crate::iset::{ISet, ISetError, ISetState};
+use crate::lattice::Lattice;
+use crate::once_cell::{OnceCell, OnceCellError, OnceCellState, OnceVal};
+
+// Effect levels, after LVish's effect signatures.
+// The structures in this crate mix deterministic operations (write, blocking reads,
+// threshold reads) with ones that observe timing (sample, state) or end growth (freeze).
+// A Handle<C, E> wraps a structure and only offers the operations its level E permits:
+//   Det      -- writes, blocking and threshold reads, handlers. Always deterministic.
+//   QuasiDet -- also freeze, and exact reads of frozen contents. Either deterministic,
+//               or an error is raised (writing after freeze), never a different answer.
+//   Io       -- also sample and state, which can answer differently from run to run.
+// A function taking Handle<_, Det> therefore cannot peek, and the compiler says so.
+
+mod private {
+    pub trait Sealed {}
+}
+
+pub trait Effect: private::Sealed + Send + Sync + 'static {}
+
+// Levels at which freeze and frozen reads are allowed.
+pub trait MayFreeze: Effect {}
+
+// Levels at which sample, state and timeouts are allowed.
+pub trait MayObserve: MayFreeze {}
+
+// Implemented when every operation allowed at Self is allowed at E,
+// so that a handle may be passed down to code with fewer capabilities.
+pub trait Within<E: Effect>: Effect {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Det;
+#[derive(Debug, Clone, Copy)]
+pub struct QuasiDet;
+#[derive(Debug, Clone, Copy)]
+pub struct Io;
+
+impl private::Sealed for Det {}
+impl private::Sealed for QuasiDet {}
+impl private::Sealed for Io {}
+
+impl Effect for Det {}
+impl Effect for QuasiDet {}
+impl Effect for Io {}
+
+impl MayFreeze for QuasiDet {}
+impl MayFreeze for Io {}
+impl MayObserve for Io {}
+
+impl Within<Det> for Det {}
+impl Within<QuasiDet> for Det {}
+impl Within<Io> for Det {}
+impl Within<QuasiDet> for QuasiDet {}
+impl Within<Io> for QuasiDet {}
+impl Within<Io> for Io {}
+
+/// A structure together with the effect level it may be used at.
+///
+/// A deterministic handle cannot observe timing:
+///
+/// ```compile_fail
+/// use quartz::effect::{Det, Handle};
+/// use quartz::once_cell::OnceCell;
+///
+/// let h = Handle::<_, Det>::new(OnceCell::<u8>::new());
+/// h.sample();
+/// ```
+///
+/// Nor freeze:
+///
+/// ```compile_fail
+/// use quartz::effect::{Det, Handle};
+/// use quartz::iset::ISet;
+///
+/// let mut h = Handle::<_, Det>::new(ISet::<u8>::new());
+/// h.freeze();
+/// ```
+///
+/// And a handle cannot gain capabilities it was not given:
+///
+/// ```compile_fail
+/// use quartz::effect::{Det, Handle, Io};
+/// use quartz::once_cell::OnceCell;
+///
+/// let h = Handle::<_, Det>::new(OnceCell::<u8>::new());
+/// let h: Handle<_, Io> = h.restrict();
+/// ```
+pub struct Handle<C, E: Effect>(C, PhantomData<E>);
+
+impl<C: Clone, E: Effect> Clone for Handle<C, E> {
+    fn clone(&self) -> Handle<C, E> {
+        Handle(self.0.clone(), PhantomData)
+    }
+}
+
+impl<C, E: Effect> Handle<C, E> {
+    // Wrapping is always allowed, the bare structure is itself an Io level handle.
+    pub fn new(c: C) -> Handle<C, E> {
+        Handle(c, PhantomData)
+    }
+
+    // Give up capabilities, e.g. to pass an Io handle to deterministic code.
+    pub fn restrict<E2>(self) -> Handle<C, E2>
+    where
+        E2: Within<E>,
+    {
+        Handle(self.0, PhantomData)
+    }
+}
+
+impl<C, E: MayObserve> Handle<C, E> {
+    // Only an Io handle may give the structure back, since the structure can do anything.
+    pub fn into_inner(self) -> C {
+        self.0
+    }
+}
+
+impl<T: PartialEq, E: Effect> Handle<OnceCell<T>, E> {
+    pub fn write(&mut self, t: T) -> Result<(), OnceCellError> {
+        self.0.write(t)
+    }
+
+    pub fn read(&self) -> Result<OnceVal<T>, OnceCellError> {
+        self.0.read()
+    }
+
+    pub fn add_handler<F>(&mut self, f: F) -> Result<(), OnceCellError>
+    where
+        F: FnOnce(OnceVal<T>) + Send + 'static,
+    {
+        self.0.add_handler(f)
+    }
+}
+
+impl<T: PartialEq, E: MayObserve> Handle<OnceCell<T>, E> {
+    pub fn sample(&self) -> Result<(bool, OnceVal<T>), OnceCellError> {
+        self.0.sample()
+    }
+
+    pub fn state(&self) -> Result<OnceCellState, OnceCellError> {
+        self.0.state()
+    }
+}
+
+impl<T: Eq + Hash + Clone, E: Effect> Handle<ISet<T>, E> {
+    pub fn insert(&mut self, t: T) -> Result<(), ISetError> {
+        self.0.insert(t)
+    }
+
+    pub fn wait_elem(&self, t: &T) -> Result<(), ISetError> {
+        self.0.wait_elem(t)
+    }
+
+    pub fn wait_size(&self, n: usize) -> Result<(), ISetError> {
+        self.0.wait_size(n)
+    }
+
+    pub fn add_handler<F>(&mut self, f: F) -> Result<(), ISetError>
+    where
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        self.0.add_handler(f)
+    }
+}
+
+impl<T: Eq + Hash + Clone, E: MayFreeze> Handle<ISet<T>, E> {
+    pub fn freeze(&mut self) -> Result<(), ISetError> {
+        self.0.freeze()
+    }
+
+    pub fn iter(&self) -> Result<std::vec::IntoIter<T>, ISetError> {
+        self.0.iter()
+    }
+}
+
+impl<T: Eq + Hash + Clone, E: MayObserve> Handle<ISet<T>, E> {
+    pub fn state(&self) -> Result<ISetState, ISetError> {
+        self.0.state()
+    }
+}
+
+impl<K: Eq + Hash + Clone, L: Lattice + Clone, E: Effect> Handle<IMap<K, L>, E> {
+    pub fn insert(&mut self, k: K, l: L) -> Result<(), IMapError> {
+        self.0.insert(k, l)
+    }
+
+    pub fn wait_key(&self, k: &K) -> Result<(), IMapError> {
+        self.0.wait_key(k)
+    }
+
+    pub fn wait_value(&self, k: &K, threshold: &L) -> Result<(), IMapError> {
+        self.0.wait_value(k, threshold)
+    }
+
+    pub fn add_handler<F>(&mut self, f: F) -> Result<(), IMapError>
+    where
+        F: Fn(&K, &L) + Send + Sync + 'static,
+    {
+        self.0.add_handler(f)
+    }
+}
+
+impl<K: Eq + Hash + Clone, L: Lattice + Clone, E: MayFreeze> Handle<IMap<K, L>, E> {
+    pub fn freeze(&mut self) -> Result<(), IMapError> {
+        self.0.freeze()
+    }
+
+    pub fn get(&self, k: &K) -> Result<Option<L>, IMapError> {
+        self.0.get(k)
+    }
+
+    pub fn iter(&self) -> Result<std::vec::IntoIter<(K, L)>, IMapError> {
+        self.0.iter()
+    }
+}
+
+impl<K: Eq + Hash + Clone, L: Lattice + Clone, E: MayObserve> Handle<IMap<K, L>, E> {
+    pub fn state(&self) -> Result<IMapState, IMapError> {
+        self.0.state()
+    }
+}
+
+impl<E: Effect> Handle<MaxCell, E> {
+    pub fn put(&mut self, v: u64) -> Result<(), CounterError> {
+        self.0.put(v)
+    }
+
+    pub fn wait_at_least(&self, n: u64) -> Result<(), CounterError> {
+        self.0.wait_at_least(n)
+    }
+}
+
+impl<E: MayFreeze> Handle<MaxCell, E> {
+    pub fn freeze(&mut self) -> u64 {
+        self.0.freeze()
+    }
+
+    pub fn get(&self) -> Result<u64, CounterError> {
+        self.0.get()
+    }
+}
+
+impl<E: Effect> Handle<MinCell, E> {
+    pub fn put(&mut self, v: u64) -> Result<(), CounterError> {
+        self.0.put(v)
+    }
+
+    pub fn wait_at_most(&self, n: u64) -> Result<(), CounterError> {
+        self.0.wait_at_most(n)
+    }
+}
+
+impl<E: MayFreeze> Handle<MinCell, E> {
+    pub fn freeze(&mut self) -> u64 {
+        self.0.freeze()
+    }
+
+    pub fn get(&self) -> Result<u64, CounterError> {
+        self.0.get()
+    }
+}
+
+impl<E: Effect> Handle<Counter, E> {
+    pub fn incr(&mut self, n: u64) -> Result<(), CounterError> {
+        self.0.incr(n)
+    }
+
+    pub fn wait_at_least(&self, n: u64) -> Result<(), CounterError> {
+        self.0.wait_at_least(n)
+    }
+}
+
+impl<E: MayFreeze> Handle<Counter, E> {
+    pub fn freeze(&mut self) -> u64 {
+        self.0.freeze()
+    }
+
+    pub fn get(&self) -> Result<u64, CounterError> {
+        self.0.get()
+    }
+}
+
+impl<E: Effect> Handle<PNCounter, E> {
+    pub fn incr(&mut self, n: u64) -> Result<(), CounterError> {
+        self.0.incr(n)
+    }
+
+    pub fn decr(&mut self, n: u64) -> Result<(), CounterError> {
+        self.0.decr(n)
+    }
+
+    pub fn wait_increments(&self, n: u64) -> Result<(), CounterError> {
+        self.0.wait_increments(n)
+    }
+
+    pub fn wait_decrements(&self, n: u64) -> Result<(), CounterError> {
+        self.0.wait_decrements(n)
+    }
+}
+
+impl<E: MayFreeze> Handle<PNCounter, E> {
+    pub fn freeze(&mut self) -> i128 {
+        self.0.freeze()
+    }
+
+    pub fn get(&self) -> Result<i128, CounterError> {
+        self.0.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Deterministic by signature: it can add and wait, but never look.
+    fn fill(mut s: Handle<ISet<usize>, Det>, n: usize) {
+        for i in 0..n {
+            s.insert(i).unwrap();
+        }
+        s.wait_size(n).unwrap();
+    }
+
+    #[test]
+    fn test_effect_handles() {
+        let set = ISet::<usize>::new();
+        let mut h = Handle::<_, QuasiDet>::new(set);
+
+        let det = h.clone().restrict::<Det>();
+        let t = thread::spawn(move || fill(det, 10));
+        h.wait_size(10).unwrap();
+        t.join().expect("Failed to Join Threads!");
+
+        h.freeze().unwrap();
+        let mut all: Vec<usize> = h.iter().unwrap().collect();
+        all.sort();
+        assert_eq!((0..10).collect::<Vec<usize>>(), all);
+
+        let mut io = Handle::<_, Io>::new(OnceCell::<usize>::new());
+        let (filled, _) = io.sample().unwrap();
+        assert!(!filled);
+        io.write(3).unwrap();
+        match io.state().unwrap() {
+            OnceCellState::Filled => println!(),
+            other => panic!("Unexpected state in filled cell {}", other),
+        };
+        let cell = io.into_inner();
+        assert_eq!(Some(3), *cell.read().unwrap().read());
+    }
+}
@@ -0,0 +1,481 @@
+use std::collections::BTreeSet;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::lattice::{Lattice, LatticeError};
+use crate::ping::spark;
+use crate::propagator::{Cell, Network, PropagatorError};
+
+// A finite-domain constraint solver on propagator cells.
+// Each variable is a cell holding the set of integers it may still take. Constraints are
+// propagators which remove values that cannot be part of any solution, and a variable left
+// with no values is a contradiction. When propagation stalls, search picks the undecided variable
+// with the fewest values and tries each in turn, each on its own fork of the network.
+// The first SPARK_DEPTH levels of the search tree run their branches in parallel on sparks.
+
+// Levels of the search tree whose branches are explored in parallel.
+const SPARK_DEPTH: usize = 2;
+
+// The values a variable may still take. Learning more means ruling values out,
+// so the join is intersection, and an empty intersection is a conflict.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Domain(BTreeSet<i64>);
+
+impl Lattice for Domain {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        let meet: BTreeSet<i64> = self.0.intersection(&other.0).cloned().collect();
+        if meet.is_empty() {
+            return Err(LatticeError::Conflict);
+        }
+        let grew = meet.len() < self.0.len();
+        self.0 = meet;
+        Ok(grew)
+    }
+}
+
+impl Domain {
+    // Every integer from lo to hi inclusive.
+    pub fn range(lo: i64, hi: i64) -> Domain {
+        Domain((lo..=hi).collect())
+    }
+
+    pub fn single(v: i64) -> Domain {
+        Domain(Some(v).into_iter().collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, v: i64) -> bool {
+        self.0.contains(&v)
+    }
+
+    // The smallest and largest values left.
+    pub fn bounds(&self) -> Option<(i64, i64)> {
+        match (self.0.iter().next(), self.0.iter().next_back()) {
+            (Some(lo), Some(hi)) => Some((*lo, *hi)),
+            _ => None,
+        }
+    }
+
+    // The value, once only one is left.
+    pub fn value(&self) -> Option<i64> {
+        match self.0.len() {
+            1 => self.0.iter().next().cloned(),
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        self.0.iter().cloned()
+    }
+
+    fn filter<F: Fn(i64) -> bool>(&self, f: F) -> Domain {
+        Domain(self.0.iter().cloned().filter(|v| f(*v)).collect())
+    }
+}
+
+// A variable of a Solver.
+#[derive(Debug)]
+pub struct Var {
+    index: usize,
+    cell: Cell<Domain>,
+}
+
+impl Clone for Var {
+    fn clone(&self) -> Var {
+        *self
+    }
+}
+
+impl Copy for Var {}
+
+impl Var {
+    // The cell holding this variable's domain, to attach propagators of one's own.
+    pub fn cell(&self) -> Cell<Domain> {
+        self.cell
+    }
+}
+
+// A value for every variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solution(Vec<i64>);
+
+impl Solution {
+    pub fn get(&self, v: Var) -> i64 {
+        self.0[v.index]
+    }
+}
+
+pub struct Solver {
+    net: Network,
+    vars: Vec<Cell<Domain>>,
+}
+
+impl Default for Solver {
+    fn default() -> Solver {
+        Solver::new()
+    }
+}
+
+impl Solver {
+    pub fn new() -> Solver {
+        Solver {
+            net: Network::new(),
+            vars: Vec::new(),
+        }
+    }
+
+    // The network the constraints live in.
+    pub fn network(&self) -> &Network {
+        &self.net
+    }
+
+    // A variable taking a value from lo to hi inclusive.
+    pub fn var(&mut self, lo: i64, hi: i64) -> Var {
+        let cell = self.net.cell::<Domain>();
+        // An empty range joins into the empty cell without complaint, search reports it.
+        let _ = self.net.add(&cell, Domain::range(lo, hi));
+        self.vars.push(cell);
+        Var {
+            index: self.vars.len() - 1,
+            cell,
+        }
+    }
+
+    // a == b
+    pub fn eq(&mut self, a: Var, b: Var) -> Result<(), PropagatorError> {
+        let (a, b) = (a.cell, b.cell);
+        self.net.propagator(&[a.id(), b.id()], move |net| {
+            let (da, db) = (domain(net, &a)?, domain(net, &b)?);
+            net.add(&a, db)?;
+            net.add(&b, da)
+        })
+    }
+
+    // a != b
+    pub fn ne(&mut self, a: Var, b: Var) -> Result<(), PropagatorError> {
+        let (a, b) = (a.cell, b.cell);
+        self.net.propagator(&[a.id(), b.id()], move |net| {
+            let (da, db) = (domain(net, &a)?, domain(net, &b)?);
+            if let Some(v) = da.value() {
+                net.add(&b, db.filter(|x| x != v))?;
+            }
+            if let Some(v) = db.value() {
+                net.add(&a, da.filter(|x| x != v))?;
+            }
+            Ok(())
+        })
+    }
+
+    // a <= b
+    pub fn le(&mut self, a: Var, b: Var) -> Result<(), PropagatorError> {
+        let (a, b) = (a.cell, b.cell);
+        self.net.propagator(&[a.id(), b.id()], move |net| {
+            let (da, db) = (domain(net, &a)?, domain(net, &b)?);
+            if let (Some((lo, _)), Some((_, hi))) = (da.bounds(), db.bounds()) {
+                net.add(&a, da.filter(|x| x <= hi))?;
+                net.add(&b, db.filter(|x| x >= lo))?;
+            }
+            Ok(())
+        })
+    }
+
+    // The sum of coefficient times variable over terms == total.
+    // Propagates bounds: each term is narrowed to what the others' extremes leave room for.
+    // Products are taken in i128, where they cannot overflow. Sums of many large products still
+    // can, and then nothing is pruned, which is weaker but never wrong.
+    pub fn linear(&mut self, terms: &[(i64, Var)], total: i64) -> Result<(), PropagatorError> {
+        let terms: Vec<(i64, Cell<Domain>)> = terms.iter().map(|(k, v)| (*k, v.cell)).collect();
+        let inputs: Vec<_> = terms.iter().map(|(_, c)| c.id()).collect();
+        self.net.propagator(&inputs, move |net| {
+            let mut doms = Vec::with_capacity(terms.len());
+            let mut extremes: Vec<(i128, i128)> = Vec::with_capacity(terms.len());
+            for (k, c) in terms.iter() {
+                let d = domain(net, c)?;
+                let (lo, hi) = match d.bounds() {
+                    Some(x) => x,
+                    None => return Ok(()),
+                };
+                let (k, lo, hi) = (*k as i128, lo as i128, hi as i128);
+                extremes.push(match k >= 0 {
+                    true => (k * lo, k * hi),
+                    false => (k * hi, k * lo),
+                });
+                doms.push(d);
+            }
+
+            let sum = |f: fn(&(i128, i128)) -> i128| {
+                extremes
+                    .iter()
+                    .try_fold(0i128, |acc, e| acc.checked_add(f(e)))
+            };
+            let (sum_min, sum_max) = match (sum(|e| e.0), sum(|e| e.1)) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Ok(()),
+            };
+            let total = total as i128;
+            for (i, (k, c)) in terms.iter().enumerate() {
+                let bounds = sum_max
+                    .checked_sub(extremes[i].1)
+                    .and_then(|rest| total.checked_sub(rest))
+                    .zip(
+                        sum_min
+                            .checked_sub(extremes[i].0)
+                            .and_then(|rest| total.checked_sub(rest)),
+                    );
+                if let Some((lo, hi)) = bounds {
+                    let k = *k as i128;
+                    net.add(c, doms[i].filter(|x| (lo..=hi).contains(&(k * x as i128))))?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // No two of vars are equal.
+    pub fn all_different(&mut self, vars: &[Var]) -> Result<(), PropagatorError> {
+        let cells: Vec<Cell<Domain>> = vars.iter().map(|v| v.cell).collect();
+        let inputs: Vec<_> = cells.iter().map(|c| c.id()).collect();
+        self.net.propagator(&inputs, move |net| {
+            let doms = cells
+                .iter()
+                .map(|c| domain(net, c))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // Too few values left to go round.
+            let values: BTreeSet<i64> = doms.iter().flat_map(|d| d.iter()).collect();
+            if values.len() < cells.len() {
+                return Err(PropagatorError::Contradiction(cells[0].id()));
+            }
+
+            for (i, d) in doms.iter().enumerate() {
+                if let Some(v) = d.value() {
+                    for (j, c) in cells.iter().enumerate().filter(|(j, _)| *j != i) {
+                        net.add(c, doms[j].filter(|x| x != v))?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // The first solution, trying smaller values first.
+    pub fn solve(&self) -> Result<Option<Solution>, PropagatorError> {
+        let vars = Arc::new(self.vars.clone());
+        Ok(search(self.net.fork(), vars, false, 0)?.into_iter().next())
+    }
+
+    // Every solution, in the order solve would find them.
+    pub fn solve_all(&self) -> Result<Vec<Solution>, PropagatorError> {
+        let vars = Arc::new(self.vars.clone());
+        search(self.net.fork(), vars, true, 0)
+    }
+}
+
+fn domain(net: &Network, c: &Cell<Domain>) -> Result<Domain, PropagatorError> {
+    net.content(c)?.ok_or(PropagatorError::ForeignCell(c.id()))
+}
+
+fn search(
+    net: Network,
+    vars: Arc<Vec<Cell<Domain>>>,
+    all: bool,
+    depth: usize,
+) -> Result<Vec<Solution>, PropagatorError> {
+    match net.run() {
+        Ok(()) => {}
+        Err(PropagatorError::Contradiction(_)) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    }
+
+    let doms = vars
+        .iter()
+        .map(|c| domain(&net, c))
+        .collect::<Result<Vec<_>, _>>()?;
+    if doms.iter().any(|d| d.is_empty()) {
+        return Ok(Vec::new());
+    }
+
+    // First fail: branch on the undecided variable with fewest values.
+    let pick = doms
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| d.len() > 1)
+        .min_by_key(|(_, d)| d.len())
+        .map(|(i, _)| i);
+    let i = match pick {
+        Some(i) => i,
+        None => {
+            let values = doms.iter().filter_map(|d| d.value()).collect();
+            return Ok(vec![Solution(values)]);
+        }
+    };
+
+    let mut branches = Vec::with_capacity(doms[i].len());
+    for v in doms[i].iter() {
+        let b = net.fork();
+        if b.add(&vars[i], Domain::single(v)).is_ok() {
+            branches.push(b);
+        }
+    }
+
+    let mut found = Vec::new();
+    match depth < SPARK_DEPTH {
+        true => {
+            let sparks: Vec<_> = branches
+                .into_iter()
+                .map(|b| {
+                    let vars = vars.clone();
+                    // A panicking propagator would otherwise never answer its spark.
+                    spark(
+                        b,
+                        Box::new(move |b| {
+                            panic::catch_unwind(AssertUnwindSafe(|| {
+                                search(b, vars, all, depth + 1)
+                            }))
+                            .unwrap_or(Err(PropagatorError::PropagatorPanicked))
+                        }),
+                    )
+                })
+                .collect();
+            // Every spark is read, finished or not, so that no branch is still searching once we return.
+            let mut res = Ok(());
+            for mut s in sparks {
+                match s.read().unwrap_or(Err(PropagatorError::PropagatorPanicked)) {
+                    Ok(sols) => found.extend(sols),
+                    Err(err) => {
+                        if res.is_ok() {
+                            res = Err(err);
+                        }
+                    }
+                }
+            }
+            res?;
+        }
+        false => {
+            for b in branches {
+                found.extend(search(b, vars.clone(), all, depth + 1)?);
+                if !all && !found.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    if !all {
+        found.truncate(1);
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_send_more_money() {
+        let mut s = Solver::new();
+        let [sv, e, n, d, m, o, r, y] = [0; 8].map(|_| s.var(0, 9));
+        s.all_different(&[sv, e, n, d, m, o, r, y]).unwrap();
+        let one = s.var(1, 1);
+        s.le(one, sv).unwrap();
+        s.le(one, m).unwrap();
+
+        //   SEND
+        // + MORE
+        // = MONEY
+        #[rustfmt::skip]
+        let terms = [
+            (1000, sv), (100, e), (10, n), (1, d),
+            (1000, m), (100, o), (10, r), (1, e),
+            (-10000, m), (-1000, o), (-100, n), (-10, e), (-1, y),
+        ];
+        s.linear(&terms, 0).unwrap();
+
+        let all = s.solve_all().unwrap();
+        assert_eq!(1, all.len());
+        let word = |ds: &[Var]| ds.iter().fold(0, |acc, v| acc * 10 + all[0].get(*v));
+        assert_eq!(9567, word(&[sv, e, n, d]));
+        assert_eq!(1085, word(&[m, o, r, e]));
+        assert_eq!(10652, word(&[m, o, n, e, y]));
+        // Products beyond i64 prune as exactly as small ones.
+        let mut s = Solver::new();
+        let [x, y] = [0; 2].map(|_| s.var(0, 2));
+        s.linear(&[(i64::MAX, x), (-i64::MAX, y)], 0).unwrap();
+        let all = s.solve_all().unwrap();
+        assert_eq!(3, all.len());
+        assert!(all.iter().all(|sol| sol.get(x) == sol.get(y)));
+    }
+
+    fn queens(n: i64) -> (Solver, Vec<Var>) {
+        let mut s = Solver::new();
+        let qs: Vec<Var> = (0..n).map(|_| s.var(0, n - 1)).collect();
+        s.all_different(&qs).unwrap();
+
+        // Diagonals: q_i + i and q_i - i are all different too.
+        let mut up = Vec::new();
+        let mut down = Vec::new();
+        for (i, q) in qs.iter().enumerate() {
+            let i = i as i64;
+            let (u, d) = (s.var(0, 2 * n), s.var(-n, n));
+            s.linear(&[(1, u), (-1, *q)], i).unwrap();
+            s.linear(&[(1, d), (-1, *q)], -i).unwrap();
+            up.push(u);
+            down.push(d);
+        }
+        s.all_different(&up).unwrap();
+        s.all_different(&down).unwrap();
+        (s, qs)
+    }
+
+    #[test]
+    fn test_fd_queens() {
+        let (s, _) = queens(6);
+        assert_eq!(4, s.solve_all().unwrap().len());
+
+        let (s, qs) = queens(8);
+        let sol = s.solve().unwrap().expect("8 queens has solutions");
+        for i in 0..8 {
+            for j in (i + 1)..8 {
+                let (a, b) = (sol.get(qs[i]), sol.get(qs[j]));
+                assert!(a != b && (a - b).abs() != (j - i) as i64);
+            }
+        }
+        assert_eq!(92, s.solve_all().unwrap().len());
+
+        let (s, _) = queens(3);
+        assert_eq!(None, s.solve().unwrap());
+    }
+
+    #[test]
+    fn test_fd_constraints() {
+        // x <= y <= z in 0..4, x == w, y != 2: compared against brute force.
+        let mut s = Solver::new();
+        let [x, y, z, w] = [0; 4].map(|_| s.var(0, 3));
+        s.le(x, y).unwrap();
+        s.le(y, z).unwrap();
+        s.eq(x, w).unwrap();
+        let two = s.var(2, 2);
+        s.ne(y, two).unwrap();
+
+        let mut expected = 0;
+        for a in 0..4 {
+            for b in a..4 {
+                expected += (b != 2) as usize * (4 - b) as usize;
+            }
+        }
+        let all = s.solve_all().unwrap();
+        assert_eq!(expected, all.len());
+        assert!(all.iter().all(|sol| sol.get(x) == sol.get(w)));
+
+        let empty = s.var(1, 0);
+        s.eq(empty, x).unwrap();
+        assert!(s.solve_all().unwrap().is_empty());
+    }
+}
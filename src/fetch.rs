@@ -0,0 +1,546 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::once_cell::OnceCell;
+use crate::once_map::OnceMap;
+use crate::ping::spark;
+
+// Batched, cached data fetching, after Haxl.
+// A Fetch<T> describes how to compute a T from remote data, without fetching anything.
+// Running it proceeds in rounds: the computation is evaluated until every branch
+// is either done or blocked on data, the requests of all blocked branches are sent,
+// one batch per data source with the sources queried in parallel, and evaluation resumes.
+// Branches combined with zip or join_all are independent, so their requests share a round.
+// and_then is the only way to make one fetch depend on another, costing a round.
+// Every answer is kept in a per-run OnceMap, so each distinct request is fetched at most once,
+// however many times, or in however many rounds, it is asked for.
+// A failed request is dropped from the cache as its waiters are told, so asking again retries it.
+
+// A service able to answer many requests at once.
+pub trait DataSource: Send + Sync + 'static {
+    type Request: Eq + Hash + Clone + Send + Sync + 'static;
+    type Response: PartialEq + Clone + Send + Sync + 'static;
+
+    // Answer every request, in order. Called once per round with that round's distinct requests.
+    // Sources which can fail should say so in their Response type.
+    fn fetch(&self, reqs: Vec<Self::Request>) -> Vec<Self::Response>;
+}
+
+type Answer<S> = Result<<S as DataSource>::Response, FetchError>;
+
+// A source together with everything it has answered during this run.
+struct SourceEntry<S: DataSource> {
+    source: S,
+    cache: OnceMap<S::Request, Answer<S>>,
+}
+
+// A request waiting to be sent, with the cache cell its answer goes in.
+// Every such cell is written exactly once, so waiters on it always wake:
+// with the answer, with the batch's failure, or, if it is dropped unsent, Unanswered.
+struct Pending<S: DataSource> {
+    inner: Option<(S::Request, OnceCell<Answer<S>>)>,
+    cache: OnceMap<S::Request, Answer<S>>,
+}
+
+impl<S: DataSource> Pending<S> {
+    // A failure is removed from the cache first, so later asks make a fresh cell.
+    fn answer(
+        cache: &OnceMap<S::Request, Answer<S>>,
+        req: &S::Request,
+        mut cell: OnceCell<Answer<S>>,
+        a: Answer<S>,
+    ) {
+        if a.is_err() {
+            let _ = cache.remove(req);
+        }
+        let _ = cell.write(a);
+    }
+}
+
+impl<S: DataSource> Drop for Pending<S> {
+    fn drop(&mut self) {
+        if let Some((req, cell)) = self.inner.take() {
+            Pending::<S>::answer(&self.cache, &req, cell, Err(FetchError::Unanswered));
+        }
+    }
+}
+
+// A source with its types erased, so sources of every kind can share a round.
+trait Batch: Send + Sync {
+    fn run_batch(&self, pending: Vec<Box<dyn Any + Send>>) -> Result<(), FetchError>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<S: DataSource> Batch for SourceEntry<S> {
+    fn run_batch(&self, pending: Vec<Box<dyn Any + Send>>) -> Result<(), FetchError> {
+        let mut reqs = Vec::with_capacity(pending.len());
+        let mut cells = Vec::with_capacity(pending.len());
+        for p in pending {
+            match p.downcast::<Pending<S>>() {
+                Ok(mut x) => {
+                    if let Some((req, cell)) = x.inner.take() {
+                        reqs.push(req);
+                        cells.push(cell);
+                    }
+                }
+                // Requests are grouped by the TypeId of their source, so this cannot happen.
+                Err(_) => return Err(FetchError::UnknownSource),
+            }
+        }
+
+        let fetched = panic::catch_unwind(AssertUnwindSafe(|| self.source.fetch(reqs.clone())));
+        let answers: Vec<Answer<S>> = match fetched {
+            Ok(resps) if resps.len() == cells.len() => resps.into_iter().map(Ok).collect(),
+            Ok(_) => vec![Err(FetchError::Unanswered); cells.len()],
+            Err(_) => vec![Err(FetchError::SourcePanicked); cells.len()],
+        };
+        let res = match answers.iter().find_map(|a| a.as_ref().err()) {
+            Some(err) => Err(err.clone()),
+            None => Ok(()),
+        };
+        for ((req, cell), a) in reqs.iter().zip(cells).zip(answers) {
+            Pending::<S>::answer(&self.cache, req, cell, a);
+        }
+        res
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// The data sources of a run, and their caches.
+// Reusing an environment for several runs shares the cache between them.
+pub struct FetchEnv {
+    sources: HashMap<TypeId, Arc<dyn Batch>>,
+    rounds: AtomicUsize,
+}
+
+impl Default for FetchEnv {
+    fn default() -> FetchEnv {
+        FetchEnv::new()
+    }
+}
+
+// A request which has not been sent yet: its source, and the request with the cell for its answer.
+struct Blocked {
+    source: TypeId,
+    req: Box<dyn Any + Send>,
+}
+
+enum Step<T> {
+    Done(T),
+    Blocked(Vec<Blocked>, Fetch<T>),
+}
+
+// Evaluates as far as it can without more data.
+type Stepper<T> = Box<dyn FnOnce(&FetchEnv) -> Result<Step<T>, FetchError> + Send>;
+
+pub struct Fetch<T>(Stepper<T>);
+
+impl FetchEnv {
+    pub fn new() -> FetchEnv {
+        FetchEnv {
+            sources: HashMap::new(),
+            rounds: AtomicUsize::new(0),
+        }
+    }
+
+    // Sources are told apart by type, adding a second S replaces the first and empties its cache.
+    pub fn add_source<S: DataSource>(&mut self, s: S) {
+        let entry = SourceEntry::<S> {
+            source: s,
+            cache: OnceMap::new(),
+        };
+        self.sources.insert(TypeId::of::<S>(), Arc::new(entry));
+    }
+
+    // The number of rounds of requests sent so far.
+    pub fn rounds(&self) -> usize {
+        self.rounds.load(Ordering::SeqCst)
+    }
+
+    fn entry<S: DataSource>(&self) -> Result<&SourceEntry<S>, FetchError> {
+        self.sources
+            .get(&TypeId::of::<S>())
+            .and_then(|b| b.as_any().downcast_ref::<SourceEntry<S>>())
+            .ok_or(FetchError::UnknownSource)
+    }
+
+    // Send one round, a batch per source, all at once.
+    fn perform(&self, blocked: Vec<Blocked>) -> Result<(), FetchError> {
+        let mut groups: HashMap<TypeId, Vec<Box<dyn Any + Send>>> = HashMap::new();
+        for b in blocked {
+            groups.entry(b.source).or_default().push(b.req);
+        }
+
+        let mut sparks = Vec::with_capacity(groups.len());
+        for (id, pending) in groups {
+            let source = match self.sources.get(&id) {
+                Some(x) => x.clone(),
+                None => return Err(FetchError::UnknownSource),
+            };
+            // A panicking source would otherwise never answer its spark.
+            sparks.push(spark(
+                pending,
+                Box::new(move |pending| {
+                    match panic::catch_unwind(AssertUnwindSafe(|| source.run_batch(pending))) {
+                        Ok(res) => res,
+                        Err(_) => Err(FetchError::SourcePanicked),
+                    }
+                }),
+            ));
+        }
+
+        // Wait for every batch before reporting the first failure.
+        let mut res = Ok(());
+        for mut s in sparks {
+            let r = s.read().unwrap_or(Err(FetchError::SourcePanicked));
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        self.rounds.fetch_add(1, Ordering::SeqCst);
+        res
+    }
+}
+
+impl<T: Send + 'static> Fetch<T> {
+    fn new<F>(f: F) -> Fetch<T>
+    where
+        F: FnOnce(&FetchEnv) -> Result<Step<T>, FetchError> + Send + 'static,
+    {
+        Fetch::<T>(Box::new(f))
+    }
+
+    // A value needing no data.
+    pub fn pure(t: T) -> Fetch<T> {
+        Fetch::new(move |_| Ok(Step::Done(t)))
+    }
+
+    pub fn map<U, F>(self, f: F) -> Fetch<U>
+    where
+        U: Send + 'static,
+        F: FnOnce(T) -> U + Send + 'static,
+    {
+        Fetch::new(move |env| match (self.0)(env)? {
+            Step::Done(t) => Ok(Step::Done(f(t))),
+            Step::Blocked(b, k) => Ok(Step::Blocked(b, k.map(f))),
+        })
+    }
+
+    // Continue with a fetch depending on this one's result.
+    // Whatever f asks for cannot be known, and so sent, until this fetch is done.
+    pub fn and_then<U, F>(self, f: F) -> Fetch<U>
+    where
+        U: Send + 'static,
+        F: FnOnce(T) -> Fetch<U> + Send + 'static,
+    {
+        Fetch::new(move |env| match (self.0)(env)? {
+            Step::Done(t) => (f(t).0)(env),
+            Step::Blocked(b, k) => Ok(Step::Blocked(b, k.and_then(f))),
+        })
+    }
+
+    // Both fetches, their requests sent in the same rounds.
+    pub fn zip<U: Send + 'static>(self, other: Fetch<U>) -> Fetch<(T, U)> {
+        Fetch::new(move |env| {
+            let a = (self.0)(env)?;
+            let b = (other.0)(env)?;
+            match (a, b) {
+                (Step::Done(t), Step::Done(u)) => Ok(Step::Done((t, u))),
+                (Step::Done(t), Step::Blocked(b, k)) => {
+                    Ok(Step::Blocked(b, k.map(move |u| (t, u))))
+                }
+                (Step::Blocked(b, k), Step::Done(u)) => {
+                    Ok(Step::Blocked(b, k.map(move |t| (t, u))))
+                }
+                (Step::Blocked(mut b1, k1), Step::Blocked(b2, k2)) => {
+                    b1.extend(b2);
+                    Ok(Step::Blocked(b1, k1.zip(k2)))
+                }
+            }
+        })
+    }
+
+    pub fn run(self, env: &FetchEnv) -> Result<T, FetchError> {
+        let mut f = self;
+        loop {
+            match (f.0)(env)? {
+                Step::Done(t) => return Ok(t),
+                Step::Blocked(b, k) => {
+                    // Only waiting on another run's requests sends nothing, and is no round.
+                    if !b.is_empty() {
+                        env.perform(b)?;
+                    }
+                    f = k;
+                }
+            }
+        }
+    }
+}
+
+// Ask S for the answer to req.
+pub fn data<S: DataSource>(req: S::Request) -> Fetch<S::Response> {
+    Fetch::new(move |env| {
+        let entry = env.entry::<S>()?;
+        let (cell, created) = entry
+            .cache
+            .get_or_insert(req.clone())
+            .map_err(|_| FetchError::PoisonGuard)?;
+
+        if let (true, v) = cell.sample().map_err(|_| FetchError::PoisonGuard)? {
+            if let Some(a) = &*v.read() {
+                return a.clone().map(Step::Done);
+            }
+        }
+
+        // Only the branch that created the cell sends the request, others asking in the same round,
+        // or in another run sharing the environment, just wait on its answer.
+        let blocked = match created {
+            true => vec![Blocked {
+                source: TypeId::of::<S>(),
+                req: Box::new(Pending::<S> {
+                    inner: Some((req, cell.clone())),
+                    cache: entry.cache.clone(),
+                }),
+            }],
+            false => Vec::new(),
+        };
+        let k = Fetch::new(move |_| {
+            let v = cell.read().map_err(|_| FetchError::PoisonGuard)?;
+            let a = v.read();
+            match &*a {
+                Some(a) => a.clone().map(Step::Done),
+                None => Err(FetchError::Unanswered),
+            }
+        });
+        Ok(Step::Blocked(blocked, k))
+    })
+}
+
+// Every fetch, their requests sent in the same rounds.
+pub fn join_all<T: Send + 'static>(fs: Vec<Fetch<T>>) -> Fetch<Vec<T>> {
+    Fetch::new(move |env| {
+        let mut steps = Vec::with_capacity(fs.len());
+        for f in fs {
+            steps.push((f.0)(env)?);
+        }
+
+        if steps.iter().all(|s| matches!(s, Step::Done(_))) {
+            let ts = steps
+                .into_iter()
+                .filter_map(|s| match s {
+                    Step::Done(t) => Some(t),
+                    Step::Blocked(..) => None,
+                })
+                .collect();
+            return Ok(Step::Done(ts));
+        }
+
+        let mut blocked = Vec::new();
+        let mut rest = Vec::with_capacity(steps.len());
+        for s in steps {
+            match s {
+                Step::Done(t) => rest.push(Fetch::pure(t)),
+                Step::Blocked(b, k) => {
+                    blocked.extend(b);
+                    rest.push(k);
+                }
+            }
+        }
+        Ok(Step::Blocked(blocked, join_all(rest)))
+    })
+}
+
+// A data source answering from a function, which records every batch it was sent.
+// Clones share the record, so keep one to inspect after handing the other to a FetchEnv.
+pub struct MockSource<Req, Resp> {
+    answer: Arc<dyn Fn(&Req) -> Resp + Send + Sync>,
+    batches: Arc<Mutex<Vec<Vec<Req>>>>,
+}
+
+impl<Req, Resp> Clone for MockSource<Req, Resp> {
+    fn clone(&self) -> MockSource<Req, Resp> {
+        MockSource {
+            answer: self.answer.clone(),
+            batches: self.batches.clone(),
+        }
+    }
+}
+
+impl<Req: Clone, Resp> MockSource<Req, Resp> {
+    pub fn new<F>(f: F) -> MockSource<Req, Resp>
+    where
+        F: Fn(&Req) -> Resp + Send + Sync + 'static,
+    {
+        MockSource {
+            answer: Arc::new(f),
+            batches: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Every batch sent so far, in the order they were sent.
+    pub fn batches(&self) -> Vec<Vec<Req>> {
+        match self.batches.lock() {
+            Ok(x) => x.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl<Req, Resp> DataSource for MockSource<Req, Resp>
+where
+    Req: Eq + Hash + Clone + Send + Sync + 'static,
+    Resp: PartialEq + Clone + Send + Sync + 'static,
+{
+    type Request = Req;
+    type Response = Resp;
+
+    fn fetch(&self, reqs: Vec<Req>) -> Vec<Resp> {
+        let resps = reqs.iter().map(|r| (self.answer)(r)).collect();
+        if let Ok(mut batches) = self.batches.lock() {
+            batches.push(reqs);
+        }
+        resps
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FetchError {
+    UnknownSource,
+    Unanswered,
+    SourcePanicked,
+    PoisonGuard,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::UnknownSource => {
+                write!(f, "No data source of this type was added to the FetchEnv")
+            }
+            FetchError::Unanswered => {
+                write!(f, "A data source did not answer every request in its batch")
+            }
+            FetchError::SourcePanicked => write!(f, "A data source panicked while fetching"),
+            FetchError::PoisonGuard => write!(f, "A cache guard was poisoned"),
+        }
+    }
+}
+
+impl Error for FetchError {
+    fn description(&self) -> &str {
+        match self {
+            FetchError::UnknownSource => "No data source of this type was added to the FetchEnv",
+            FetchError::Unanswered => "A data source did not answer every request in its batch",
+            FetchError::SourcePanicked => "A data source panicked while fetching",
+            FetchError::PoisonGuard => "A cache guard was poisoned",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Names = MockSource<u32, String>;
+    type Lengths = MockSource<String, usize>;
+
+    #[test]
+    fn test_fetch_rounds() {
+        let names = Names::new(|id| format!("user{}", id));
+        let lengths = Lengths::new(|s| s.len());
+        let mut env = FetchEnv::new();
+        env.add_source(names.clone());
+        env.add_source(lengths.clone());
+
+        // Four lookups, one duplicated, then a dependent lookup for each answer.
+        let ids = vec![1, 2, 2, 30];
+        let f = join_all(
+            ids.into_iter()
+                .map(|id| data::<Names>(id).and_then(data::<Lengths>))
+                .collect(),
+        )
+        .zip(data::<Names>(1));
+
+        let (lens, first) = f.run(&env).unwrap();
+        assert_eq!(vec![5, 5, 5, 6], lens);
+        assert_eq!("user1", first);
+        assert_eq!(2, env.rounds());
+
+        let mut round1 = names.batches().remove(0);
+        round1.sort();
+        assert_eq!(vec![1, 2, 30], round1);
+        assert_eq!(1, names.batches().len());
+        assert_eq!(3, lengths.batches()[0].len());
+
+        // Cached answers cost no further rounds.
+        let again = data::<Names>(30).map(|n| n.len()).run(&env).unwrap();
+        assert_eq!(6, again);
+        assert_eq!(2, env.rounds());
+    }
+
+    struct Mute;
+
+    impl DataSource for Mute {
+        type Request = u8;
+        type Response = u8;
+
+        fn fetch(&self, _reqs: Vec<u8>) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_fetch_errors() {
+        let mut env = FetchEnv::new();
+        assert_eq!(Err(FetchError::UnknownSource), data::<Mute>(1).run(&env));
+
+        env.add_source(Mute);
+        assert_eq!(Err(FetchError::Unanswered), data::<Mute>(1).run(&env));
+
+        env.add_source(MockSource::<u8, u8>::new(|_| {
+            panic!("Expected panic in data source")
+        }));
+        let f = data::<MockSource<u8, u8>>(1).zip(Fetch::pure(2));
+        assert_eq!(Err(FetchError::SourcePanicked), f.run(&env));
+    }
+
+    #[test]
+    fn test_fetch_retry() {
+        // A source which panics on its first batch only.
+        let failed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let f = failed.clone();
+        let mut env = FetchEnv::new();
+        env.add_source(MockSource::<u8, u8>::new(move |x| {
+            if !f.swap(true, Ordering::SeqCst) {
+                panic!("Expected panic in data source");
+            }
+            x + 1
+        }));
+
+        let f = || data::<MockSource<u8, u8>>(1);
+        assert_eq!(Err(FetchError::SourcePanicked), f().run(&env));
+        assert!(failed.load(Ordering::SeqCst));
+        assert_eq!(Ok(2), f().run(&env));
+        assert_eq!(Ok(2), f().run(&env));
+        assert_eq!(2, env.rounds());
+
+        // A request abandoned unsent, by a sibling failing first, is retried too.
+        env.add_source(Mute);
+        let g = data::<MockSource<u8, u8>>(5).zip(data::<Names>(1));
+        assert_eq!(Err(FetchError::UnknownSource), g.run(&env));
+        assert_eq!(Ok(6), data::<MockSource<u8, u8>>(5).run(&env));
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+
+use crate::imap::{IMap, IMapError};
+use crate::iset::{ISet, ISetError};
+use crate::lattice::Lattice;
+use crate::once_cell::{OnceCell, OnceCellError, OnceVal};
+use crate::wait_group::WaitGroup;
+
+// LVish's handler pools.
+// Callbacks attached through a pool run on its fixed set of worker threads,
+// once for every growth of the structure they are attached to.
+// quiesce blocks until every callback triggered so far, including those
+// triggered by other callbacks, has finished.
+// That makes it the join point for traversals which discover their own work,
+// such as a parallel graph search over an ISet of visited nodes.
+pub struct HandlerPool(Arc<PoolHandle>);
+
+impl Clone for HandlerPool {
+    fn clone(&self) -> HandlerPool {
+        HandlerPool(self.0.clone())
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Shared with the workers, who must not keep the pool itself alive.
+struct PoolQueue {
+    jobs: Mutex<VecDeque<Job>>,
+    cond: Condvar,
+    shutdown: AtomicBool,
+    // Each triggered callback is added when queued and marked done when finished.
+    // A callback which triggers another adds to the count before finishing,
+    // so the count only reaches 0 once no work remains.
+    outstanding: WaitGroup,
+    panicked: AtomicUsize,
+}
+
+struct PoolHandle(Arc<PoolQueue>);
+
+impl Drop for PoolHandle {
+    fn drop(&mut self) {
+        // The workers finish what is queued, then exit.
+        self.0.shutdown.store(true, Ordering::SeqCst);
+        let _guard = self.0.jobs.lock();
+        self.0.cond.notify_all();
+    }
+}
+
+// Anything which can report its growth to a callback.
+// Each event describes one growth, and no growth is reported twice.
+pub trait Observable {
+    type Event: Send + 'static;
+    type Error;
+
+    fn observe(&mut self, f: Box<dyn Fn(Self::Event) + Send + Sync>) -> Result<(), Self::Error>;
+}
+
+impl<T: PartialEq + Send + Sync + 'static> Observable for OnceCell<T> {
+    type Event = OnceVal<T>;
+    type Error = OnceCellError;
+
+    fn observe(&mut self, f: Box<dyn Fn(OnceVal<T>) + Send + Sync>) -> Result<(), OnceCellError> {
+        self.add_handler(f)
+    }
+}
+
+impl<T: Eq + Hash + Clone + Send + 'static> Observable for ISet<T> {
+    type Event = T;
+    type Error = ISetError;
+
+    fn observe(&mut self, f: Box<dyn Fn(T) + Send + Sync>) -> Result<(), ISetError> {
+        self.add_handler(move |t| f(t.clone()))
+    }
+}
+
+impl<K, L> Observable for IMap<K, L>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    L: Lattice + Clone + Send + 'static,
+{
+    type Event = (K, L);
+    type Error = IMapError;
+
+    fn observe(&mut self, f: Box<dyn Fn((K, L)) + Send + Sync>) -> Result<(), IMapError> {
+        self.add_handler(move |k, l| f((k.clone(), l.clone())))
+    }
+}
+
+impl HandlerPool {
+    // Start a pool with the given number of worker threads (at least one).
+    pub fn new(workers: usize) -> HandlerPool {
+        let queue = Arc::new(PoolQueue {
+            jobs: Mutex::new(VecDeque::new()),
+            cond: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            outstanding: WaitGroup::new(),
+            panicked: AtomicUsize::new(0),
+        });
+
+        for _ in 0..workers.max(1) {
+            let q = queue.clone();
+            thread::spawn(move || work(q));
+        }
+
+        HandlerPool(Arc::new(PoolHandle(queue)))
+    }
+
+    // Run f on the pool, counted towards quiescence.
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let q = &(self.0).0;
+        q.outstanding.add(1);
+        let mut jobs = match q.jobs.lock() {
+            Ok(x) => x,
+            // Only a panic inside VecDeque could poison this, the queue itself is intact.
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        jobs.push_back(Box::new(f));
+        q.cond.notify_one();
+    }
+
+    // Run f on the pool for every growth of the observed structure.
+    // Growth which happened before attaching is replayed.
+    // The structure only holds the pool weakly, as f often holds the structure,
+    // so growth after the pool is dropped runs nothing.
+    pub fn attach<O, F>(&self, o: &mut O, f: F) -> Result<(), O::Error>
+    where
+        O: Observable,
+        F: Fn(O::Event) + Send + Sync + 'static,
+    {
+        let pool: Weak<PoolHandle> = Arc::downgrade(&self.0);
+        let f = Arc::new(f);
+        o.observe(Box::new(move |e| {
+            if let Some(pool) = pool.upgrade() {
+                let f = f.clone();
+                HandlerPool(pool).spawn(move || f(e));
+            }
+        }))
+    }
+
+    // Block until every triggered callback has finished.
+    // Reports, and resets, the number of callbacks which panicked along the way.
+    pub fn quiesce(&self) -> Result<(), HandlerPoolError> {
+        let q = &(self.0).0;
+        q.outstanding.wait();
+        match q.panicked.swap(0, Ordering::SeqCst) {
+            0 => Ok(()),
+            n => Err(HandlerPoolError::HandlerPanicked(n)),
+        }
+    }
+}
+
+fn work(q: Arc<PoolQueue>) {
+    loop {
+        let job = {
+            let mut jobs = match q.jobs.lock() {
+                Ok(x) => x,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            loop {
+                if let Some(job) = jobs.pop_front() {
+                    break job;
+                }
+                if q.shutdown.load(Ordering::SeqCst) {
+                    return;
+                }
+                jobs = match q.cond.wait(jobs) {
+                    Ok(x) => x,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+            }
+        };
+
+        // A panicking handler must still be counted as done, or quiesce would never return.
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            q.panicked.fetch_add(1, Ordering::SeqCst);
+        }
+        q.outstanding.done();
+    }
+}
+
+#[derive(Debug)]
+pub enum HandlerPoolError {
+    HandlerPanicked(usize),
+}
+
+impl fmt::Display for HandlerPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandlerPoolError::HandlerPanicked(n) => {
+                write!(f, "{} handler(s) panicked before quiescence", n)
+            }
+        }
+    }
+}
+
+impl Error for HandlerPoolError {
+    fn description(&self) -> &str {
+        match self {
+            HandlerPoolError::HandlerPanicked(_) => "Handler(s) panicked before quiescence",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handler_pool_traversal() {
+        // A graph on 0..200, where n links to 2n and 2n+1.
+        let pool = HandlerPool::new(4);
+        let mut visited = ISet::<usize>::new();
+
+        let v = visited.clone();
+        pool.attach(&mut visited, move |n| {
+            let mut v = v.clone();
+            for m in [2 * n, 2 * n + 1].iter().filter(|m| **m < 200) {
+                v.insert(*m).unwrap();
+            }
+        })
+        .unwrap();
+
+        visited.insert(1).unwrap();
+        pool.quiesce().unwrap();
+
+        visited.freeze().unwrap();
+        let mut all: Vec<usize> = visited.iter().unwrap().collect();
+        all.sort();
+        assert_eq!((1..200).collect::<Vec<usize>>(), all);
+
+        // The handler holds visited, which must not keep the pool and its workers alive.
+        let handle = Arc::downgrade(&pool.0);
+        drop(pool);
+        assert!(handle.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_handler_pool_once_cell() {
+        let pool = HandlerPool::new(2);
+        let mut c1 = OnceCell::<usize>::new();
+        let mut c2 = OnceCell::<usize>::new();
+
+        // c1 filling fills c2, which is attached to afterwards.
+        let out = c2.clone();
+        pool.attach(&mut c1, move |v| {
+            let x = v.read().unwrap();
+            out.clone().write(x * 2).unwrap();
+        })
+        .unwrap();
+
+        let seen = Arc::new(AtomicUsize::new(0));
+        let s = seen.clone();
+        pool.attach(&mut c2, move |v| {
+            s.store(v.read().unwrap(), Ordering::SeqCst);
+        })
+        .unwrap();
+
+        c1.write(21).unwrap();
+        pool.quiesce().unwrap();
+        assert_eq!(42, seen.load(Ordering::SeqCst));
+
+        pool.spawn(|| panic!("Expected panic in handler"));
+        match pool.quiesce() {
+            Err(HandlerPoolError::HandlerPanicked(1)) => println!(),
+            other => panic!("Unexpected quiescence after panic {:?}", other),
+        };
+    }
+}
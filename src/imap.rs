@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::lattice::{Lattice, LatticeError};
+
+// A monotonic map, LVish's IMap.
+// Keys can only be added, and each key's value is itself a lattice:
+// inserting at a present key joins the new value into the old one.
+// A join which changes nothing is accepted silently, as with OnceCell's repeated writes,
+// and a join with no upper bound is a Conflict, as with OnceCell's mismatched writes.
+// Readers may block until a key is present, or until its value has reached a threshold,
+// but may not read values until the map has been frozen.
+// Handlers are called for every key, and again each time that key's value grows.
+pub struct IMap<K, L>(Arc<IMapMachine<K, L>>)
+where
+    K: Eq + Hash + Clone,
+    L: Lattice + Clone;
+
+impl<K: Eq + Hash + Clone, L: Lattice + Clone> Clone for IMap<K, L> {
+    fn clone(&self) -> IMap<K, L> {
+        IMap::<K, L>(self.0.clone())
+    }
+}
+
+impl<K: Eq + Hash + Clone, L: Lattice + Clone> Default for IMap<K, L> {
+    fn default() -> IMap<K, L> {
+        IMap::<K, L>::new()
+    }
+}
+
+type Handler<K, L> = Arc<dyn Fn(&K, &L) + Send + Sync>;
+
+struct IMapMachine<K, L> {
+    inner: Mutex<IMapInner<K, L>>,
+    cond: Condvar,
+}
+
+struct IMapInner<K, L> {
+    entries: HashMap<K, L>,
+    frozen: bool,
+    handlers: Vec<Handler<K, L>>,
+}
+
+#[derive(Debug)]
+pub enum IMapState {
+    Open,   // Keys may still arrive, values may still grow.
+    Frozen, // The contents are final and may be read.
+}
+
+impl fmt::Display for IMapState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IMapState::Open => write!(f, "Open"),
+            IMapState::Frozen => write!(f, "Frozen"),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, L: Lattice + Clone> IMap<K, L> {
+    pub fn new() -> IMap<K, L> {
+        IMap::<K, L>(Arc::new(IMapMachine::<K, L> {
+            inner: Mutex::new(IMapInner::<K, L> {
+                entries: HashMap::new(),
+                frozen: false,
+                handlers: Vec::new(),
+            }),
+            cond: Condvar::new(),
+        }))
+    }
+
+    pub fn state(&self) -> Result<IMapState, IMapError> {
+        match self.lock()?.frozen {
+            true => Ok(IMapState::Frozen),
+            false => Ok(IMapState::Open),
+        }
+    }
+
+    // Insert l at k, or join it into the value already there.
+    // Growth of a frozen map is an error, a no-op insert is not.
+    pub fn insert(&mut self, k: K, l: L) -> Result<(), IMapError> {
+        let (handlers, grown) = {
+            let mut inner = self.lock()?;
+            let frozen = inner.frozen;
+            let grown = match inner.entries.get_mut(&k) {
+                Some(old) => {
+                    // Join into a copy, the map is untouched unless the write is legal.
+                    let mut joined = old.clone();
+                    match joined.join(&l) {
+                        Err(LatticeError::Conflict) => return Err(IMapError::Conflict),
+                        Ok(false) => return Ok(()),
+                        Ok(true) if frozen => return Err(IMapError::InsertAfterFreeze),
+                        Ok(true) => {
+                            *old = joined.clone();
+                            joined
+                        }
+                    }
+                }
+                None if frozen => return Err(IMapError::InsertAfterFreeze),
+                None => {
+                    inner.entries.insert(k.clone(), l.clone());
+                    l
+                }
+            };
+
+            self.0.cond.notify_all();
+            (inner.handlers.clone(), grown)
+        };
+
+        // Outside the lock, so handlers may insert into this very map.
+        for h in handlers.iter() {
+            h(&k, &grown);
+        }
+        Ok(())
+    }
+
+    // Blocks until k is present.
+    pub fn wait_key(&self, k: &K) -> Result<(), IMapError> {
+        let mut inner = self.lock()?;
+        while !inner.entries.contains_key(k) {
+            inner = self
+                .0
+                .cond
+                .wait(inner)
+                .map_err(|_| IMapError::PoisonGuard)?;
+        }
+        Ok(())
+    }
+
+    // Blocks until the value at k is at or above threshold.
+    // Since values only grow, the answer can never change once given.
+    pub fn wait_value(&self, k: &K, threshold: &L) -> Result<(), IMapError> {
+        let mut inner = self.lock()?;
+        loop {
+            if let Some(l) = inner.entries.get(k) {
+                if threshold.leq(l) {
+                    return Ok(());
+                }
+                // No future value can be above both.
+                if l.clone().join(threshold).is_err() {
+                    return Err(IMapError::Conflict);
+                }
+            }
+            inner = self
+                .0
+                .cond
+                .wait(inner)
+                .map_err(|_| IMapError::PoisonGuard)?;
+        }
+    }
+
+    // Register a callback which is run for every key, and on each growth of its value.
+    // Existing entries are replayed on the calling thread, later growth
+    // is handled on the inserting thread.
+    pub fn add_handler<F>(&mut self, f: F) -> Result<(), IMapError>
+    where
+        F: Fn(&K, &L) + Send + Sync + 'static,
+    {
+        let h: Handler<K, L> = Arc::new(f);
+        let existing: Vec<(K, L)> = {
+            let mut inner = self.lock()?;
+            inner.handlers.push(h.clone());
+            inner
+                .entries
+                .iter()
+                .map(|(k, l)| (k.clone(), l.clone()))
+                .collect()
+        };
+
+        for (k, l) in existing.iter() {
+            h(k, l);
+        }
+        Ok(())
+    }
+
+    // Forbid any further growth. Freezing twice is harmless.
+    pub fn freeze(&mut self) -> Result<(), IMapError> {
+        self.lock()?.frozen = true;
+        Ok(())
+    }
+
+    // Read the final value at k, only permitted once frozen.
+    pub fn get(&self, k: &K) -> Result<Option<L>, IMapError> {
+        let inner = self.lock()?;
+        match inner.frozen {
+            false => Err(IMapError::NotFrozen),
+            true => Ok(inner.entries.get(k).cloned()),
+        }
+    }
+
+    // Iterate a snapshot of the contents, only permitted once frozen.
+    pub fn iter(&self) -> Result<std::vec::IntoIter<(K, L)>, IMapError> {
+        let inner = self.lock()?;
+        match inner.frozen {
+            false => Err(IMapError::NotFrozen),
+            true => Ok(inner
+                .entries
+                .iter()
+                .map(|(k, l)| (k.clone(), l.clone()))
+                .collect::<Vec<(K, L)>>()
+                .into_iter()),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, IMapInner<K, L>>, IMapError> {
+        self.0.inner.lock().map_err(|_| IMapError::PoisonGuard)
+    }
+}
+
+#[derive(Debug)]
+pub enum IMapError {
+    Conflict,
+    InsertAfterFreeze,
+    NotFrozen,
+    PoisonGuard,
+}
+
+impl fmt::Display for IMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IMapError::Conflict => write!(f, "IMap recieved a value at a key which conflicts with the value present"),
+            IMapError::InsertAfterFreeze => write!(f, "IMap is frozen, no key may be added or grown"),
+            IMapError::NotFrozen => write!(f, "IMap must be frozen before its values are observed"),
+            IMapError::PoisonGuard => write!(f, "A thread has panicked while holding the IMap's guard, this map is now inaccessible"),
+        }
+    }
+}
+
+impl Error for IMapError {
+    fn description(&self) -> &str {
+        match self {
+            IMapError::Conflict => {
+                "IMap recieved a value at a key which conflicts with the value present"
+            }
+            IMapError::InsertAfterFreeze => "IMap is frozen, no key may be added or grown",
+            IMapError::NotFrozen => "IMap must be frozen before its values are observed",
+            IMapError::PoisonGuard => {
+                "A thread has panicked while holding the IMap's guard, this map is now inaccessible"
+            }
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // A max register below 100, anything above is a contradiction.
+    #[derive(Clone, Debug, PartialEq)]
+    struct Capped(u32);
+
+    impl Lattice for Capped {
+        fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+            match other.0 >= 100 {
+                true => Err(LatticeError::Conflict),
+                false => match other.0 > self.0 {
+                    true => {
+                        self.0 = other.0;
+                        Ok(true)
+                    }
+                    false => Ok(false),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_i_map() {
+        let mut m1 = IMap::<&str, Capped>::new();
+        let mut q1 = m1.clone();
+        let grown = Arc::new(Mutex::new(Vec::new()));
+
+        let grown1 = grown.clone();
+        m1.add_handler(move |k, l| grown1.lock().unwrap().push((*k, l.0)))
+            .unwrap();
+
+        let h = thread::spawn(move || {
+            q1.wait_key(&"a").unwrap();
+            q1.wait_value(&"a", &Capped(5)).unwrap();
+            q1.insert("b", Capped(1)).unwrap();
+        });
+
+        m1.insert("a", Capped(3)).unwrap();
+        m1.insert("a", Capped(5)).unwrap();
+        m1.insert("a", Capped(4))
+            .expect("Insert below the present value was refused");
+        match m1.insert("a", Capped(100)) {
+            Err(IMapError::Conflict) => println!(),
+            _ => panic!("Conflicting insert was accepted"),
+        };
+
+        h.join().expect("Failed to Join Threads!");
+
+        match m1.get(&"a") {
+            Err(IMapError::NotFrozen) => println!(),
+            _ => panic!("Read an open map"),
+        };
+
+        m1.freeze().unwrap();
+        m1.insert("b", Capped(0))
+            .expect("No-op insert after freeze was refused");
+        match m1.insert("b", Capped(2)) {
+            Err(IMapError::InsertAfterFreeze) => println!(),
+            _ => panic!("Grew a key after freeze"),
+        };
+
+        assert_eq!(Some(Capped(5)), m1.get(&"a").unwrap());
+        let mut entries: Vec<(&str, u32)> = m1.iter().unwrap().map(|(k, l)| (k, l.0)).collect();
+        entries.sort();
+        assert_eq!(vec![("a", 5), ("b", 1)], entries);
+        assert_eq!(vec![("a", 3), ("a", 5), ("b", 1)], *grown.lock().unwrap());
+    }
+}
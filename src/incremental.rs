@@ -0,0 +1,589 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard};
+
+use crate::ping::spark;
+
+// Incremental recomputation, after Salsa's red-green algorithm.
+// A Database holds inputs, which are set from outside, and queries, which are pure functions
+// of inputs and other queries. A query records what it reads as it runs, and its results are
+// memoised along with those dependencies. Every change to an input starts a new revision.
+// Asking for a memoised result in a later revision first checks its dependencies, bringing
+// each up to date (in parallel on sparks), and only runs the query again if one of them changed.
+// A result which comes out equal to the last one counts as unchanged, so whatever depends on it
+// is not run again either: the change stops propagating there.
+// Queries must not depend on themselves, directly or otherwise, or they deadlock.
+// Nor may they set inputs: set waits for running gets, including the one running the query.
+// That is refused with SetInQuery on the query's own threads, but not on threads it starts.
+// Likewise a query reads other queries through its QueryCtx, which records them as dependencies.
+// Database::get inside a query would record nothing, and could deadlock against a waiting set,
+// so it is refused with GetInQuery.
+
+static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // How many queries this thread is running, one inside another.
+    static IN_QUERY: Cell<usize> = const { Cell::new(0) };
+}
+
+// Counts a running query for as long as it lives, panics included.
+struct Running;
+
+impl Running {
+    fn start() -> Running {
+        IN_QUERY.with(|n| n.set(n.get() + 1));
+        Running
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        IN_QUERY.with(|n| n.set(n.get() - 1));
+    }
+}
+
+pub struct Database(Arc<DbInner>);
+
+impl Clone for Database {
+    fn clone(&self) -> Database {
+        Database(self.0.clone())
+    }
+}
+
+impl Default for Database {
+    fn default() -> Database {
+        Database::new()
+    }
+}
+
+struct DbInner {
+    id: usize,
+    revision: AtomicU64,
+    // Held for reading while a get runs, and for writing while an input is set,
+    // so that a computation only ever sees one revision.
+    epoch: RwLock<()>,
+}
+
+// An input cell, remembering the revision it last changed in.
+pub struct Input<T>(Arc<InputInner<T>>);
+
+impl<T> Clone for Input<T> {
+    fn clone(&self) -> Input<T> {
+        Input(self.0.clone())
+    }
+}
+
+struct InputInner<T> {
+    db: usize,
+    slot: RwLock<(T, u64)>,
+}
+
+// A memoised query from K to V.
+pub struct Query<K, V>(Arc<QueryInner<K, V>>);
+
+impl<K, V> Clone for Query<K, V> {
+    fn clone(&self) -> Query<K, V> {
+        Query(self.0.clone())
+    }
+}
+
+type QueryFn<K, V> = Box<dyn Fn(&QueryCtx, &K) -> Result<V, QueryError> + Send + Sync>;
+
+struct QueryInner<K, V> {
+    db: usize,
+    f: QueryFn<K, V>,
+    // Each key's memo is locked while being checked or recomputed,
+    // so concurrent askers wait for one computation rather than repeat it.
+    memos: Mutex<HashMap<K, Slot<V>>>,
+}
+
+type Slot<V> = Arc<Mutex<Option<Memo<V>>>>;
+
+struct Memo<V> {
+    value: V,
+    changed_at: u64,
+    verified_at: u64,
+    deps: Vec<Dep>,
+}
+
+// Something a query read: an input, or another query at some key.
+trait Dependency: Send + Sync {
+    // Bring it up to date, and return the revision in which it last changed.
+    fn changed_at(&self, db: &Database) -> Result<u64, QueryError>;
+    fn is_input(&self) -> bool;
+}
+
+type Dep = Arc<dyn Dependency>;
+
+impl<T: Send + Sync> Dependency for InputInner<T> {
+    fn changed_at(&self, _db: &Database) -> Result<u64, QueryError> {
+        let slot = self.slot.read().map_err(|_| QueryError::PoisonGuard)?;
+        Ok(slot.1)
+    }
+
+    fn is_input(&self) -> bool {
+        true
+    }
+}
+
+struct QueryDep<K, V> {
+    query: Query<K, V>,
+    key: K,
+}
+
+impl<K, V> Dependency for QueryDep<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: PartialEq + Clone + Send + Sync + 'static,
+{
+    fn changed_at(&self, db: &Database) -> Result<u64, QueryError> {
+        Ok(fetch(db, &self.query, &self.key)?.1)
+    }
+
+    fn is_input(&self) -> bool {
+        false
+    }
+}
+
+// Handed to a running query, recording everything it reads.
+pub struct QueryCtx {
+    db: Database,
+    deps: Mutex<Vec<Dep>>,
+}
+
+impl Database {
+    pub fn new() -> Database {
+        Database(Arc::new(DbInner {
+            id: NEXT_DB.fetch_add(1, Ordering::SeqCst),
+            revision: AtomicU64::new(1),
+            epoch: RwLock::new(()),
+        }))
+    }
+
+    // The current revision, which every change to an input advances.
+    pub fn revision(&self) -> u64 {
+        self.0.revision.load(Ordering::SeqCst)
+    }
+
+    pub fn new_input<T>(&self, t: T) -> Input<T>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+    {
+        Input(Arc::new(InputInner {
+            db: self.0.id,
+            slot: RwLock::new((t, self.revision())),
+        }))
+    }
+
+    // Change an input, waiting for running gets to finish first.
+    // Setting an equal value changes nothing, and starts no new revision.
+    pub fn set<T>(&self, i: &Input<T>, t: T) -> Result<(), QueryError>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+    {
+        self.check(i.0.db)?;
+        if IN_QUERY.with(|n| n.get()) > 0 {
+            return Err(QueryError::SetInQuery);
+        }
+        let _epoch = self.0.epoch.write().map_err(|_| QueryError::PoisonGuard)?;
+        let mut slot = i.0.slot.write().map_err(|_| QueryError::PoisonGuard)?;
+        if slot.0 != t {
+            let rev = self.0.revision.fetch_add(1, Ordering::SeqCst) + 1;
+            *slot = (t, rev);
+        }
+        Ok(())
+    }
+
+    pub fn read<T>(&self, i: &Input<T>) -> Result<T, QueryError>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+    {
+        self.check(i.0.db)?;
+        let slot = i.0.slot.read().map_err(|_| QueryError::PoisonGuard)?;
+        Ok(slot.0.clone())
+    }
+
+    pub fn query<K, V, F>(&self, f: F) -> Query<K, V>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+        V: PartialEq + Clone + Send + Sync + 'static,
+        F: Fn(&QueryCtx, &K) -> Result<V, QueryError> + Send + Sync + 'static,
+    {
+        Query(Arc::new(QueryInner {
+            db: self.0.id,
+            f: Box::new(f),
+            memos: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // The query's result at k, as of the current revision.
+    pub fn get<K, V>(&self, q: &Query<K, V>, k: K) -> Result<V, QueryError>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+        V: PartialEq + Clone + Send + Sync + 'static,
+    {
+        let _epoch = self.enter()?;
+        Ok(fetch(self, q, &k)?.0)
+    }
+
+    // The query's results at every key, computed in parallel.
+    pub fn get_many<K, V>(&self, q: &Query<K, V>, ks: Vec<K>) -> Result<Vec<V>, QueryError>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+        V: PartialEq + Clone + Send + Sync + 'static,
+    {
+        let _epoch = self.enter()?;
+        fetch_many(self, q, ks)
+    }
+
+    // Hold the epoch for a get from outside any query.
+    fn enter(&self) -> Result<RwLockReadGuard<'_, ()>, QueryError> {
+        if IN_QUERY.with(|n| n.get()) > 0 {
+            return Err(QueryError::GetInQuery);
+        }
+        self.0.epoch.read().map_err(|_| QueryError::PoisonGuard)
+    }
+
+    fn check(&self, db: usize) -> Result<(), QueryError> {
+        match db == self.0.id {
+            true => Ok(()),
+            false => Err(QueryError::ForeignDatabase),
+        }
+    }
+}
+
+impl QueryCtx {
+    pub fn read<T>(&self, i: &Input<T>) -> Result<T, QueryError>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+    {
+        let t = self.db.read(i)?;
+        self.record(i.0.clone());
+        Ok(t)
+    }
+
+    pub fn get<K, V>(&self, q: &Query<K, V>, k: K) -> Result<V, QueryError>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+        V: PartialEq + Clone + Send + Sync + 'static,
+    {
+        let (v, _) = fetch(&self.db, q, &k)?;
+        self.record(Arc::new(QueryDep {
+            query: q.clone(),
+            key: k,
+        }));
+        Ok(v)
+    }
+
+    // As get at every key, computed in parallel.
+    pub fn get_many<K, V>(&self, q: &Query<K, V>, ks: Vec<K>) -> Result<Vec<V>, QueryError>
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+        V: PartialEq + Clone + Send + Sync + 'static,
+    {
+        let vs = fetch_many(&self.db, q, ks.clone())?;
+        for k in ks {
+            self.record(Arc::new(QueryDep {
+                query: q.clone(),
+                key: k,
+            }));
+        }
+        Ok(vs)
+    }
+
+    fn record(&self, d: Dep) {
+        if let Ok(mut deps) = self.deps.lock() {
+            deps.push(d);
+        }
+    }
+}
+
+// The result at k and the revision it last changed in, recomputing only if a dependency changed.
+fn fetch<K, V>(db: &Database, q: &Query<K, V>, k: &K) -> Result<(V, u64), QueryError>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: PartialEq + Clone + Send + Sync + 'static,
+{
+    db.check(q.0.db)?;
+    let slot = {
+        // Only a panic inside the HashMap could poison this, the memos themselves are intact.
+        let mut memos = q.0.memos.lock().unwrap_or_else(PoisonError::into_inner);
+        memos.entry(k.clone()).or_default().clone()
+    };
+    // A query which panicked under a get poisons its slot. The memo is dropped, so it runs again.
+    let mut memo = match slot.lock() {
+        Ok(x) => x,
+        Err(poisoned) => {
+            slot.clear_poison();
+            let mut x = poisoned.into_inner();
+            *x = None;
+            x
+        }
+    };
+    let now = db.revision();
+
+    if let Some(m) = memo.as_mut() {
+        if m.verified_at == now || !changed_since(db, &m.deps, m.verified_at)? {
+            m.verified_at = now;
+            return Ok((m.value.clone(), m.changed_at));
+        }
+    }
+
+    let ctx = QueryCtx {
+        db: db.clone(),
+        deps: Mutex::new(Vec::new()),
+    };
+    let v = {
+        let _running = Running::start();
+        (q.0.f)(&ctx, k)?
+    };
+    let deps = ctx.deps.into_inner().map_err(|_| QueryError::PoisonGuard)?;
+
+    // Early cutoff: an equal result keeps its old revision.
+    let changed_at = match memo.as_ref() {
+        Some(m) if m.value == v => m.changed_at,
+        _ => now,
+    };
+    *memo = Some(Memo {
+        value: v.clone(),
+        changed_at,
+        verified_at: now,
+        deps,
+    });
+    Ok((v, changed_at))
+}
+
+// Whether any dependency changed after rev. Inputs are checked first, as they are cheap,
+// then queries, in parallel when there are several.
+fn changed_since(db: &Database, deps: &[Dep], rev: u64) -> Result<bool, QueryError> {
+    for d in deps.iter().filter(|d| d.is_input()) {
+        if d.changed_at(db)? > rev {
+            return Ok(true);
+        }
+    }
+
+    let queries: Vec<Dep> = deps.iter().filter(|d| !d.is_input()).cloned().collect();
+    if queries.len() < 2 {
+        for d in queries {
+            if d.changed_at(db)? > rev {
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    let sparks: Vec<_> = queries
+        .into_iter()
+        .map(|d| {
+            let db = db.clone();
+            spark(d, Box::new(move |d| guarded(|| d.changed_at(&db))))
+        })
+        .collect();
+    let mut changed = Ok(false);
+    for mut s in sparks {
+        let r = s.read().unwrap_or(Err(QueryError::QueryPanicked));
+        changed = match (changed, r) {
+            (Err(err), _) | (Ok(_), Err(err)) => Err(err),
+            (Ok(c), Ok(at)) => Ok(c || at > rev),
+        };
+    }
+    changed
+}
+
+fn fetch_many<K, V>(db: &Database, q: &Query<K, V>, ks: Vec<K>) -> Result<Vec<V>, QueryError>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: PartialEq + Clone + Send + Sync + 'static,
+{
+    let sparks: Vec<_> = ks
+        .into_iter()
+        .map(|k| {
+            let (db, q) = (db.clone(), q.clone());
+            spark(k, Box::new(move |k| guarded(|| fetch(&db, &q, &k))))
+        })
+        .collect();
+    // Every spark is read, so that no query is still running once we return.
+    let mut vs = Ok(Vec::with_capacity(sparks.len()));
+    for mut s in sparks {
+        let r = s.read().unwrap_or(Err(QueryError::QueryPanicked));
+        vs = match (vs, r) {
+            (Err(err), _) | (Ok(_), Err(err)) => Err(err),
+            (Ok(mut vs), Ok((v, _))) => {
+                vs.push(v);
+                Ok(vs)
+            }
+        };
+    }
+    vs
+}
+
+// A panicking query would otherwise never answer its spark.
+fn guarded<T, F: FnOnce() -> Result<T, QueryError>>(f: F) -> Result<T, QueryError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(Err(QueryError::QueryPanicked))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    ForeignDatabase,
+    QueryPanicked,
+    SetInQuery,
+    GetInQuery,
+    PoisonGuard,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::ForeignDatabase => {
+                write!(f, "The input or query belongs to another database")
+            }
+            QueryError::QueryPanicked => write!(f, "A query panicked"),
+            QueryError::SetInQuery => write!(f, "An input cannot be set from inside a query"),
+            QueryError::GetInQuery => {
+                write!(f, "A query must get other queries through its QueryCtx")
+            }
+            QueryError::PoisonGuard => write!(f, "A memo guard was poisoned"),
+        }
+    }
+}
+
+impl Error for QueryError {
+    fn description(&self) -> &str {
+        match self {
+            QueryError::ForeignDatabase => "The input or query belongs to another database",
+            QueryError::QueryPanicked => "A query panicked",
+            QueryError::SetInQuery => "An input cannot be set from inside a query",
+            QueryError::GetInQuery => "A query must get other queries through its QueryCtx",
+            QueryError::PoisonGuard => "A memo guard was poisoned",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn counter() -> (Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let c = Arc::new(AtomicUsize::new(0));
+        (c.clone(), c)
+    }
+
+    #[test]
+    fn test_incremental_cutoff() {
+        let db = Database::new();
+        let files: Vec<Input<String>> = ["fn a", "fn b", "fn c"]
+            .iter()
+            .map(|s| db.new_input(s.to_string()))
+            .collect();
+
+        // Per file: its length, then whether that is even. The total counts the even files.
+        let (len_runs, lr) = counter();
+        let fs = files.clone();
+        let len = db.query(move |ctx, i: &usize| {
+            lr.fetch_add(1, Ordering::SeqCst);
+            Ok(ctx.read(&fs[*i])?.len())
+        });
+        let (even_runs, er) = counter();
+        let l = len.clone();
+        let even = db.query(move |ctx, i: &usize| {
+            er.fetch_add(1, Ordering::SeqCst);
+            Ok(ctx.get(&l, *i)? % 2 == 0)
+        });
+        let (total_runs, tr) = counter();
+        let e = even.clone();
+        let total = db.query(move |ctx, _: &()| {
+            tr.fetch_add(1, Ordering::SeqCst);
+            let evens = ctx.get_many(&e, vec![0, 1, 2])?;
+            Ok(evens.into_iter().filter(|x| *x).count())
+        });
+
+        assert_eq!(Ok(3), db.get(&total, ()));
+        assert_eq!(Ok(3), db.get(&total, ()));
+        let runs = |c: &Arc<AtomicUsize>| c.load(Ordering::SeqCst);
+        assert_eq!(
+            (3, 3, 1),
+            (runs(&len_runs), runs(&even_runs), runs(&total_runs))
+        );
+
+        // File 1's length runs again, but is unchanged, so nothing after it runs.
+        db.set(&files[1], "fn x".to_string()).unwrap();
+        assert_eq!(Ok(3), db.get(&total, ()));
+        assert_eq!(
+            (4, 3, 1),
+            (runs(&len_runs), runs(&even_runs), runs(&total_runs))
+        );
+
+        // Its length changes, but is still even, so the total does not run.
+        db.set(&files[1], "fn bb".to_string()).unwrap();
+        db.set(&files[1], "fn bbb".to_string()).unwrap();
+        assert_eq!(Ok(3), db.get(&total, ()));
+        assert_eq!(
+            (5, 4, 1),
+            (runs(&len_runs), runs(&even_runs), runs(&total_runs))
+        );
+
+        // Now file 2's is odd, and the change reaches the total.
+        db.set(&files[2], "fn cc".to_string()).unwrap();
+        assert_eq!(Ok(2), db.get(&total, ()));
+        assert_eq!(
+            (6, 5, 2),
+            (runs(&len_runs), runs(&even_runs), runs(&total_runs))
+        );
+
+        // Setting an equal value is no change at all.
+        let rev = db.revision();
+        db.set(&files[0], "fn a".to_string()).unwrap();
+        assert_eq!(rev, db.revision());
+        assert_eq!(Ok(vec![4, 6, 5]), db.get_many(&len, vec![0, 1, 2]));
+        assert_eq!(6, runs(&len_runs));
+    }
+
+    #[test]
+    fn test_incremental_errors() {
+        let db = Database::new();
+        let other = Database::new();
+        let i = db.new_input(1);
+        assert_eq!(Err(QueryError::ForeignDatabase), other.set(&i, 2));
+
+        let boom = db.query(move |ctx, k: &u8| match *k {
+            0 => panic!("Expected panic in query"),
+            _ => Ok(ctx.read(&i)? + *k as i32),
+        });
+        assert_eq!(Ok(vec![2, 3]), db.get_many(&boom, vec![1, 2]));
+        assert_eq!(
+            Err(QueryError::QueryPanicked),
+            db.get_many(&boom, vec![1, 0])
+        );
+        assert_eq!(Err(QueryError::ForeignDatabase), other.get(&boom, 1));
+
+        // A panic under a direct get poisons nothing for the next one.
+        let i = db.new_input(false);
+        let j = i.clone();
+        let flaky = db.query(move |ctx, _: &()| match ctx.read(&j)? {
+            false => panic!("Expected panic in query"),
+            true => Ok(1),
+        });
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| db.get(&flaky, ()))).is_err());
+        db.set(&i, true).unwrap();
+        assert_eq!(Ok(1), db.get(&flaky, ()));
+
+        // Setting an input from a query is refused, rather than waiting on itself.
+        let d = db.clone();
+        let setter = db.query(move |_, _: &()| d.set(&i, false));
+        assert_eq!(Err(QueryError::SetInQuery), db.get(&setter, ()));
+        let (d, f) = (db.clone(), flaky.clone());
+        let getter = db.query(move |_, _: &()| d.get(&f, ()));
+        assert_eq!(Err(QueryError::GetInQuery), db.get(&getter, ()));
+        assert_eq!(Ok(1), db.get(&flaky, ()));
+    }
+}
@@ -0,0 +1,310 @@
+use std::ops::{Add, Div, Mul, Sub};
+
+use crate::lattice::{Lattice, LatticeError};
+use crate::propagator::{Cell, Network, PropagatorError};
+
+// Intervals of reals, the motivating example of Radul's propagators.
+// An interval is partial information about a quantity: it lies somewhere between lo and hi.
+// Independent estimates narrow each other, so the join is intersection,
+// and estimates which do not overlap are a contradiction.
+// The propagators below run in every direction: knowing any two of a, b and a + b
+// narrows the third. Computed bounds are rounded outwards, so they never exclude the true value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    lo: f64,
+    hi: f64,
+}
+
+impl Lattice for Interval {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        let meet = Interval {
+            lo: self.lo.max(other.lo),
+            hi: self.hi.min(other.hi),
+        };
+        if meet.is_empty() {
+            return Err(LatticeError::Conflict);
+        }
+        let grew = meet != *self;
+        *self = meet;
+        Ok(grew)
+    }
+
+    fn is_top(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl Interval {
+    // Empty when lo > hi, or either bound is NaN.
+    pub fn new(lo: f64, hi: f64) -> Interval {
+        Interval { lo, hi }
+    }
+
+    pub fn exact(v: f64) -> Interval {
+        Interval { lo: v, hi: v }
+    }
+
+    // Knowing nothing at all.
+    pub fn whole() -> Interval {
+        Interval {
+            lo: f64::NEG_INFINITY,
+            hi: f64::INFINITY,
+        }
+    }
+
+    pub fn lo(&self) -> f64 {
+        self.lo
+    }
+
+    pub fn hi(&self) -> f64 {
+        self.hi
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lo > self.hi || self.lo.is_nan() || self.hi.is_nan()
+    }
+
+    pub fn contains(&self, v: f64) -> bool {
+        self.lo <= v && v <= self.hi
+    }
+
+    pub fn width(&self) -> f64 {
+        self.hi - self.lo
+    }
+
+    // The non-negative square root, of the non-negative part.
+    pub fn sqrt(self) -> Interval {
+        match self.hi < 0.0 {
+            true => Interval::new(1.0, 0.0),
+            false => outward(self.lo.max(0.0).sqrt(), self.hi.sqrt()),
+        }
+    }
+
+    pub fn square(self) -> Interval {
+        let (a, b) = (self.lo * self.lo, self.hi * self.hi);
+        match self.contains(0.0) {
+            true => outward(0.0, a.max(b)),
+            false => outward(a.min(b), a.max(b)),
+        }
+    }
+}
+
+// Bounds widened by a unit in the last place, covering rounding in whatever computed them.
+fn outward(lo: f64, hi: f64) -> Interval {
+    let down = match lo.is_finite() {
+        true => lo.next_down(),
+        false => lo,
+    };
+    let up = match hi.is_finite() {
+        true => hi.next_up(),
+        false => hi,
+    };
+    Interval { lo: down, hi: up }
+}
+
+impl Add for Interval {
+    type Output = Interval;
+
+    fn add(self, other: Interval) -> Interval {
+        outward(self.lo + other.lo, self.hi + other.hi)
+    }
+}
+
+impl Sub for Interval {
+    type Output = Interval;
+
+    fn sub(self, other: Interval) -> Interval {
+        outward(self.lo - other.hi, self.hi - other.lo)
+    }
+}
+
+impl Mul for Interval {
+    type Output = Interval;
+
+    fn mul(self, other: Interval) -> Interval {
+        // Zero times an infinite bound is zero here, not NaN.
+        let p = |x: f64, y: f64| match x == 0.0 || y == 0.0 {
+            true => 0.0,
+            false => x * y,
+        };
+        let ps = [
+            p(self.lo, other.lo),
+            p(self.lo, other.hi),
+            p(self.hi, other.lo),
+            p(self.hi, other.hi),
+        ];
+        let lo = ps.iter().cloned().fold(f64::INFINITY, f64::min);
+        let hi = ps.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        outward(lo, hi)
+    }
+}
+
+impl Div for Interval {
+    type Output = Interval;
+
+    // Dividing by an interval around zero says nothing.
+    fn div(self, other: Interval) -> Interval {
+        match other.contains(0.0) {
+            true => Interval::whole(),
+            false => self * outward(1.0 / other.hi, 1.0 / other.lo),
+        }
+    }
+}
+
+// Attach f(inputs) -> out, once every input knows something.
+fn relate<F>(
+    net: &Network,
+    ins: [Cell<Interval>; 2],
+    out: Cell<Interval>,
+    f: F,
+) -> Result<(), PropagatorError>
+where
+    F: Fn(Interval, Interval) -> Interval + Send + Sync + 'static,
+{
+    net.lift2(&ins[0], &ins[1], &out, move |x, y| f(*x, *y))
+}
+
+// sum = a + b, in every direction.
+pub fn add(
+    net: &Network,
+    a: &Cell<Interval>,
+    b: &Cell<Interval>,
+    sum: &Cell<Interval>,
+) -> Result<(), PropagatorError> {
+    relate(net, [*a, *b], *sum, |x, y| x + y)?;
+    relate(net, [*sum, *b], *a, |s, y| s - y)?;
+    relate(net, [*sum, *a], *b, |s, x| s - x)
+}
+
+// diff = a - b, in every direction.
+pub fn sub(
+    net: &Network,
+    a: &Cell<Interval>,
+    b: &Cell<Interval>,
+    diff: &Cell<Interval>,
+) -> Result<(), PropagatorError> {
+    add(net, b, diff, a)
+}
+
+// prod = a * b, in every direction.
+pub fn mul(
+    net: &Network,
+    a: &Cell<Interval>,
+    b: &Cell<Interval>,
+    prod: &Cell<Interval>,
+) -> Result<(), PropagatorError> {
+    relate(net, [*a, *b], *prod, |x, y| x * y)?;
+    relate(net, [*prod, *b], *a, |p, y| p / y)?;
+    relate(net, [*prod, *a], *b, |p, x| p / x)
+}
+
+// quot = a / b, in every direction.
+pub fn div(
+    net: &Network,
+    a: &Cell<Interval>,
+    b: &Cell<Interval>,
+    quot: &Cell<Interval>,
+) -> Result<(), PropagatorError> {
+    mul(net, b, quot, a)
+}
+
+// root = sqrt(a), the non-negative root, in both directions.
+pub fn sqrt(
+    net: &Network,
+    a: &Cell<Interval>,
+    root: &Cell<Interval>,
+) -> Result<(), PropagatorError> {
+    net.lift1(a, root, |x| x.sqrt())?;
+    net.lift1(root, a, |r| r.square())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn near(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn test_interval_arithmetic() {
+        let net = Network::new();
+        let (a, b, c) = (net.cell(), net.cell(), net.cell());
+        add(&net, &a, &b, &c).unwrap();
+        net.add(&c, Interval::exact(10.0)).unwrap();
+        net.add(&a, Interval::new(3.0, 4.0)).unwrap();
+        net.run().unwrap();
+        let bv = net.content(&b).unwrap().unwrap();
+        assert!(near(6.0, bv.lo()) && near(7.0, bv.hi()));
+
+        // A square root and its square agree whichever is known.
+        let (x, r) = (net.cell(), net.cell());
+        sqrt(&net, &x, &r).unwrap();
+        net.add(&r, Interval::new(2.0, 3.0)).unwrap();
+        net.run().unwrap();
+        let xv = net.content(&x).unwrap().unwrap();
+        assert!(near(4.0, xv.lo()) && near(9.0, xv.hi()));
+
+        let res = net.add(&a, Interval::new(5.0, 6.0));
+        assert_eq!(Err(PropagatorError::Contradiction(a.id())), res);
+
+        // An empty interval is a contradiction even in a cell which knew nothing.
+        let net = Network::new();
+        let empty = net.cell();
+        let res = net.add(&empty, Interval::new(5.0, 1.0));
+        assert_eq!(Err(PropagatorError::Contradiction(empty.id())), res);
+        let net = Network::new();
+        let (x, r) = (net.cell(), net.cell());
+        sqrt(&net, &x, &r).unwrap();
+        net.add(&x, Interval::new(-4.0, -1.0)).unwrap();
+        assert_eq!(Err(PropagatorError::Contradiction(r.id())), net.run());
+        assert_eq!(None, net.content(&r).unwrap());
+    }
+
+    #[test]
+    fn test_interval_barometer() {
+        // Radul's building height, measured twice with a barometer, the estimates fused.
+        let net = Network::with_workers(4);
+        let cell = || net.cell::<Interval>();
+
+        // Dropped from the roof: h = g t^2 / 2.
+        let (t, g, h) = (cell(), cell(), cell());
+        let (t2, gt2, half) = (cell(), cell(), cell());
+        sqrt(&net, &t2, &t).unwrap();
+        mul(&net, &g, &t2, &gt2).unwrap();
+        mul(&net, &half, &gt2, &h).unwrap();
+
+        // Shadows: h / building shadow = barometer height / barometer shadow.
+        let (bs, bh, bsh, ratio) = (cell(), cell(), cell(), cell());
+        div(&net, &bh, &bsh, &ratio).unwrap();
+        div(&net, &h, &bs, &ratio).unwrap();
+
+        // Readings arrive concurrently, in any order.
+        let readings = vec![
+            (t, Interval::new(2.9, 3.1)),
+            (g, Interval::new(9.789, 9.832)),
+            (half, Interval::exact(0.5)),
+            (bs, Interval::new(54.9, 55.1)),
+            (bh, Interval::new(0.3, 0.32)),
+            (bsh, Interval::new(0.36, 0.37)),
+        ];
+        let writers: Vec<_> = readings
+            .into_iter()
+            .map(|(c, v)| {
+                let net = net.clone();
+                thread::spawn(move || net.add(&c, v).unwrap())
+            })
+            .collect();
+        for w in writers {
+            w.join().expect("Failed to Join Threads!");
+        }
+        net.run().unwrap();
+
+        let hv = net.content(&h).unwrap().unwrap();
+        assert!(near(44.514, hv.lo()) && near(47.243, hv.hi()));
+        // Fusion flows back: the fall time is now known better than it was measured.
+        let tv = net.content(&t).unwrap().unwrap();
+        assert!(tv.lo() > 3.0 && near(3.1, tv.hi()));
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+// A monotonic set, LVish's ISet.
+// Elements can only be added, and adding an element which is already present
+// is a no-op rather than an error, in the spirit of OnceCell's repeated writes.
+// Readers may block until a given element (or a number of elements) is present,
+// but may not ask what the set holds until it has been frozen.
+// Handlers are called once for every element, whether it arrived before or after
+// the handler was added.
+pub struct ISet<T>(Arc<ISetMachine<T>>)
+where
+    T: Eq + Hash + Clone;
+
+impl<T: Eq + Hash + Clone> Clone for ISet<T> {
+    fn clone(&self) -> ISet<T> {
+        ISet::<T>(self.0.clone())
+    }
+}
+
+impl<T: Eq + Hash + Clone> Default for ISet<T> {
+    fn default() -> ISet<T> {
+        ISet::<T>::new()
+    }
+}
+
+type Handler<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+struct ISetMachine<T> {
+    inner: Mutex<ISetInner<T>>,
+    cond: Condvar,
+}
+
+struct ISetInner<T> {
+    elems: HashSet<T>,
+    frozen: bool,
+    handlers: Vec<Handler<T>>,
+}
+
+#[derive(Debug)]
+pub enum ISetState {
+    Open,   // Elements may still arrive.
+    Frozen, // The contents are final and may be iterated.
+}
+
+impl fmt::Display for ISetState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ISetState::Open => write!(f, "Open"),
+            ISetState::Frozen => write!(f, "Frozen"),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> ISet<T> {
+    pub fn new() -> ISet<T> {
+        ISet::<T>(Arc::new(ISetMachine::<T> {
+            inner: Mutex::new(ISetInner::<T> {
+                elems: HashSet::new(),
+                frozen: false,
+                handlers: Vec::new(),
+            }),
+            cond: Condvar::new(),
+        }))
+    }
+
+    pub fn state(&self) -> Result<ISetState, ISetError> {
+        match self.lock()?.frozen {
+            true => Ok(ISetState::Frozen),
+            false => Ok(ISetState::Open),
+        }
+    }
+
+    // Add an element to the set. Adding a present element is always allowed,
+    // adding a new element to a frozen set is an error.
+    pub fn insert(&mut self, t: T) -> Result<(), ISetError> {
+        let handlers = {
+            let mut inner = self.lock()?;
+            if inner.elems.contains(&t) {
+                return Ok(());
+            }
+            if inner.frozen {
+                return Err(ISetError::InsertAfterFreeze);
+            }
+
+            inner.elems.insert(t.clone());
+            self.0.cond.notify_all();
+            inner.handlers.clone()
+        };
+
+        // Outside the lock, so handlers may insert into this very set.
+        for h in handlers.iter() {
+            h(&t);
+        }
+        Ok(())
+    }
+
+    // Blocks until t is a member of the set.
+    pub fn wait_elem(&self, t: &T) -> Result<(), ISetError> {
+        let mut inner = self.lock()?;
+        while !inner.elems.contains(t) {
+            inner = self
+                .0
+                .cond
+                .wait(inner)
+                .map_err(|_| ISetError::PoisonGuard)?;
+        }
+        Ok(())
+    }
+
+    // Blocks until the set holds at least n elements.
+    pub fn wait_size(&self, n: usize) -> Result<(), ISetError> {
+        let mut inner = self.lock()?;
+        while inner.elems.len() < n {
+            inner = self
+                .0
+                .cond
+                .wait(inner)
+                .map_err(|_| ISetError::PoisonGuard)?;
+        }
+        Ok(())
+    }
+
+    // Register a callback which is run once for every element of the set.
+    // Existing elements are replayed on the calling thread, later elements
+    // are handled on the inserting thread.
+    pub fn add_handler<F>(&mut self, f: F) -> Result<(), ISetError>
+    where
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        let h: Handler<T> = Arc::new(f);
+        let existing: Vec<T> = {
+            let mut inner = self.lock()?;
+            inner.handlers.push(h.clone());
+            inner.elems.iter().cloned().collect()
+        };
+
+        for t in existing.iter() {
+            h(t);
+        }
+        Ok(())
+    }
+
+    // Forbid any further growth. Freezing twice is harmless.
+    pub fn freeze(&mut self) -> Result<(), ISetError> {
+        self.lock()?.frozen = true;
+        Ok(())
+    }
+
+    // Iterate a snapshot of the contents, only permitted once frozen.
+    pub fn iter(&self) -> Result<std::vec::IntoIter<T>, ISetError> {
+        let inner = self.lock()?;
+        match inner.frozen {
+            false => Err(ISetError::NotFrozen),
+            true => Ok(inner.elems.iter().cloned().collect::<Vec<T>>().into_iter()),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, ISetInner<T>>, ISetError> {
+        self.0.inner.lock().map_err(|_| ISetError::PoisonGuard)
+    }
+}
+
+#[derive(Debug)]
+pub enum ISetError {
+    InsertAfterFreeze,
+    NotFrozen,
+    PoisonGuard,
+}
+
+impl fmt::Display for ISetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ISetError::InsertAfterFreeze => write!(f, "ISet is frozen, no new elements may be inserted"),
+            ISetError::NotFrozen => write!(f, "ISet must be frozen before its contents are observed"),
+            ISetError::PoisonGuard => write!(f, "A thread has panicked while holding the ISet's guard, this set is now inaccessible"),
+        }
+    }
+}
+
+impl Error for ISetError {
+    fn description(&self) -> &str {
+        match self {
+            ISetError::InsertAfterFreeze => "ISet is frozen, no new elements may be inserted",
+            ISetError::NotFrozen => "ISet must be frozen before its contents are observed",
+            ISetError::PoisonGuard => {
+                "A thread has panicked while holding the ISet's guard, this set is now inaccessible"
+            }
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_i_set() {
+        let mut s1 = ISet::<usize>::new();
+        let mut q1 = s1.clone();
+        let seen = Arc::new(AtomicUsize::new(0));
+
+        let h = thread::spawn(move || {
+            q1.wait_elem(&3).unwrap();
+            q1.wait_size(3).unwrap();
+            q1.insert(4).unwrap();
+        });
+
+        s1.insert(1).unwrap();
+        let seen1 = seen.clone();
+        s1.add_handler(move |x| {
+            seen1.fetch_add(*x, Ordering::SeqCst);
+        })
+        .unwrap();
+        s1.insert(2).unwrap();
+        s1.insert(3).unwrap();
+        s1.insert(3).expect("Repeated insert was refused");
+
+        h.join().expect("Failed to Join Threads!");
+
+        match s1.iter() {
+            Err(ISetError::NotFrozen) => println!(),
+            _ => panic!("Iterated an open set"),
+        };
+
+        s1.freeze().unwrap();
+        s1.insert(4)
+            .expect("Repeated insert after freeze was refused");
+        match s1.insert(5) {
+            Err(ISetError::InsertAfterFreeze) => println!(),
+            _ => panic!("Inserted a new element after freeze"),
+        };
+
+        let mut elems: Vec<usize> = s1.iter().unwrap().collect();
+        elems.sort();
+        assert_eq!(vec![1, 2, 3, 4], elems);
+        assert_eq!(10, seen.load(Ordering::SeqCst));
+    }
+}
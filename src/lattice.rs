@@ -0,0 +1,372 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+
+// Composes joins field-wise, see quartz-derive for the enum flavours.
+pub use quartz_derive::Lattice;
+
+// A join-semilattice, the shape of any value which only grows.
+// OnceCell is the simplest such value: empty, then filled, with any
+// differing second write being a contradiction (the lattice's top).
+// Lattice variables (such as IMap's values) only ever move a value up the
+// lattice by joining, so every reader observes a monotonic history.
+pub trait Lattice {
+    // Join other into self, leaving self as the least upper bound of the two.
+    // Returns whether self grew, so that idempotent writes can be detected.
+    // Err(Conflict) means the two values have no upper bound short of top,
+    // and must leave self as it was: a join takes effect wholly or not at all.
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError>;
+
+    // Whether self is already top, contradictory in itself, such as an empty interval.
+    // Joining such a value into bottom is a Conflict, as joining it into anything else would be.
+    fn is_top(&self) -> bool {
+        false
+    }
+
+    // Whether self is at or below other, i.e. joining self into other changes nothing.
+    fn leq(&self, other: &Self) -> bool
+    where
+        Self: Clone,
+    {
+        match other.clone().join(self) {
+            Ok(grew) => !grew,
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LatticeError {
+    Conflict,
+}
+
+impl fmt::Display for LatticeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LatticeError::Conflict => write!(
+                f,
+                "Joined values have no upper bound, the lattice has reached top"
+            ),
+        }
+    }
+}
+
+impl Error for LatticeError {
+    fn description(&self) -> &str {
+        match self {
+            LatticeError::Conflict => {
+                "Joined values have no upper bound, the lattice has reached top"
+            }
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+// The largest value seen.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Max<T>(pub T);
+
+impl<T: Ord + Clone> Lattice for Max<T> {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        match other.0 > self.0 {
+            true => {
+                self.0 = other.0.clone();
+                Ok(true)
+            }
+            false => Ok(false),
+        }
+    }
+}
+
+// The smallest value seen.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Min<T>(pub T);
+
+impl<T: Ord + Clone> Lattice for Min<T> {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        match other.0 < self.0 {
+            true => {
+                self.0 = other.0.clone();
+                Ok(true)
+            }
+            false => Ok(false),
+        }
+    }
+}
+
+// Every element seen.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SetUnion<T: Eq + Hash>(pub HashSet<T>);
+
+impl<T: Eq + Hash + Clone> Lattice for SetUnion<T> {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        let mut grew = false;
+        for t in other.0.iter() {
+            if !self.0.contains(t) {
+                self.0.insert(t.clone());
+                grew = true;
+            }
+        }
+        Ok(grew)
+    }
+}
+
+// Unwritten or a single value, with any differing value a conflict.
+// These are OnceCell's semantics, as a lattice.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Flat<T> {
+    #[default]
+    Empty,
+    Full(T),
+}
+
+impl<T: PartialEq + Clone> Lattice for Flat<T> {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        match (&*self, other) {
+            (_, Flat::Empty) => Ok(false),
+            (Flat::Empty, Flat::Full(t)) => {
+                *self = Flat::Full(t.clone());
+                Ok(true)
+            }
+            (Flat::Full(a), Flat::Full(b)) => match a == b {
+                true => Ok(false),
+                false => Err(LatticeError::Conflict),
+            },
+        }
+    }
+}
+
+// Two lattices side by side, joined component-wise.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Lattice)]
+pub struct Pair<A, B>(pub A, pub B);
+
+// Lifts a lattice by adding a new bottom, None.
+impl<L: Lattice + Clone> Lattice for Option<L> {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        match (self.as_mut(), other) {
+            (_, None) => Ok(false),
+            (None, Some(l)) if l.is_top() => Err(LatticeError::Conflict),
+            (None, Some(l)) => {
+                *self = Some(l.clone());
+                Ok(true)
+            }
+            (Some(a), Some(b)) => a.join(b),
+        }
+    }
+}
+
+// Lifts a lattice point-wise over keys, absent keys being bottom.
+// Every key is joined into a copy before any is stored, so a conflict at one key leaves all of them.
+impl<K: Eq + Hash + Clone, L: Lattice + Clone> Lattice for HashMap<K, L> {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        let mut grown = Vec::new();
+        for (k, l) in other.iter() {
+            match self.get(k) {
+                Some(old) => {
+                    let mut next = old.clone();
+                    if next.join(l)? {
+                        grown.push((k.clone(), next));
+                    }
+                }
+                None => grown.push((k.clone(), l.clone())),
+            }
+        }
+        let grew = !grown.is_empty();
+        self.extend(grown);
+        Ok(grew)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+    // Values drawn from a small domain, so that equal and conflicting values are common.
+    fn small(g: &mut Gen) -> u8 {
+        u8::arbitrary(g) % 4
+    }
+
+    impl Arbitrary for Max<u8> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Max(small(g))
+        }
+    }
+
+    impl Arbitrary for Min<u8> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Min(small(g))
+        }
+    }
+
+    impl Arbitrary for SetUnion<u8> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            SetUnion((0..small(g)).map(|_| small(g)).collect())
+        }
+    }
+
+    impl Arbitrary for Flat<u8> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            match bool::arbitrary(g) {
+                true => Flat::Full(small(g)),
+                false => Flat::Empty,
+            }
+        }
+    }
+
+    impl<A: Arbitrary, B: Arbitrary> Arbitrary for Pair<A, B> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Pair(A::arbitrary(g), B::arbitrary(g))
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Lattice)]
+    struct Stats {
+        hi: Max<u8>,
+        lo: Min<u8>,
+        seen: SetUnion<u8>,
+    }
+
+    impl Arbitrary for Stats {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Stats {
+                hi: Max::arbitrary(g),
+                lo: Min::arbitrary(g),
+                seen: SetUnion::arbitrary(g),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Lattice)]
+    enum Tagged {
+        Count(Max<u8>),
+        Named { id: Flat<u8>, tags: SetUnion<u8> },
+        Nothing,
+    }
+
+    impl Arbitrary for Tagged {
+        fn arbitrary(g: &mut Gen) -> Self {
+            match small(g) % 3 {
+                0 => Tagged::Count(Max::arbitrary(g)),
+                1 => Tagged::Named {
+                    id: Flat::arbitrary(g),
+                    tags: SetUnion::arbitrary(g),
+                },
+                _ => Tagged::Nothing,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Lattice)]
+    #[lattice(lexicographic)]
+    enum Phase {
+        Pending,
+        Running(Max<u8>),
+        Done(Flat<u8>),
+    }
+
+    impl Arbitrary for Phase {
+        fn arbitrary(g: &mut Gen) -> Self {
+            match small(g) % 3 {
+                0 => Phase::Pending,
+                1 => Phase::Running(Max::arbitrary(g)),
+                _ => Phase::Done(Flat::arbitrary(g)),
+            }
+        }
+    }
+
+    fn joined<L: Lattice + Clone>(a: &L, b: &L) -> Result<L, LatticeError> {
+        let mut x = a.clone();
+        x.join(b).map(|_| x)
+    }
+
+    // Idempotence, commutativity, associativity, the least upper bound,
+    // and an honest report of growth.
+    fn laws<L: Lattice + Clone + PartialEq>(a: L, b: L, c: L) -> bool {
+        let idempotent = joined(&a, &a) == Ok(a.clone()) && a.clone().join(&a) == Ok(false);
+        let commutative = joined(&a, &b) == joined(&b, &a);
+        let associative = joined(&a, &b).and_then(|ab| joined(&ab, &c))
+            == joined(&b, &c).and_then(|bc| joined(&a, &bc));
+
+        let mut x = a.clone();
+        let bounded = match x.join(&b) {
+            Ok(grew) => grew == (x != a) && a.leq(&x) && b.leq(&x),
+            Err(_) => true,
+        };
+
+        idempotent && commutative && associative && bounded
+    }
+
+    fn check<L: Lattice + Clone + PartialEq + Arbitrary + fmt::Debug>() {
+        QuickCheck::new()
+            .tests(500)
+            .quickcheck(laws::<L> as fn(L, L, L) -> bool);
+    }
+
+    #[test]
+    fn test_lattice_laws() {
+        check::<Max<u8>>();
+        check::<Min<u8>>();
+        check::<SetUnion<u8>>();
+        check::<Flat<u8>>();
+        check::<Option<Max<u8>>>();
+        check::<Pair<Max<u8>, Flat<u8>>>();
+        check::<HashMap<u8, Max<u8>>>();
+    }
+
+    #[test]
+    fn test_derived_lattice_laws() {
+        check::<Stats>();
+        check::<Tagged>();
+        check::<Phase>();
+    }
+
+    #[test]
+    fn test_derived_lattice() {
+        let mut p = Phase::Running(Max(3));
+        assert_eq!(Ok(false), p.join(&Phase::Pending));
+        assert_eq!(Ok(true), p.join(&Phase::Running(Max(5))));
+        assert_eq!(Ok(true), p.join(&Phase::Done(Flat::Full(1))));
+        assert_eq!(Phase::Done(Flat::Full(1)), p);
+        assert_eq!(
+            Err(LatticeError::Conflict),
+            p.join(&Phase::Done(Flat::Full(2)))
+        );
+
+        let mut t = Tagged::Count(Max(1));
+        assert_eq!(Err(LatticeError::Conflict), t.join(&Tagged::Nothing));
+
+        // A conflict in a later field leaves the earlier ones unjoined.
+        #[derive(Clone, Debug, PartialEq, Lattice)]
+        struct Reading {
+            hi: Max<u8>,
+            id: Flat<u8>,
+        }
+        let mut r = Reading {
+            hi: Max(1),
+            id: Flat::Full(1),
+        };
+        let before = r.clone();
+        let other = Reading {
+            hi: Max(5),
+            id: Flat::Full(2),
+        };
+        assert_eq!(Err(LatticeError::Conflict), r.join(&other));
+        assert_eq!(before, r);
+
+        // Likewise a map conflicting on its second key, whichever key is visited first.
+        let mut m: HashMap<u8, Flat<u8>> = vec![(1, Flat::Empty), (2, Flat::Full(1))]
+            .into_iter()
+            .collect();
+        let before = m.clone();
+        let other = vec![(1, Flat::Full(1)), (2, Flat::Full(2)), (3, Flat::Full(3))]
+            .into_iter()
+            .collect();
+        assert_eq!(Err(LatticeError::Conflict), m.join(&other));
+        assert_eq!(before, m);
+    }
+}
@@ -1,3 +1,47 @@
+// Lets #[derive(Lattice)] name ::quartz from inside this crate too.
+extern crate self as quartz;
+
+// The baseline modules are kept as written, so their lints are allowed here rather than fixed.
+#[allow(unused_imports, clippy::new_without_default)]
 pub mod wait_group;
+#[allow(
+    clippy::bool_comparison,
+    clippy::new_without_default,
+    clippy::op_ref,
+    clippy::println_empty_string,
+    clippy::redundant_field_names,
+    clippy::redundant_pattern_matching
+)]
 pub mod once_cell;
-pub mod ping;
\ No newline at end of file
+#[allow(
+    clippy::multiple_bound_locations,
+    clippy::new_without_default,
+    clippy::println_empty_string
+)]
+pub mod ping;
+mod monitor;
+pub mod select;
+pub mod chan;
+pub mod buffered;
+pub mod call;
+#[cfg(target_os = "linux")]
+pub mod shm;
+pub mod lattice;
+pub mod iset;
+pub mod imap;
+pub mod counter;
+pub mod handler_pool;
+pub mod effect;
+pub mod par;
+pub mod once_map;
+pub mod fetch;
+pub mod propagator;
+pub mod fd;
+pub mod interval;
+pub mod tms;
+pub mod logic_var;
+pub mod incremental;
+pub mod signal;
+
+#[cfg(test)]
+mod test_util;
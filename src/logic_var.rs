@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+// Logic variables, as in Prolog or Oz dataflow variables, after guanxi.
+// A variable is either free, bound to a term (once, as with OnceCell), or unified with
+// another variable, so that the two are one from then on. Terms may contain variables,
+// so unifying two bound variables unifies their terms structurally, child by child.
+// Unification is union-find: each class of unified variables has one root,
+// which is the only variable in the class that holds the binding.
+//
+// A unification is first worked out without holding any lock, including the occurs check
+// (refusing to bind x to a term containing x), which sees the structure as it would be after.
+// It is then committed by locking every root it looked at, in address order, and checking
+// that none has changed in the meantime; if one has, it is worked out again.
+// So a failed unification changes nothing, and unifications of unrelated variables
+// never wait on each other. Read blocks only on the variables it waits for.
+
+// A term that can be unified: some shape, with variables as children.
+pub trait Unify: Clone + Send + Sync + 'static {
+    // The variables directly inside this term.
+    fn children(&self) -> Vec<LogicVar<Self>> {
+        Vec::new()
+    }
+
+    // If the two terms have the same shape, the pairs of children which must then unify.
+    // None if the shapes clash.
+    fn zip(&self, other: &Self) -> Option<Vec<(LogicVar<Self>, LogicVar<Self>)>>;
+}
+
+// Values without variables unify when equal.
+macro_rules! atomic_unify {
+    ($($t:ty),*) => {
+        $(impl Unify for $t {
+            fn zip(&self, other: &Self) -> Option<Vec<(LogicVar<Self>, LogicVar<Self>)>> {
+                match self == other {
+                    true => Some(Vec::new()),
+                    false => None,
+                }
+            }
+        })*
+    };
+}
+
+atomic_unify!(
+    bool,
+    char,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    String,
+    &'static str
+);
+
+enum Link<T> {
+    Free,
+    Bound(T),
+    Forward(LogicVar<T>),
+}
+
+struct Node<T> {
+    link: Mutex<Link<T>>,
+    // Readers of a free variable wait here, and are woken when it is bound or forwarded.
+    cond: Condvar,
+}
+
+pub struct LogicVar<T>(Arc<Node<T>>);
+
+impl<T> Clone for LogicVar<T> {
+    fn clone(&self) -> LogicVar<T> {
+        LogicVar(self.0.clone())
+    }
+}
+
+impl<T: Unify> Default for LogicVar<T> {
+    fn default() -> LogicVar<T> {
+        LogicVar::new()
+    }
+}
+
+impl<T: Unify + fmt::Debug> fmt::Debug for LogicVar<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.sample() {
+            Ok(Some(t)) => write!(f, "{:?}", t),
+            _ => write!(f, "_"),
+        }
+    }
+}
+
+impl<T: Unify> LogicVar<T> {
+    // A free variable.
+    pub fn new() -> LogicVar<T> {
+        LogicVar::with_link(Link::Free)
+    }
+
+    // A variable already bound to t.
+    pub fn term(t: T) -> LogicVar<T> {
+        LogicVar::with_link(Link::Bound(t))
+    }
+
+    fn with_link(link: Link<T>) -> LogicVar<T> {
+        LogicVar(Arc::new(Node {
+            link: Mutex::new(link),
+            cond: Condvar::new(),
+        }))
+    }
+
+    // Bind this variable to t. If it is already bound, t is unified with its term instead.
+    pub fn bind(&mut self, t: T) -> Result<(), LogicVarError> {
+        self.unify(&LogicVar::term(t))
+    }
+
+    // Make the two variables one, unifying their terms if both are bound.
+    // A failed unification changes nothing.
+    pub fn unify(&mut self, other: &LogicVar<T>) -> Result<(), LogicVarError> {
+        loop {
+            let mut plan = Plan {
+                seen: HashMap::new(),
+                merges: HashMap::new(),
+            };
+            plan.merge(self, other)?;
+            plan.check(self)?;
+            if plan.commit()? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Whether the two variables have been unified.
+    pub fn same(&self, other: &LogicVar<T>) -> Result<bool, LogicVarError> {
+        let (a, b) = (self.root()?, other.root()?);
+        Ok(Arc::ptr_eq(&a.0, &b.0))
+    }
+
+    // The term bound so far, which may still contain free variables, or None if free.
+    pub fn sample(&self) -> Result<Option<T>, LogicVarError> {
+        let root = self.root()?;
+        let link = root.lock()?;
+        match &*link {
+            Link::Bound(t) => Ok(Some(t.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    // Block until this variable, and every variable inside its term, is bound.
+    pub fn read(&self) -> Result<T, LogicVarError> {
+        let t = self.wait_bound()?;
+        for c in t.children() {
+            c.read()?;
+        }
+        Ok(t)
+    }
+
+    fn wait_bound(&self) -> Result<T, LogicVarError> {
+        let mut node = self.clone();
+        loop {
+            let next = {
+                let mut link = node.lock()?;
+                loop {
+                    match &*link {
+                        Link::Bound(t) => return Ok(t.clone()),
+                        Link::Forward(n) => break n.clone(),
+                        Link::Free => {
+                            link = node
+                                .0
+                                .cond
+                                .wait(link)
+                                .map_err(|_| LogicVarError::PoisonGuard)?
+                        }
+                    }
+                }
+            };
+            node = next;
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Link<T>>, LogicVarError> {
+        self.0.link.lock().map_err(|_| LogicVarError::PoisonGuard)
+    }
+
+    fn root(&self) -> Result<LogicVar<T>, LogicVarError> {
+        let mut node = self.clone();
+        loop {
+            let next = match &*node.lock()? {
+                Link::Forward(n) => Some(n.clone()),
+                _ => None,
+            };
+            match next {
+                Some(n) => node = n,
+                None => return Ok(node),
+            }
+        }
+    }
+
+    // As root, pointing every variable on the way straight at it.
+    // Forwards are only ever replaced by forwards further along, so this is safe at any time.
+    fn compress(&self) -> Result<LogicVar<T>, LogicVarError> {
+        let root = self.root()?;
+        let mut node = self.clone();
+        while !Arc::ptr_eq(&node.0, &root.0) {
+            let next = {
+                let mut link = node.lock()?;
+                let next = match &*link {
+                    Link::Forward(n) => n.clone(),
+                    _ => break,
+                };
+                *link = Link::Forward(root.clone());
+                next
+            };
+            node = next;
+        }
+        Ok(root)
+    }
+}
+
+// A unification worked out without holding any lock, to be committed all at once.
+struct Plan<T> {
+    // Every root looked at, with its term at the time.
+    seen: HashMap<*const Node<T>, (LogicVar<T>, Option<T>)>,
+    // The roots to forward, and where to.
+    merges: HashMap<*const Node<T>, LogicVar<T>>,
+}
+
+impl<T: Unify> Plan<T> {
+    // The root of v as it would be after the merges so far.
+    fn find(&mut self, v: &LogicVar<T>) -> Result<LogicVar<T>, LogicVarError> {
+        let r = self.observe(v)?;
+        Ok(self.resolve(r))
+    }
+
+    fn resolve(&self, mut r: LogicVar<T>) -> LogicVar<T> {
+        while let Some(n) = self.merges.get(&Arc::as_ptr(&r.0)) {
+            r = n.clone();
+        }
+        r
+    }
+
+    // The real root of v, remembering its term.
+    fn observe(&mut self, v: &LogicVar<T>) -> Result<LogicVar<T>, LogicVarError> {
+        let mut node = v.compress()?;
+        loop {
+            if self.seen.contains_key(&Arc::as_ptr(&node.0)) {
+                return Ok(node);
+            }
+            let next = match &*node.lock()? {
+                Link::Forward(n) => Err(n.clone()),
+                Link::Free => Ok(None),
+                Link::Bound(t) => Ok(Some(t.clone())),
+            };
+            match next {
+                Ok(t) => {
+                    self.seen.insert(Arc::as_ptr(&node.0), (node.clone(), t));
+                    return Ok(node);
+                }
+                // Forwarded since compress found it.
+                Err(n) => node = n,
+            }
+        }
+    }
+
+    fn term(&self, r: &LogicVar<T>) -> Option<T> {
+        self.seen
+            .get(&Arc::as_ptr(&r.0))
+            .and_then(|(_, t)| t.clone())
+    }
+
+    fn merge(&mut self, a: &LogicVar<T>, b: &LogicVar<T>) -> Result<(), LogicVarError> {
+        let mut todo = vec![(a.clone(), b.clone())];
+        while let Some((x, y)) = todo.pop() {
+            let (rx, ry) = (self.find(&x)?, self.find(&y)?);
+            if Arc::ptr_eq(&rx.0, &ry.0) {
+                continue;
+            }
+            match (self.term(&rx), self.term(&ry)) {
+                (None, _) => {
+                    self.merges.insert(Arc::as_ptr(&rx.0), ry);
+                }
+                (Some(_), None) => {
+                    self.merges.insert(Arc::as_ptr(&ry.0), rx);
+                }
+                (Some(s), Some(t)) => {
+                    let pairs = s.zip(&t).ok_or(LogicVarError::Conflict)?;
+                    // Joined first, so that cyclic structure would meet itself rather than recurse.
+                    self.merges.insert(Arc::as_ptr(&ry.0), rx);
+                    todo.extend(pairs);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Fail if the merges would make any term reachable from v contain itself.
+    // The structure was acyclic before, so any new cycle passes through v's class.
+    fn check(&mut self, v: &LogicVar<T>) -> Result<(), LogicVarError> {
+        if self.merges.is_empty() {
+            return Ok(());
+        }
+        // Depth first, where meeting a root still being walked means a cycle.
+        let mut done: HashMap<*const Node<T>, bool> = HashMap::new();
+        let mut todo = vec![(v.clone(), false)];
+        while let Some((n, leaving)) = todo.pop() {
+            let r = self.find(&n)?;
+            let p = Arc::as_ptr(&r.0);
+            if leaving {
+                done.insert(p, true);
+                continue;
+            }
+            match done.get(&p) {
+                Some(true) => continue,
+                Some(false) => return Err(LogicVarError::Occurs),
+                None => (),
+            }
+            done.insert(p, false);
+            todo.push((r.clone(), true));
+            if let Some(t) = self.term(&r) {
+                todo.extend(t.children().into_iter().map(|c| (c, false)));
+            }
+        }
+        Ok(())
+    }
+
+    // Apply the merges, or return false if a root seen has changed since.
+    fn commit(self) -> Result<bool, LogicVarError> {
+        if self.merges.is_empty() {
+            return Ok(true);
+        }
+        let targets: Vec<(*const Node<T>, LogicVar<T>)> = self
+            .merges
+            .iter()
+            .map(|(p, n)| (*p, self.resolve(n.clone())))
+            .collect();
+
+        let mut roots: Vec<&(LogicVar<T>, Option<T>)> = self.seen.values().collect();
+        roots.sort_by_key(|(v, _)| Arc::as_ptr(&v.0) as usize);
+        let mut guards = Vec::with_capacity(roots.len());
+        for (v, t) in roots.iter() {
+            let g = v.lock()?;
+            let unchanged = matches!((&*g, t), (Link::Free, None) | (Link::Bound(_), Some(_)));
+            if !unchanged {
+                return Ok(false);
+            }
+            guards.push((Arc::as_ptr(&v.0), g));
+        }
+
+        for (p, g) in guards.iter_mut() {
+            if let Some((_, n)) = targets.iter().find(|(q, _)| q == p) {
+                **g = Link::Forward(n.clone());
+            }
+        }
+        drop(guards);
+        for (p, _) in targets.iter() {
+            if let Some((v, _)) = self.seen.get(p) {
+                v.0.cond.notify_all();
+            }
+        }
+        Ok(true)
+    }
+}
+
+// Unify a and b.
+pub fn unify<T: Unify>(a: &LogicVar<T>, b: &LogicVar<T>) -> Result<(), LogicVarError> {
+    a.clone().unify(b)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LogicVarError {
+    Conflict,
+    Occurs,
+    PoisonGuard,
+}
+
+impl fmt::Display for LogicVarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogicVarError::Conflict => {
+                write!(f, "The variables are bound to terms which do not unify")
+            }
+            LogicVarError::Occurs => {
+                write!(f, "A variable cannot be bound to a term containing itself")
+            }
+            LogicVarError::PoisonGuard => write!(f, "A variable guard was poisoned"),
+        }
+    }
+}
+
+impl Error for LogicVarError {
+    fn description(&self) -> &str {
+        match self {
+            LogicVarError::Conflict => "The variables are bound to terms which do not unify",
+            LogicVarError::Occurs => "A variable cannot be bound to a term containing itself",
+            LogicVarError::PoisonGuard => "A variable guard was poisoned",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Clone, Debug)]
+    enum Term {
+        Atom(&'static str),
+        Pair(LogicVar<Term>, LogicVar<Term>),
+    }
+
+    impl Unify for Term {
+        fn children(&self) -> Vec<LogicVar<Term>> {
+            match self {
+                Term::Atom(_) => Vec::new(),
+                Term::Pair(a, b) => vec![a.clone(), b.clone()],
+            }
+        }
+
+        fn zip(&self, other: &Term) -> Option<Vec<(LogicVar<Term>, LogicVar<Term>)>> {
+            match (self, other) {
+                (Term::Atom(x), Term::Atom(y)) if x == y => Some(Vec::new()),
+                (Term::Pair(a, b), Term::Pair(c, d)) => {
+                    Some(vec![(a.clone(), c.clone()), (b.clone(), d.clone())])
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn atom(s: &'static str) -> LogicVar<Term> {
+        LogicVar::term(Term::Atom(s))
+    }
+
+    fn name(v: &LogicVar<Term>) -> &'static str {
+        match v.read().unwrap() {
+            Term::Atom(s) => s,
+            Term::Pair(..) => panic!("Expected an atom"),
+        }
+    }
+
+    #[test]
+    fn test_logic_var_structural() {
+        // (a, "b") = ("1", c)
+        let (a, c) = (LogicVar::new(), LogicVar::new());
+        let mut l = LogicVar::term(Term::Pair(a.clone(), atom("b")));
+        let r = LogicVar::term(Term::Pair(atom("1"), c.clone()));
+        l.unify(&r).unwrap();
+        assert_eq!("1", name(&a));
+        assert_eq!("b", name(&c));
+        assert!(l.same(&r).unwrap());
+
+        let mut x = LogicVar::new();
+        x.bind(Term::Atom("x")).unwrap();
+        x.bind(Term::Atom("x")).expect("Matching bind was refused");
+        match x.bind(Term::Atom("y")) {
+            Err(LogicVarError::Conflict) => println!(),
+            _ => panic!("Conflicting bind was accepted"),
+        };
+
+        // x = (y, x) has no finite solution, nor does y = (z, z), z = (y, w).
+        let mut x = LogicVar::new();
+        let y = LogicVar::new();
+        match x.bind(Term::Pair(y.clone(), x.clone())) {
+            Err(LogicVarError::Occurs) => println!(),
+            _ => panic!("Cyclic bind was accepted"),
+        };
+        let (mut y, mut z) = (LogicVar::new(), LogicVar::new());
+        y.bind(Term::Pair(z.clone(), z.clone())).unwrap();
+        match z.bind(Term::Pair(y.clone(), LogicVar::new())) {
+            Err(LogicVarError::Occurs) => println!(),
+            _ => panic!("Indirectly cyclic bind was accepted"),
+        };
+        assert!(z.sample().unwrap().is_none());
+        assert!(y.sample().unwrap().is_some());
+
+        // A clash in the second child leaves the first, and the roots, as they were.
+        let (a, b) = (LogicVar::new(), LogicVar::new());
+        let mut l = LogicVar::term(Term::Pair(a.clone(), atom("b")));
+        let r = LogicVar::term(Term::Pair(b.clone(), atom("c")));
+        match l.unify(&r) {
+            Err(LogicVarError::Conflict) => println!(),
+            _ => panic!("Conflicting unify was accepted"),
+        };
+        assert!(!l.same(&r).unwrap());
+        assert!(!a.same(&b).unwrap());
+    }
+
+    #[test]
+    fn test_logic_var_threads() {
+        // A chain of variables unified pairwise from many threads, then one bound.
+        let vars: Vec<LogicVar<u32>> = (0..16).map(|_| LogicVar::new()).collect();
+
+        let readers: Vec<_> = vars
+            .iter()
+            .map(|v| {
+                let v = v.clone();
+                thread::spawn(move || v.read().unwrap())
+            })
+            .collect();
+
+        let unifiers: Vec<_> = (0..15)
+            .map(|i| {
+                let (a, b) = (vars[i].clone(), vars[i + 1].clone());
+                thread::spawn(move || unify(&a, &b).unwrap())
+            })
+            .collect();
+        for u in unifiers {
+            u.join().expect("Failed to Join Threads!");
+        }
+
+        vars[7].clone().bind(42).unwrap();
+        for r in readers {
+            assert_eq!(42, r.join().expect("Failed to Join Threads!"));
+        }
+        match vars[0].clone().bind(41) {
+            Err(LogicVarError::Conflict) => println!(),
+            _ => panic!("Conflicting bind was accepted"),
+        };
+
+        // Reading waits for the children too.
+        let (p, q) = (LogicVar::<Term>::new(), LogicVar::<Term>::new());
+        let mut top = LogicVar::<Term>::new();
+        let t = top.clone();
+        let reader = thread::spawn(move || {
+            let parts = t.read().unwrap().children();
+            (name(&parts[0]), name(&parts[1]))
+        });
+        top.bind(Term::Pair(p.clone(), q.clone())).unwrap();
+        p.clone().bind(Term::Atom("p")).unwrap();
+        unify(&q, &atom("q")).unwrap();
+        assert_eq!(("p", "q"), reader.join().expect("Failed to Join Threads!"));
+    }
+}
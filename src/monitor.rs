@@ -0,0 +1,71 @@
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::Waker;
+use std::time::Instant;
+
+// A Mutex with the Condvar its waiters block on, shared by Ping, Chan and Buffered.
+// Each keeps its state in a Monitor, and adds its own methods to Monitor<ItsState>.
+// Tasks awaiting a change register a Waker in the state, and are woken alongside blocked threads.
+pub(crate) struct Monitor<S> {
+    state: Mutex<S>,
+    cv: Condvar,
+}
+
+// State which keeps the wakers of the tasks awaiting it.
+pub(crate) trait Wakers {
+    fn wakers(&mut self) -> &mut Vec<Waker>;
+}
+
+impl<S> Monitor<S> {
+    pub(crate) fn new(s: S) -> Monitor<S> {
+        Monitor {
+            state: Mutex::new(s),
+            cv: Condvar::new(),
+        }
+    }
+
+    // No user code runs under the lock, so a poisoned guard is still consistent.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, S> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Wait for a change, returning false if the deadline passed first.
+    pub(crate) fn wait<'a>(
+        &self,
+        st: MutexGuard<'a, S>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, S>, bool) {
+        match deadline {
+            None => (
+                self.cv.wait(st).unwrap_or_else(PoisonError::into_inner),
+                true,
+            ),
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                None => (st, false),
+                Some(left) => {
+                    let (st, _) = self
+                        .cv
+                        .wait_timeout(st, left)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    (st, true)
+                }
+            },
+        }
+    }
+}
+
+impl<S: Wakers> Monitor<S> {
+    // Wake every waiting thread and task, after a change to the state.
+    pub(crate) fn notify(&self, st: &mut S) {
+        self.cv.notify_all();
+        for w in st.wakers().drain(..) {
+            w.wake();
+        }
+    }
+
+    pub(crate) fn register(&self, st: &mut S, w: &Waker) {
+        let wakers = st.wakers();
+        if !wakers.iter().any(|x| x.will_wake(w)) {
+            wakers.push(w.clone());
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::cmp::PartialEq;
 use std::error::Error;
 use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::PoisonError;
 use std::sync::{Arc, LockResult, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use crate::wait_group::WaitGroup;
 
@@ -130,6 +132,20 @@ where
     val: Arc<Mutex<OnceVal<T>>>,
     send_guard: Arc<Mutex<bool>>,
     recv_wg: WaitGroup,
+    handlers: Arc<Mutex<FillHandlers<T>>>,
+}
+
+type FillHandler<T> = Box<dyn FnOnce(OnceVal<T>) + Send>;
+
+// Callbacks awaiting the first write.
+struct FillHandlers<T>(Vec<FillHandler<T>>)
+where
+    T: PartialEq;
+
+impl<T: PartialEq> fmt::Debug for FillHandlers<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FillHandlers {{ count: {:?} }}", self.0.len())
+    }
 }
 
 impl<T: PartialEq> OnceCell<T> {
@@ -150,6 +166,7 @@ impl<T: PartialEq> OnceCell<T> {
             val: Arc::new(Mutex::new(OnceVal::new(Arc::new(RwLock::new(None))))),
             send_guard: Arc::new(Mutex::new(false)),
             recv_wg: recv_wg,
+            handlers: Arc::new(Mutex::new(FillHandlers::<T>(Vec::new()))),
         }))
     }
 
@@ -176,7 +193,7 @@ impl<T: PartialEq> OnceCell<T> {
     // If the OnceCell is not initialized, or if the value is neither the first
     // nor matches the existing value, an error is raised.
     pub fn write(&mut self, t: T) -> Result<(), OnceCellError> {
-        match self.check_init() {
+        let res = match self.check_init() {
             // TODO: Bubble here!
             Err(_) => Err(OnceCellError::Uninitialized),
             Ok(x) => match *x {
@@ -203,7 +220,7 @@ impl<T: PartialEq> OnceCell<T> {
                                     let data = wrapper.read();
                                     match &*data {
                                         Some(x) => match &t == x {
-                                            true => Ok(()),
+                                            true => Ok(false),
                                             _ => Err(OnceCellError::ValueMismatch),
                                         },
 
@@ -232,7 +249,7 @@ impl<T: PartialEq> OnceCell<T> {
                                         Ok(mut data) => {
                                             *data = Some(t);
                                             self.0.recv_wg.done();
-                                            Ok(())
+                                            Ok(true)
                                         }
                                     }
                                 }
@@ -241,6 +258,14 @@ impl<T: PartialEq> OnceCell<T> {
                     }
                 }
             }, 
+        };
+
+        // Outside the guards, so handlers may write to this very cell.
+        // Only the first write runs them: later handlers find the cell filled and run themselves.
+        match res {
+            Ok(true) => self.fire_handlers(),
+            Ok(false) => Ok(()),
+            Err(err) => Err(err),
         }
     }
 
@@ -302,6 +327,71 @@ impl<T: PartialEq> OnceCell<T> {
         }
     }
 
+    // Register a callback to be run with the value once the first write concludes.
+    // If the cell is already filled, it is run immediately on the calling thread,
+    // otherwise it is run on the thread of the first writer.
+    pub fn add_handler<F>(&mut self, f: F) -> Result<(), OnceCellError>
+    where
+        F: FnOnce(OnceVal<T>) + Send + 'static,
+    {
+        let mut handlers = match self.0.handlers.lock() {
+            Err(_) => return Err(OnceCellError::PosionHandlerGuard),
+            Ok(x) => x,
+        };
+
+        // The writer drains the handlers only after marking the cell used,
+        // so holding the handler guard here means we are either pushed in time, or too late.
+        let filled = match self.check_send_used() {
+            Err(_) => return Err(OnceCellError::PosionWriteGuard),
+            Ok(x) => *x,
+        };
+
+        match filled {
+            false => {
+                handlers.0.push(Box::new(f));
+                Ok(())
+            }
+            true => {
+                drop(handlers);
+                f(self.read()?);
+                Ok(())
+            }
+        }
+    }
+
+    // Runs after the value is committed, so nothing here may fail the write itself.
+    // No handler runs under either guard, so a poisoned one still holds consistent data.
+    // Every handler runs even if one panics, which is reported as HandlerPanicked.
+    fn fire_handlers(&self) -> Result<(), OnceCellError> {
+        let pending = std::mem::take(
+            &mut self
+                .0
+                .handlers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .0,
+        );
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let val = self
+            .0
+            .val
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let mut panicked = false;
+        for f in pending {
+            let v = val.clone();
+            panicked |= panic::catch_unwind(AssertUnwindSafe(move || f(v))).is_err();
+        }
+        match panicked {
+            true => Err(OnceCellError::HandlerPanicked),
+            false => Ok(()),
+        }
+    }
+
     fn check_send_used(&self) -> LockResult<MutexGuard<'_, bool>> {
         self.0.send_guard.lock()
     }
@@ -316,6 +406,9 @@ pub enum OnceCellError {
     PosionWriteLock,
     PosionWriteGuard,
     PosionValueGuard,
+    PosionHandlerGuard,
+    // The value was written, but a handler fired by the write panicked.
+    HandlerPanicked,
     Uninitialized,
     ValueMismatch,
 }
@@ -326,6 +419,8 @@ impl fmt::Display for OnceCellError {
             OnceCellError::PosionWriteLock => write!(f, "Impossible poisoned write lock, this is should NEVER HAPPEN, PLEASE FILE A BUG REPORT: github krhoda quartz"),
             OnceCellError::PosionWriteGuard => write!(f, "A thread has panicked while holding the OnceCell's write guard, this cell is now inaccessible this error is likely from a healthy thread, this is should NEVER HAPPEN, PLEASE FILE A BUG REPORT: github krhoda quartz"),
             OnceCellError::PosionValueGuard => write!(f, "Some other operation has panicked while holding the OnceCells value guard, this cell is now inaccessible this is should NEVER HAPPEN, PLEASE FILE A BUG REPORT: github krhoda quartz"),
+            OnceCellError::PosionHandlerGuard => write!(f, "Some other operation has panicked while holding the OnceCells handler guard, handlers can no longer be added or run"),
+            OnceCellError::HandlerPanicked => write!(f, "The value was written, but a handler run by the write panicked"),
             OnceCellError::ValueMismatch => write!(f, "OnceCell recieved differing values on write, only one value may be written to a give OnceCell"),
             OnceCellError::Uninitialized => write!(f, "OnceCell must be initialized to use safely"),
         }
@@ -338,6 +433,8 @@ impl Error for OnceCellError {
             OnceCellError::PosionWriteLock =>  "Impossible poisoned write lock, this is should NEVER HAPPEN, PLEASE FILE A BUG REPORT: github krhoda quartz",
             OnceCellError::PosionWriteGuard => "A thread has panicked while holding the OnceCell's write guard, this cell is now inaccessible, this error is likely from a healthy thread, this is should NEVER HAPPEN, PLEASE FILE A BUG REPORT: github krhoda quartz",
             OnceCellError::PosionValueGuard => "Some other operation has panicked while holding the OnceCells value guard, this cell is now inaccessible this is should NEVER HAPPEN, PLEASE FILE A BUG REPORT: github krhoda quartz",
+            OnceCellError::PosionHandlerGuard => "Some other operation has panicked while holding the OnceCells handler guard, handlers can no longer be added or run",
+            OnceCellError::HandlerPanicked => "The value was written, but a handler run by the write panicked",
             OnceCellError::ValueMismatch => "OnceCell recieved differing values on write, only one value may be written to a give OnceCell",
             OnceCellError::Uninitialized => "OnceCell must be initialized to use safely",
         }
@@ -475,4 +572,20 @@ mod tests {
         assert_ne!(p1, p3);
         assert_ne!(p3, p2);
     }
+
+    #[test]
+    fn test_i_var_handler_panic() {
+        let mut p1 = OnceCell::<usize>::new();
+        let q1 = p1.clone();
+        p1.add_handler(|_| panic!("Expected panic in handler")).unwrap();
+
+        // The write still happened, and says so.
+        match p1.write(1) {
+            Err(OnceCellError::HandlerPanicked) => println!(),
+            other => panic!("Unexpected result of write {:?}", other),
+        };
+        let (written, _) = q1.sample().unwrap();
+        assert!(written);
+        p1.write(1).unwrap();
+    }
 }
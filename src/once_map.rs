@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::once_cell::{OnceCell, OnceCellError, OnceVal};
+
+// A map of keys to OnceCells, created on first mention.
+// Every key behaves exactly as its OnceCell: written once (or again with the same value),
+// read by blocking until written. The map itself is only ever locked long enough
+// to find or create a key's cell, never while waiting on one.
+// Useful as a deduplicating cache: the first caller to create a key does the work,
+// everyone else reads.
+pub struct OnceMap<K, V>(Arc<Mutex<HashMap<K, OnceCell<V>>>>)
+where
+    K: Eq + Hash + Clone,
+    V: PartialEq;
+
+impl<K: Eq + Hash + Clone, V: PartialEq> Clone for OnceMap<K, V> {
+    fn clone(&self) -> OnceMap<K, V> {
+        OnceMap::<K, V>(self.0.clone())
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: PartialEq> Default for OnceMap<K, V> {
+    fn default() -> OnceMap<K, V> {
+        OnceMap::<K, V>::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: PartialEq> OnceMap<K, V> {
+    pub fn new() -> OnceMap<K, V> {
+        OnceMap::<K, V>(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    // The cell for k, and whether this call created it.
+    pub fn get_or_insert(&self, k: K) -> Result<(OnceCell<V>, bool), OnceCellError> {
+        let mut cells = self.lock()?;
+        match cells.get(&k) {
+            Some(c) => Ok((c.clone(), false)),
+            None => {
+                let c = OnceCell::<V>::new();
+                cells.insert(k, c.clone());
+                Ok((c, true))
+            }
+        }
+    }
+
+    // The cell for k, if anyone has mentioned k.
+    pub fn get(&self, k: &K) -> Result<Option<OnceCell<V>>, OnceCellError> {
+        Ok(self.lock()?.get(k).cloned())
+    }
+
+    // Forget k, so that the next mention creates a fresh cell.
+    // Anyone already holding the old cell keeps it.
+    pub fn remove(&self, k: &K) -> Result<Option<OnceCell<V>>, OnceCellError> {
+        Ok(self.lock()?.remove(k))
+    }
+
+    pub fn write(&mut self, k: K, v: V) -> Result<(), OnceCellError> {
+        let (mut c, _) = self.get_or_insert(k)?;
+        c.write(v)
+    }
+
+    // Blocks until k is written.
+    pub fn read(&self, k: K) -> Result<OnceVal<V>, OnceCellError> {
+        let (c, _) = self.get_or_insert(k)?;
+        c.read()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<K, OnceCell<V>>>, OnceCellError> {
+        self.0.lock().map_err(|_| OnceCellError::PosionValueGuard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_once_map() {
+        let mut m1 = OnceMap::<&str, usize>::new();
+        let q1 = m1.clone();
+
+        let h = thread::spawn(move || *q1.read("a").unwrap().read());
+
+        let (_, created) = m1.get_or_insert("b").unwrap();
+        assert!(created);
+        let (_, created) = m1.get_or_insert("b").unwrap();
+        assert!(!created);
+
+        m1.write("a", 1).unwrap();
+        m1.write("a", 1).expect("Matching write was refused");
+        match m1.write("a", 2) {
+            Err(OnceCellError::ValueMismatch) => println!(),
+            _ => panic!("Mismatched write was accepted"),
+        };
+
+        assert_eq!(Some(1), h.join().expect("Failed to Join Threads!"));
+        assert!(m1.get(&"c").unwrap().is_none());
+    }
+}
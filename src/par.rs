@@ -0,0 +1,295 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::handler_pool::{HandlerPool, HandlerPoolError};
+use crate::once_cell::{OnceCell, OnceCellError};
+
+// Deterministic parallel computation, in the style of Haskell's Par monad.
+// A computation is a tree of tasks which communicate only through IVars:
+//   fork    -- start a task
+//   new_ivar -- make an empty IVar
+//   put     -- fill an IVar (again with the same value is fine, as with OnceCell)
+//   get     -- continue with an IVar's value once it is filled
+// Rust has no first-class continuations, so get takes the rest of the task as a closure.
+// That closure is parked on the IVar as a OnceCell handler, not on an OS thread,
+// so any number of blocked tasks share the fixed set of HandlerPool workers.
+// When the pool quiesces the computation is over. If only these operations were used,
+// the result is the same on every run, whatever order the workers took.
+pub struct Par;
+
+// The capability handed to every task.
+pub struct ParCtx {
+    pool: HandlerPool,
+    error: Arc<Mutex<Option<ParError>>>,
+}
+
+impl Clone for ParCtx {
+    fn clone(&self) -> ParCtx {
+        ParCtx {
+            pool: self.pool.clone(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+// A write-once variable belonging to a Par computation.
+pub struct IVar<T>(OnceCell<T>)
+where
+    T: PartialEq;
+
+impl<T: PartialEq> Clone for IVar<T> {
+    fn clone(&self) -> IVar<T> {
+        IVar::<T>(self.0.clone())
+    }
+}
+
+impl Par {
+    // Run a computation on as many workers as the machine has cores.
+    // The root task returns the IVar holding the result.
+    pub fn run<T, F>(f: F) -> Result<T, ParError>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+        F: FnOnce(&ParCtx) -> IVar<T> + Send + 'static,
+    {
+        let workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Par::run_with(workers, f)
+    }
+
+    // Run a computation on a fixed number of workers.
+    pub fn run_with<T, F>(workers: usize, f: F) -> Result<T, ParError>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+        F: FnOnce(&ParCtx) -> IVar<T> + Send + 'static,
+    {
+        let ctx = ParCtx {
+            pool: HandlerPool::new(workers),
+            error: Arc::new(Mutex::new(None)),
+        };
+
+        let result = IVar::<T>(OnceCell::new());
+        let (root, out) = (ctx.clone(), result.clone());
+        ctx.pool.spawn(move || {
+            let iv = f(&root);
+            root.get(&iv, move |ctx, t| ctx.put(&out, t));
+        });
+
+        if let Err(HandlerPoolError::HandlerPanicked(_)) = ctx.pool.quiesce() {
+            return Err(ParError::TaskPanicked);
+        }
+        if let Some(err) = ctx.error.lock().map_err(|_| ParError::TaskPanicked)?.take() {
+            return Err(err);
+        }
+
+        // Quiescent, yet the result was never put: every remaining task is parked.
+        match result.0.sample() {
+            Ok((true, v)) => match &*v.read() {
+                Some(t) => Ok(t.clone()),
+                None => Err(ParError::Deadlock),
+            },
+            _ => Err(ParError::Deadlock),
+        }
+    }
+}
+
+impl ParCtx {
+    // Start f as a new task.
+    pub fn fork<F>(&self, f: F)
+    where
+        F: FnOnce(&ParCtx) + Send + 'static,
+    {
+        let ctx = self.clone();
+        self.pool.spawn(move || f(&ctx));
+    }
+
+    pub fn new_ivar<T: PartialEq>(&self) -> IVar<T> {
+        IVar::<T>(OnceCell::new())
+    }
+
+    // Fill iv. Filling it again with a different value fails the whole computation.
+    pub fn put<T: PartialEq>(&self, iv: &IVar<T>, t: T) {
+        if let Err(err) = iv.0.clone().write(t) {
+            self.fail(err.into());
+        }
+    }
+
+    // Continue with k once iv is filled.
+    // The current task should return after calling get, k is the rest of it.
+    pub fn get<T, F>(&self, iv: &IVar<T>, k: F)
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+        F: FnOnce(&ParCtx, T) + Send + 'static,
+    {
+        let ctx = self.clone();
+        let res = iv.0.clone().add_handler(move |v| {
+            let t = v.read().clone();
+            ctx.fork(move |ctx| match t {
+                Some(t) => k(ctx, t),
+                None => ctx.fail(ParError::Deadlock),
+            });
+        });
+        if let Err(err) = res {
+            self.fail(err.into());
+        }
+    }
+
+    // Fork f, returning the IVar its result will be put in.
+    pub fn spawn<T, F>(&self, f: F) -> IVar<T>
+    where
+        T: PartialEq + Send + Sync + 'static,
+        F: FnOnce(&ParCtx) -> T + Send + 'static,
+    {
+        let iv = self.new_ivar();
+        let out = iv.clone();
+        self.fork(move |ctx| {
+            let t = f(ctx);
+            ctx.put(&out, t);
+        });
+        iv
+    }
+
+    // Only the first error is kept, which is the only one every run is sure to share.
+    fn fail(&self, err: ParError) {
+        if let Ok(mut slot) = self.error.lock() {
+            if slot.is_none() {
+                *slot = Some(err);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParError {
+    MultiplePut,
+    Deadlock,
+    TaskPanicked,
+    PoisonGuard,
+}
+
+impl From<OnceCellError> for ParError {
+    fn from(err: OnceCellError) -> ParError {
+        match err {
+            OnceCellError::ValueMismatch => ParError::MultiplePut,
+            // A handler is a parked continuation, so this is a task panicking.
+            OnceCellError::HandlerPanicked => ParError::TaskPanicked,
+            OnceCellError::PosionWriteLock
+            | OnceCellError::PosionWriteGuard
+            | OnceCellError::PosionValueGuard
+            | OnceCellError::PosionHandlerGuard
+            | OnceCellError::Uninitialized => ParError::PoisonGuard,
+        }
+    }
+}
+
+impl fmt::Display for ParError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParError::MultiplePut => write!(f, "An IVar was put twice with differing values"),
+            ParError::Deadlock => write!(
+                f,
+                "Every remaining task is waiting on an IVar which is never put"
+            ),
+            ParError::TaskPanicked => write!(f, "A task panicked, the computation cannot complete"),
+            ParError::PoisonGuard => write!(f, "An IVar guard was poisoned"),
+        }
+    }
+}
+
+impl Error for ParError {
+    fn description(&self) -> &str {
+        match self {
+            ParError::MultiplePut => "An IVar was put twice with differing values",
+            ParError::Deadlock => "Every remaining task is waiting on an IVar which is never put",
+            ParError::TaskPanicked => "A task panicked, the computation cannot complete",
+            ParError::PoisonGuard => "An IVar guard was poisoned",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each call is a task, each sum a parked continuation.
+    fn fib(ctx: &ParCtx, n: u64, out: IVar<u64>) {
+        if n < 2 {
+            ctx.put(&out, n);
+            return;
+        }
+
+        let (a, b) = (ctx.new_ivar(), ctx.new_ivar());
+        let (a1, b1) = (a.clone(), b.clone());
+        ctx.fork(move |ctx| fib(ctx, n - 1, a1));
+        ctx.fork(move |ctx| fib(ctx, n - 2, b1));
+        ctx.get(&a, move |ctx, x| {
+            ctx.get(&b, move |ctx, y| ctx.put(&out, x + y));
+        });
+    }
+
+    #[test]
+    fn test_par_fib() {
+        // Over 8000 tasks and as many parked continuations on 4 workers, several times over.
+        for _ in 0..3 {
+            let r = Par::run_with(4, |ctx| {
+                let out = ctx.new_ivar();
+                let o = out.clone();
+                ctx.fork(move |ctx| fib(ctx, 18, o));
+                out
+            });
+            assert_eq!(Ok(2584), r);
+        }
+    }
+
+    #[test]
+    fn test_par_errors() {
+        let twice = Par::run_with(2, |ctx| {
+            let iv = ctx.new_ivar();
+            let (a, b) = (iv.clone(), iv.clone());
+            ctx.fork(move |ctx| ctx.put(&a, 1));
+            ctx.fork(move |ctx| ctx.put(&b, 2));
+            iv
+        });
+        assert_eq!(Err(ParError::MultiplePut), twice);
+
+        let never = Par::run_with(2, |ctx| {
+            let iv: IVar<u8> = ctx.new_ivar();
+            let out = ctx.new_ivar();
+            let o = out.clone();
+            ctx.get(&iv, move |ctx, x| ctx.put(&o, x));
+            out
+        });
+        assert_eq!(Err(ParError::Deadlock), never);
+
+        let spawned = Par::run_with(2, |ctx| {
+            let a = ctx.spawn(|_| 20);
+            let out = ctx.new_ivar();
+            let o = out.clone();
+            ctx.get(&a, move |ctx, x| ctx.put(&o, x + 1));
+            out
+        });
+        assert_eq!(Ok(21), spawned);
+
+        // Each IVar failure is reported as what it was.
+        let failures = vec![
+            OnceCellError::ValueMismatch,
+            OnceCellError::HandlerPanicked,
+            OnceCellError::PosionHandlerGuard,
+        ];
+        let reported: Vec<ParError> = failures.into_iter().map(ParError::from).collect();
+        assert_eq!(
+            vec![
+                ParError::MultiplePut,
+                ParError::TaskPanicked,
+                ParError::PoisonGuard
+            ],
+            reported
+        );
+    }
+}
@@ -1,11 +1,13 @@
 use std::error::Error;
 use std::fmt;
-use std::sync::{Arc, Barrier, Mutex};
-// TODO:
-// Add PoisonErrs similar to IVar
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
-// CONSIDER FOR DEADLOCK FREEDOM:
-// Breaking into sender + reciever, killing a wait if the other drops from exisitence.
+use crate::monitor::{Monitor, Wakers};
+use crate::select::{self, Attempt, Claim, Selectable, Token};
 
 // This is a single-use rendezvous channel, obeying the laws of Pi Calculus.
 pub struct Ping<T>(Arc<PingMachine<T>>);
@@ -37,140 +39,848 @@ impl fmt::Display for PingState {
     }
 }
 
-struct PingMachine<T> {
-    init: Arc<Mutex<bool>>,
-    val: Arc<Mutex<Option<T>>>,
-    send_guard: Arc<Mutex<bool>>,
-    send_bar: Arc<Barrier>,
-    recv_guard: Arc<Mutex<bool>>,
-    recv_bar: Arc<Barrier>,
+type PingMachine<T> = Monitor<Slot<T>>;
+
+struct Slot<T> {
+    send_used: bool,
+    recv_used: bool,
+    // Placed by the sender, and taken by the reciever.
+    val: Option<T>,
+    delivered: bool,
+    // Only set by dropping the halves from Ping::pair.
+    sender_gone: bool,
+    receiver_gone: bool,
+    // Tasks awaiting a change, woken alongside blocked threads.
+    wakers: Vec<Waker>,
+    // Offers left by selects, which a peer may complete by claiming the token.
+    // Unlike a committed send or recv, an offer does not use the channel.
+    recv_offers: Vec<Token>,
+    send_offers: Vec<(Token, T)>,
+}
+
+impl<T> Wakers for Slot<T> {
+    fn wakers(&mut self) -> &mut Vec<Waker> {
+        &mut self.wakers
+    }
 }
 
 impl<T> Ping<T> {
     pub fn new() -> Ping<T> {
-        let send_barrier = Arc::new(Barrier::new(2));
-        let recv_barrier = Arc::new(Barrier::new(2));
-        // Each thread will decrement each barrier once.
-        // The recv barrier is lifted by both parties.
-        // The send barrier is lifted by the reciever.
-        // The sender places the value.
-        // The send barrier is lifted by the sender.
-        // The reciever extracts the value.
-
-        // If this were not rendezvous or were long lived
-        // WaitGroups would be needed to "lock the door" behind you.
-
-        Ping::<T>(Arc::new(PingMachine::<T> {
-            init: Arc::new(Mutex::new(true)),
-            val: Arc::new(Mutex::new(None)),
-            send_guard: Arc::new(Mutex::new(false)),
-            send_bar: send_barrier,
-            recv_guard: Arc::new(Mutex::new(false)),
-            recv_bar: recv_barrier,
-        }))
+        Ping::<T>(Arc::new(PingMachine::new(Slot::new())))
+    }
+
+    // The same channel, split into a half that can only send and one that can only recv.
+    // Dropping either half wakes the other with a Disconnected error,
+    // so a thread is never left waiting on a peer that no longer exists.
+    pub fn pair() -> (PingSender<T>, PingReceiver<T>) {
+        let m = Arc::new(PingMachine::new(Slot::new()));
+        (PingSender(m.clone()), PingReceiver(m))
+    }
+
+    // As pair, but each half is consumed by its send or recv,
+    // so using one twice is a compile error rather than a Used* error.
+    pub fn affine() -> (SendOnce<T>, RecvOnce<T>) {
+        let (tx, rx) = Ping::pair();
+        (SendOnce(tx), RecvOnce(rx))
     }
 
     pub fn state(&self) -> PingState {
-        match self.check_init() {
-            false => PingState::Unintialized,
-            _ => match self.check_send_used() {
-                true => match self.check_recv_used() {
-                    true => PingState::Used,
-                    _ => PingState::AwaitRecv,
-                },
-                _ => match self.check_recv_used() {
-                    true => PingState::AwaitSend,
-                    _ => PingState::Open,
-                },
-            },
-        }
+        self.0.state()
     }
 
     pub fn send(&mut self, t: T) -> Result<(), PingError> {
-        match self.check_init() {
-            // We have come into the possession of an uninitialized channel through spectacular means.
-            false => Err(PingError::UninitializedChanError),
-            true => {
-                let r = self.set_send_used();
+        self.0.send(t).map_err(|SendError(err, _)| err)
+    }
 
-                match r {
-                    // We are not the winning sender, the channel has been used.
-                    Err(x) => Err(x),
+    pub fn recv(&mut self) -> Result<T, PingError> {
+        self.0.recv()
+    }
 
-                    Ok(()) => {
-                        // Detect Recieve.
-                        self.0.recv_bar.wait();
+    // As send, giving up after the timeout. The channel is then Open again,
+    // for this or another sender, and the value is handed back.
+    pub fn send_timeout(&mut self, t: T, timeout: Duration) -> Result<(), SendError<T>> {
+        self.0.send_until(t, Some(Instant::now() + timeout))
+    }
 
-                        // finally.
-                        let mut data = self.0.val.lock().unwrap();
-                        *data = Some(t);
+    pub fn send_deadline(&mut self, t: T, deadline: Instant) -> Result<(), SendError<T>> {
+        self.0.send_until(t, Some(deadline))
+    }
 
-                        // Inform recieve we exist
-                        self.0.send_bar.wait();
+    // As recv, giving up after the timeout, when the channel is Open again.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, PingError> {
+        self.0.recv_until(Some(Instant::now() + timeout))
+    }
 
-                        // Weaken references to self?
-                        // If so, one here.
-                        Ok(())
-                    }
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, PingError> {
+        self.0.recv_until(Some(deadline))
+    }
+
+    // Send only if a reciever is already waiting, never blocking.
+    // Acting on state() instead would race with other users of the channel.
+    pub fn try_send(&mut self, t: T) -> Result<(), TrySendError<T>> {
+        self.0.try_send(t)
+    }
+
+    // Recv only if a sender is already waiting, never blocking.
+    pub fn try_recv(&mut self) -> Result<T, PingError> {
+        self.0.try_recv()
+    }
+
+    // As send, awaited on any executor. Dropping the future before it completes
+    // steps back from the channel, as a timeout does, though the value is dropped with it.
+    pub fn send_async(&mut self, t: T) -> SendFuture<'_, T> {
+        SendFuture::new(&self.0, t)
+    }
+
+    // As recv, awaited on any executor. Dropping the future before it completes
+    // steps back from the channel, leaving any value placed for it to the next reciever.
+    pub fn recv_async(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture::new(&self.0)
+    }
+
+    // A send for a Select to choose.
+    pub fn select_send(&mut self, t: T) -> SelectSend<'_, T> {
+        SelectSend::new(&self.0, t)
+    }
+
+    // A recv for a Select to choose.
+    pub fn select_recv(&mut self) -> SelectRecv<'_, T> {
+        SelectRecv::new(&self.0)
+    }
+}
+
+enum Taken<T> {
+    Value(T),
+    Empty,
+    // Our own select chose another arm.
+    Lost,
+}
+
+impl<T> Slot<T> {
+    fn new() -> Slot<T> {
+        Slot {
+            send_used: false,
+            recv_used: false,
+            val: None,
+            delivered: false,
+            sender_gone: false,
+            receiver_gone: false,
+            wakers: Vec::new(),
+            recv_offers: Vec::new(),
+            send_offers: Vec::new(),
+        }
+    }
+}
+
+impl<T> PingMachine<T> {
+    // Take a value for a reciever: one a sender placed, or one a select offered, if it can be claimed.
+    // own is the reciever's token, if it is selecting too, and is claimed first.
+    fn take_value(&self, slot: &mut Slot<T>, own: Option<&Token>) -> Taken<T> {
+        if let Some(t) = slot.val.take() {
+            return match own.is_none_or(|o| o.claim()) {
+                true => Taken::Value(t),
+                false => {
+                    slot.val = Some(t);
+                    Taken::Lost
+                }
+            };
+        }
+        let mut i = 0;
+        while i < slot.send_offers.len() {
+            if own.is_some_and(|o| o.same(&slot.send_offers[i].0)) {
+                i += 1;
+                continue;
+            }
+            match select::claim_pair(own, &slot.send_offers[i].0) {
+                Claim::Won => {
+                    let (_, t) = slot.send_offers.remove(i);
+                    slot.send_used = true;
+                    return Taken::Value(t);
                 }
+                Claim::Lost => return Taken::Lost,
+                Claim::Taken => i += 1,
             }
         }
+        Taken::Empty
+    }
+
+    // Find a reciever for a sender: one waiting in recv, or a select's offer, if it can be claimed.
+    fn find_receiver(&self, slot: &mut Slot<T>, own: Option<&Token>) -> Taken<()> {
+        if slot.recv_used && slot.val.is_none() && !slot.delivered {
+            return match own.is_none_or(|o| o.claim()) {
+                true => Taken::Value(()),
+                false => Taken::Lost,
+            };
+        }
+        let mut i = 0;
+        while i < slot.recv_offers.len() {
+            if own.is_some_and(|o| o.same(&slot.recv_offers[i])) {
+                i += 1;
+                continue;
+            }
+            match select::claim_pair(own, &slot.recv_offers[i]) {
+                Claim::Won => {
+                    slot.recv_offers.remove(i);
+                    slot.recv_used = true;
+                    return Taken::Value(());
+                }
+                Claim::Lost => return Taken::Lost,
+                Claim::Taken => i += 1,
+            }
+        }
+        Taken::Empty
+    }
+
+    fn state(&self) -> PingState {
+        let slot = self.lock();
+        match slot.send_used {
+            true => match slot.recv_used {
+                true => PingState::Used,
+                _ => PingState::AwaitRecv,
+            },
+            _ => match slot.recv_used {
+                true => PingState::AwaitSend,
+                _ => PingState::Open,
+            },
+        }
+    }
+
+    fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.send_until(t, None)
+    }
+
+    fn send_until(&self, t: T, deadline: Option<Instant>) -> Result<(), SendError<T>> {
+        let mut slot = self.lock();
+        // We are not the winning sender, the channel has been used.
+        if slot.send_used {
+            return Err(SendError(PingError::UsedSendChanError, t));
+        }
+        if slot.receiver_gone {
+            return Err(SendError(PingError::Disconnected, t));
+        }
+        slot.send_used = true;
+        slot.val = Some(t);
+        self.notify(&mut slot);
+
+        // Rendezvous: return only once the reciever holds the value.
+        let mut waiting = true;
+        while !slot.delivered && !slot.receiver_gone && waiting {
+            let (s, w) = self.wait(slot, deadline);
+            slot = s;
+            waiting = w;
+        }
+        if slot.delivered {
+            return Ok(());
+        }
+        let t = match slot.val.take() {
+            Some(t) => t,
+            None => unreachable!("An undelivered value is still in the slot"),
+        };
+        match slot.receiver_gone {
+            true => Err(SendError(PingError::Disconnected, t)),
+            // Timed out: step back, leaving the channel as if we never came.
+            false => {
+                slot.send_used = false;
+                self.notify(&mut slot);
+                Err(SendError(PingError::Timeout, t))
+            }
+        }
+    }
+
+    // Place the value and return at once, leaving it for whichever reciever comes.
+    fn deposit(&self, t: T) -> Result<(), SendError<T>> {
+        let mut slot = self.lock();
+        if slot.send_used {
+            return Err(SendError(PingError::UsedSendChanError, t));
+        }
+        if slot.receiver_gone {
+            return Err(SendError(PingError::Disconnected, t));
+        }
+        slot.send_used = true;
+        slot.val = Some(t);
+        self.notify(&mut slot);
+        Ok(())
+    }
+
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        let mut slot = self.lock();
+        if slot.send_used {
+            return Err(TrySendError::Used(t));
+        }
+        if slot.receiver_gone {
+            return Err(TrySendError::Disconnected(t));
+        }
+        match self.find_receiver(&mut slot, None) {
+            // The waiting reciever takes the value as it wakes, and cannot step back once it is placed.
+            Taken::Value(()) => {
+                slot.send_used = true;
+                slot.val = Some(t);
+                self.notify(&mut slot);
+                Ok(())
+            }
+            _ => Err(TrySendError::NotReady(t)),
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, PingError> {
+        let mut slot = self.lock();
+        if slot.recv_used {
+            return Err(PingError::UsedRecvChanError);
+        }
+        match self.take_value(&mut slot, None) {
+            Taken::Value(t) => {
+                slot.recv_used = true;
+                slot.delivered = true;
+                self.notify(&mut slot);
+                Ok(t)
+            }
+            _ => match slot.sender_gone {
+                true => Err(PingError::Disconnected),
+                false => Err(PingError::NotReady),
+            },
+        }
+    }
+
+    fn recv(&self) -> Result<T, PingError> {
+        self.recv_until(None)
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, PingError> {
+        let mut slot = self.lock();
+        if slot.recv_used {
+            return Err(PingError::UsedRecvChanError);
+        }
+        slot.recv_used = true;
+        self.notify(&mut slot);
+
+        let mut waiting = true;
+        loop {
+            if let Taken::Value(t) = self.take_value(&mut slot, None) {
+                slot.delivered = true;
+                self.notify(&mut slot);
+                return Ok(t);
+            }
+            if slot.sender_gone {
+                return Err(PingError::Disconnected);
+            }
+            // A value placed as we timed out is still taken, above.
+            if !waiting {
+                slot.recv_used = false;
+                self.notify(&mut slot);
+                return Err(PingError::Timeout);
+            }
+            let (s, w) = self.wait(slot, deadline);
+            slot = s;
+            waiting = w;
+        }
+    }
+}
+
+// The sending half of Ping::pair.
+pub struct PingSender<T>(Arc<PingMachine<T>>);
+
+impl<T> PingSender<T> {
+    pub fn state(&self) -> PingState {
+        self.0.state()
+    }
+
+    // If the reciever is dropped before taking the value, it is handed back.
+    pub fn send(&mut self, t: T) -> Result<(), SendError<T>> {
+        self.0.send(t)
+    }
+
+    pub fn send_timeout(&mut self, t: T, timeout: Duration) -> Result<(), SendError<T>> {
+        self.0.send_until(t, Some(Instant::now() + timeout))
+    }
+
+    pub fn send_deadline(&mut self, t: T, deadline: Instant) -> Result<(), SendError<T>> {
+        self.0.send_until(t, Some(deadline))
+    }
+
+    pub fn try_send(&mut self, t: T) -> Result<(), TrySendError<T>> {
+        self.0.try_send(t)
+    }
+
+    pub fn send_async(&mut self, t: T) -> SendFuture<'_, T> {
+        SendFuture::new(&self.0, t)
+    }
+
+    pub fn select_send(&mut self, t: T) -> SelectSend<'_, T> {
+        SelectSend::new(&self.0, t)
+    }
+}
+
+impl<T> Drop for PingSender<T> {
+    fn drop(&mut self) {
+        let mut slot = self.0.lock();
+        slot.sender_gone = true;
+        self.0.notify(&mut slot);
+    }
+}
+
+// The recieving half of Ping::pair.
+pub struct PingReceiver<T>(Arc<PingMachine<T>>);
+
+impl<T> PingReceiver<T> {
+    pub fn state(&self) -> PingState {
+        self.0.state()
     }
 
     pub fn recv(&mut self) -> Result<T, PingError> {
-        match self.check_init() {
-            // We have come into the possession of an uninitialized channel through spectacular means.
-            false => Err(PingError::UninitializedChanError),
-            true => {
-                let r = self.set_recv_used();
-                match r {
-                    Err(x) => Err(x),
-                    Ok(()) => {
-                        self.0.recv_bar.wait(); // Alert the sender.
+        self.0.recv()
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, PingError> {
+        self.0.recv_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, PingError> {
+        self.0.recv_until(Some(deadline))
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, PingError> {
+        self.0.try_recv()
+    }
+
+    pub fn recv_async(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture::new(&self.0)
+    }
+
+    pub fn select_recv(&mut self) -> SelectRecv<'_, T> {
+        SelectRecv::new(&self.0)
+    }
+}
+
+impl<T> Drop for PingReceiver<T> {
+    fn drop(&mut self) {
+        let mut slot = self.0.lock();
+        slot.receiver_gone = true;
+        self.0.notify(&mut slot);
+    }
+}
+
+/// The sending half of Ping::affine, used up by its send.
+///
+/// A second send does not compile:
+///
+/// ```compile_fail
+/// use quartz::ping::Ping;
+///
+/// let (tx, _rx) = Ping::<u8>::affine();
+/// tx.send(1).ok();
+/// tx.send(2).ok();
+/// ```
+pub struct SendOnce<T>(PingSender<T>);
+
+impl<T> SendOnce<T> {
+    pub fn state(&self) -> PingState {
+        self.0.state()
+    }
+
+    // Only fails if the reciever was dropped, handing the value back.
+    pub fn send(mut self, t: T) -> Result<(), SendError<T>> {
+        self.0.send(t)
+    }
+}
+
+/// The recieving half of Ping::affine, used up by its recv.
+///
+/// A second recv does not compile:
+///
+/// ```compile_fail
+/// use quartz::ping::Ping;
+///
+/// let (_tx, rx) = Ping::<u8>::affine();
+/// rx.recv().ok();
+/// rx.recv().ok();
+/// ```
+pub struct RecvOnce<T>(PingReceiver<T>);
+
+impl<T> RecvOnce<T> {
+    pub fn state(&self) -> PingState {
+        self.0.state()
+    }
+
+    // Only fails if the sender was dropped without sending.
+    pub fn recv(mut self) -> Result<T, PingError> {
+        self.0.recv()
+    }
+}
+
+// A Ping whose sender need not wait: send leaves the value in the channel and returns,
+// and recv takes it whenever it comes. Each side is still used once, as with a Ping.
+pub struct Oneshot<T>(Arc<PingMachine<T>>);
+
+impl<T> Clone for Oneshot<T> {
+    fn clone(&self) -> Oneshot<T> {
+        Oneshot(self.0.clone())
+    }
+}
+
+impl<T> Default for Oneshot<T> {
+    fn default() -> Oneshot<T> {
+        Oneshot::new()
+    }
+}
+
+impl<T> Oneshot<T> {
+    pub fn new() -> Oneshot<T> {
+        Oneshot(Arc::new(PingMachine::new(Slot::new())))
+    }
+
+    pub fn state(&self) -> PingState {
+        self.0.state()
+    }
+
+    // Deposit the value without waiting for a reciever.
+    pub fn send(&mut self, t: T) -> Result<(), SendError<T>> {
+        self.0.deposit(t)
+    }
+
+    // As a Ping's send, waiting until a reciever has taken the value.
+    pub fn send_and_wait(&mut self, t: T) -> Result<(), SendError<T>> {
+        self.0.send(t)
+    }
+
+    pub fn recv(&mut self) -> Result<T, PingError> {
+        self.0.recv()
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, PingError> {
+        self.0.recv_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, PingError> {
+        self.0.recv_until(Some(deadline))
+    }
+
+    // Recv only if the value has been sent.
+    pub fn try_recv(&mut self) -> Result<T, PingError> {
+        self.0.try_recv()
+    }
+
+    pub fn recv_async(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture::new(&self.0)
+    }
+
+    // A recv for a Select to choose.
+    pub fn select_recv(&mut self) -> SelectRecv<'_, T> {
+        SelectRecv::new(&self.0)
+    }
+}
+
+// The future of send_async.
+pub struct SendFuture<'a, T> {
+    m: &'a PingMachine<T>,
+    val: Option<T>,
+    // Whether the value is in the slot, and whether we have returned.
+    placed: bool,
+    done: bool,
+}
+
+// The value is moved, never pinned.
+impl<'a, T> Unpin for SendFuture<'a, T> {}
+
+impl<'a, T> SendFuture<'a, T> {
+    fn new(m: &'a PingMachine<T>, t: T) -> SendFuture<'a, T> {
+        SendFuture {
+            m,
+            val: Some(t),
+            placed: false,
+            done: false,
+        }
+    }
+}
+
+impl<'a, T> Future for SendFuture<'a, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut slot = this.m.lock();
+        if !this.placed {
+            let t = match this.val.take() {
+                Some(t) => t,
+                None => panic!("SendFuture polled after completion"),
+            };
+            if slot.send_used {
+                this.done = true;
+                return Poll::Ready(Err(SendError(PingError::UsedSendChanError, t)));
+            }
+            if slot.receiver_gone {
+                this.done = true;
+                return Poll::Ready(Err(SendError(PingError::Disconnected, t)));
+            }
+            slot.send_used = true;
+            slot.val = Some(t);
+            this.placed = true;
+            this.m.notify(&mut slot);
+        }
+
+        if slot.delivered {
+            this.done = true;
+            return Poll::Ready(Ok(()));
+        }
+        if slot.receiver_gone {
+            this.done = true;
+            return match slot.val.take() {
+                Some(t) => Poll::Ready(Err(SendError(PingError::Disconnected, t))),
+                None => unreachable!("An undelivered value is still in the slot"),
+            };
+        }
+        this.m.register(&mut slot, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for SendFuture<'a, T> {
+    fn drop(&mut self) {
+        if self.placed && !self.done {
+            let mut slot = self.m.lock();
+            if !slot.delivered {
+                slot.val = None;
+                slot.send_used = false;
+                self.m.notify(&mut slot);
+            }
+        }
+    }
+}
+
+// The future of recv_async.
+pub struct RecvFuture<'a, T> {
+    m: &'a PingMachine<T>,
+    // Whether we hold the recv side, and whether we have returned.
+    entered: bool,
+    done: bool,
+}
+
+impl<'a, T> RecvFuture<'a, T> {
+    fn new(m: &'a PingMachine<T>) -> RecvFuture<'a, T> {
+        RecvFuture {
+            m,
+            entered: false,
+            done: false,
+        }
+    }
+}
+
+impl<'a, T> Future for RecvFuture<'a, T> {
+    type Output = Result<T, PingError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut slot = this.m.lock();
+        if !this.entered {
+            if slot.recv_used {
+                this.done = true;
+                return Poll::Ready(Err(PingError::UsedRecvChanError));
+            }
+            slot.recv_used = true;
+            this.entered = true;
+            this.m.notify(&mut slot);
+        }
 
-                        self.0.send_bar.wait(); // Await the sender.
+        if let Taken::Value(t) = this.m.take_value(&mut slot, None) {
+            slot.delivered = true;
+            this.done = true;
+            this.m.notify(&mut slot);
+            return Poll::Ready(Ok(t));
+        }
+        if slot.sender_gone {
+            this.done = true;
+            return Poll::Ready(Err(PingError::Disconnected));
+        }
+        this.m.register(&mut slot, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for RecvFuture<'a, T> {
+    fn drop(&mut self) {
+        if self.entered && !self.done {
+            let mut slot = self.m.lock();
+            slot.recv_used = false;
+            self.m.notify(&mut slot);
+        }
+    }
+}
+
+// The send arm of a Select.
+pub struct SelectSend<'a, T> {
+    m: &'a PingMachine<T>,
+    // None while the value sits in an offer.
+    val: Option<T>,
+}
+
+impl<'a, T> SelectSend<'a, T> {
+    fn new(m: &'a PingMachine<T>, t: T) -> SelectSend<'a, T> {
+        SelectSend { m, val: Some(t) }
+    }
+
+    // The value, from wherever it is.
+    fn reclaim(&mut self, slot: &mut Slot<T>, token: &Token) -> T {
+        if let Some(t) = self.val.take() {
+            return t;
+        }
+        match slot.send_offers.iter().position(|(o, _)| o.same(token)) {
+            Some(i) => slot.send_offers.remove(i).1,
+            None => unreachable!("An unsent value is in the select or its offer"),
+        }
+    }
+
+    fn fail(
+        &mut self,
+        slot: &mut Slot<T>,
+        token: &Token,
+        err: PingError,
+    ) -> Attempt<Result<(), SendError<T>>> {
+        match token.claim() {
+            true => Attempt::Ready(Err(SendError(err, self.reclaim(slot, token)))),
+            false => Attempt::Lost,
+        }
+    }
+}
+
+impl<'a, T> select::private::Sealed for SelectSend<'a, T> {}
+
+impl<'a, T> Selectable for SelectSend<'a, T> {
+    type Output = Result<(), SendError<T>>;
 
-                        Ok(self.0.val.lock().unwrap().take().unwrap())
+    fn attempt(&mut self, token: &Token, offer: bool) -> Attempt<Self::Output> {
+        let mut slot = self.m.lock();
+        if slot.send_used {
+            return self.fail(&mut slot, token, PingError::UsedSendChanError);
+        }
+        if slot.receiver_gone {
+            return self.fail(&mut slot, token, PingError::Disconnected);
+        }
+        match self.m.find_receiver(&mut slot, Some(token)) {
+            Taken::Value(()) => {
+                let t = self.reclaim(&mut slot, token);
+                slot.send_used = true;
+                slot.val = Some(t);
+                self.m.notify(&mut slot);
+                Attempt::Ready(Ok(()))
+            }
+            Taken::Lost => Attempt::Lost,
+            Taken::Empty => {
+                if offer {
+                    if let Some(t) = self.val.take() {
+                        slot.send_offers.push((token.clone(), t));
+                        self.m.notify(&mut slot);
                     }
                 }
+                self.m.register(&mut slot, &token.waker());
+                Attempt::Pending
             }
         }
     }
 
-    fn set_send_used(&mut self) -> Result<(), PingError> {
-        let mut is_used = self.0.send_guard.lock().unwrap();
+    // A reciever took the offered value.
+    fn finish(&mut self, _token: &Token) -> Self::Output {
+        Ok(())
+    }
 
-        match *is_used {
-            true => Err(PingError::UsedSendChanError),
-            false => {
-                *is_used = true;
-                Ok(())
+    fn withdraw(&mut self, token: &Token) {
+        if self.val.is_none() {
+            let mut slot = self.m.lock();
+            if let Some(i) = slot.send_offers.iter().position(|(o, _)| o.same(token)) {
+                self.val = Some(slot.send_offers.remove(i).1);
+                self.m.notify(&mut slot);
             }
         }
     }
 
-    fn set_recv_used(&mut self) -> Result<(), PingError> {
-        let mut is_used = self.0.recv_guard.lock().unwrap();
+    fn poke(&self) {
+        let mut slot = self.m.lock();
+        self.m.notify(&mut slot);
+    }
+}
 
-        match *is_used {
-            true => Err(PingError::UsedRecvChanError),
-            false => {
-                *is_used = true;
-                Ok(())
+// The recv arm of a Select.
+pub struct SelectRecv<'a, T> {
+    m: &'a PingMachine<T>,
+}
+
+impl<'a, T> SelectRecv<'a, T> {
+    fn new(m: &'a PingMachine<T>) -> SelectRecv<'a, T> {
+        SelectRecv { m }
+    }
+
+    fn fail(
+        &mut self,
+        slot: &mut Slot<T>,
+        token: &Token,
+        err: PingError,
+    ) -> Attempt<Result<T, PingError>> {
+        match token.claim() {
+            true => {
+                slot.recv_offers.retain(|o| !o.same(token));
+                Attempt::Ready(Err(err))
             }
+            false => Attempt::Lost,
         }
     }
+}
+
+impl<'a, T> select::private::Sealed for SelectRecv<'a, T> {}
 
-    fn check_send_used(&self) -> bool {
-        *self.0.send_guard.lock().unwrap()
+impl<'a, T> Selectable for SelectRecv<'a, T> {
+    type Output = Result<T, PingError>;
+
+    fn attempt(&mut self, token: &Token, offer: bool) -> Attempt<Self::Output> {
+        let mut slot = self.m.lock();
+        if slot.recv_used {
+            return self.fail(&mut slot, token, PingError::UsedRecvChanError);
+        }
+        match self.m.take_value(&mut slot, Some(token)) {
+            Taken::Value(t) => {
+                slot.recv_offers.retain(|o| !o.same(token));
+                slot.recv_used = true;
+                slot.delivered = true;
+                self.m.notify(&mut slot);
+                Attempt::Ready(Ok(t))
+            }
+            Taken::Lost => Attempt::Lost,
+            Taken::Empty => {
+                if slot.sender_gone {
+                    return self.fail(&mut slot, token, PingError::Disconnected);
+                }
+                if offer && !slot.recv_offers.iter().any(|o| o.same(token)) {
+                    slot.recv_offers.push(token.clone());
+                    self.m.notify(&mut slot);
+                }
+                self.m.register(&mut slot, &token.waker());
+                Attempt::Pending
+            }
+        }
     }
 
-    fn check_recv_used(&self) -> bool {
-        *self.0.recv_guard.lock().unwrap()
+    // A sender claimed the offer and placed its value.
+    fn finish(&mut self, _token: &Token) -> Self::Output {
+        let mut slot = self.m.lock();
+        match slot.val.take() {
+            Some(t) => {
+                slot.delivered = true;
+                self.m.notify(&mut slot);
+                Ok(t)
+            }
+            None => unreachable!("A sender claiming a recv offer places its value"),
+        }
     }
 
-    fn check_init(&self) -> bool {
-        *self.0.init.lock().unwrap()
+    fn withdraw(&mut self, token: &Token) {
+        let mut slot = self.m.lock();
+        let before = slot.recv_offers.len();
+        slot.recv_offers.retain(|o| !o.same(token));
+        if slot.recv_offers.len() != before {
+            self.m.notify(&mut slot);
+        }
+    }
+
+    fn poke(&self) {
+        let mut slot = self.m.lock();
+        self.m.notify(&mut slot);
     }
 }
 
@@ -179,6 +889,9 @@ pub enum PingError {
     UsedSendChanError,
     UsedRecvChanError,
     UninitializedChanError,
+    Disconnected,
+    Timeout,
+    NotReady,
 }
 
 impl fmt::Display for PingError {
@@ -191,6 +904,9 @@ impl fmt::Display for PingError {
             PingError::UninitializedChanError => {
                 write!(f, "Ping must be initialized to use safely")
             }
+            PingError::Disconnected => write!(f, "The other half of this Ping was dropped"),
+            PingError::Timeout => write!(f, "No peer arrived before the deadline"),
+            PingError::NotReady => write!(f, "No peer is waiting"),
         }
     }
 }
@@ -201,6 +917,9 @@ impl Error for PingError {
             PingError::UsedSendChanError => "This instance of Ping already has a sender",
             PingError::UsedRecvChanError => "This instance of Ping already has a reciever",
             PingError::UninitializedChanError => "Ping must be initialized to use safely",
+            PingError::Disconnected => "The other half of this Ping was dropped",
+            PingError::Timeout => "No peer arrived before the deadline",
+            PingError::NotReady => "No peer is waiting",
         }
     }
 
@@ -209,7 +928,63 @@ impl Error for PingError {
     }
 }
 
-pub struct Spark<T> (Ping<T>);
+// A failed send, handing back the value that was never delivered.
+#[derive(Debug)]
+pub struct SendError<T>(pub PingError, pub T);
+
+impl<T> SendError<T> {
+    pub fn error(&self) -> &PingError {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.1
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Debug> Error for SendError<T> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+// A failed try_send, handing back the value.
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    NotReady(T), // No reciever is waiting.
+    Disconnected(T),
+    Used(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::NotReady(t) => t,
+            TrySendError::Disconnected(t) => t,
+            TrySendError::Used(t) => t,
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::NotReady(_) => PingError::NotReady.fmt(f),
+            TrySendError::Disconnected(_) => PingError::Disconnected.fmt(f),
+            TrySendError::Used(_) => PingError::UsedSendChanError.fmt(f),
+        }
+    }
+}
+
+impl<T: fmt::Debug> Error for TrySendError<T> {}
+
+pub struct Spark<T> (Oneshot<T>);
 impl<T> Spark<T> {
     pub fn read(&mut self) -> Result<T, PingError> {
         self.0.recv()
@@ -221,13 +996,12 @@ where
     T: Send,
     U: Send,
 {
-    let p = Ping::<U>::new();
+    let p = Oneshot::<U>::new();
     let mut q = p.clone();
     let f = move || {
         let x = action(arg);
+        // The value is left for read, so the thread finishes without waiting on the caller.
         let r = q.send(x);
-        // Given that Ping is a rendezvous, this will block until the caller lifts the barriers.
-        // Consider creating non-rendezvous channel and replacing later.
         match r {
             Ok(_) => {},
             Err(err) => {
@@ -244,6 +1018,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{block_on, waker};
     use std::thread;
 
     #[test]
@@ -342,4 +1117,193 @@ mod tests {
             _ => panic!("No result")
         }
     }
+
+    #[test]
+    fn test_ping_pair() {
+        let (mut tx, mut rx) = Ping::<String>::pair();
+        let h = thread::spawn(move || tx.send("hello".to_string()));
+        assert_eq!("hello", rx.recv().expect("Recv on a fresh pair"));
+        h.join()
+            .expect("Failed to Join Threads!")
+            .expect("Send on a fresh pair");
+
+        // A reciever dropped while the sender waits hands the value back.
+        let (mut tx, rx) = Ping::<String>::pair();
+        let h = thread::spawn(move || tx.send("lost".to_string()));
+        while let PingState::Open = rx.state() {
+            thread::yield_now();
+        }
+        drop(rx);
+        match h.join().expect("Failed to Join Threads!") {
+            Err(SendError(PingError::Disconnected, t)) => assert_eq!("lost", t),
+            _ => panic!("Send succeeded without a reciever"),
+        }
+
+        // A sender dropped while the reciever waits.
+        let (tx, mut rx) = Ping::<String>::pair();
+        let h = thread::spawn(move || rx.recv());
+        drop(tx);
+        match h.join().expect("Failed to Join Threads!") {
+            Err(PingError::Disconnected) => println!(),
+            _ => panic!("Recv succeeded without a sender"),
+        }
+    }
+
+    #[test]
+    fn test_ping_timeout() {
+        let mut p = Ping::<u8>::new();
+        let mut q = p.clone();
+        match p.send_timeout(1, Duration::from_millis(10)) {
+            Err(SendError(PingError::Timeout, t)) => assert_eq!(1, t),
+            _ => panic!("Send completed without a reciever"),
+        }
+        match p.state() {
+            PingState::Open => println!(),
+            st => panic!("Timed out send left the channel {}", st),
+        }
+        match q.recv_deadline(Instant::now() + Duration::from_millis(10)) {
+            Err(PingError::Timeout) => println!(),
+            _ => panic!("Recv completed without a sender"),
+        }
+
+        // Both sides roll back, so the channel still works.
+        let h = thread::spawn(move || q.recv_timeout(Duration::from_secs(10)));
+        p.send(2).expect("Send after rolled back timeouts");
+        assert_eq!(2, h.join().expect("Failed to Join Threads!").unwrap());
+    }
+
+    #[test]
+    fn test_ping_try() {
+        let mut p = Ping::<u8>::new();
+        let mut q = p.clone();
+        match p.try_send(1) {
+            Err(TrySendError::NotReady(t)) => assert_eq!(1, t),
+            _ => panic!("Sent without a reciever"),
+        }
+        match q.try_recv() {
+            Err(PingError::NotReady) => println!(),
+            _ => panic!("Recieved without a sender"),
+        }
+
+        // A parked reciever is found by try_send.
+        let h = thread::spawn(move || q.recv());
+        let mut t = 2;
+        loop {
+            match p.try_send(t) {
+                Ok(()) => break,
+                Err(TrySendError::NotReady(back)) => t = back,
+                Err(err) => panic!("Unexpected {}", err),
+            }
+            thread::yield_now();
+        }
+        assert_eq!(2, h.join().expect("Failed to Join Threads!").unwrap());
+
+        // And a parked sender by try_recv.
+        let (mut tx, mut rx) = Ping::<u8>::pair();
+        let h = thread::spawn(move || tx.send(3));
+        let got = loop {
+            match rx.try_recv() {
+                Ok(x) => break x,
+                Err(PingError::NotReady) => thread::yield_now(),
+                Err(err) => panic!("Unexpected {}", err),
+            }
+        };
+        assert_eq!(3, got);
+        h.join().expect("Failed to Join Threads!").unwrap();
+    }
+
+    #[test]
+    fn test_ping_async() {
+        // A blocking sender meets an awaiting reciever, and the other way around.
+        let mut p = Ping::<u8>::new();
+        let mut q = p.clone();
+        let h = thread::spawn(move || q.send(1));
+        assert_eq!(1, block_on(p.recv_async()).unwrap());
+        h.join().expect("Failed to Join Threads!").unwrap();
+
+        let (mut tx, mut rx) = Ping::<u8>::pair();
+        let h = thread::spawn(move || rx.recv());
+        block_on(tx.send_async(2)).unwrap();
+        assert_eq!(2, h.join().expect("Failed to Join Threads!").unwrap());
+
+        // A dropped future steps back, leaving the channel Open.
+        let mut p = Ping::<u8>::new();
+        let q = p.clone();
+        let waker = waker();
+        let mut cx = Context::from_waker(&waker);
+        {
+            let mut f = p.recv_async();
+            match Pin::new(&mut f).poll(&mut cx) {
+                Poll::Pending => println!(),
+                _ => panic!("Recieved without a sender"),
+            }
+            match q.state() {
+                PingState::AwaitSend => println!(),
+                st => panic!("Pending recv left the channel {}", st),
+            }
+        }
+        match p.state() {
+            PingState::Open => println!(),
+            st => panic!("Cancelled recv left the channel {}", st),
+        }
+        match p.try_send(3) {
+            Err(TrySendError::NotReady(_)) => println!(),
+            _ => panic!("Sent to a cancelled reciever"),
+        }
+    }
+
+    #[test]
+    fn test_oneshot() {
+        // The send returns before anyone recieves.
+        let mut o = Oneshot::<u8>::new();
+        let mut q = o.clone();
+        o.send(1).unwrap();
+        match o.send(2) {
+            Err(SendError(PingError::UsedSendChanError, 2)) => println!(),
+            _ => panic!("Sent twice on a Oneshot"),
+        }
+        match q.state() {
+            PingState::AwaitRecv => println!(),
+            st => panic!("Deposited value left the channel {}", st),
+        }
+        let h = thread::spawn(move || q.recv());
+        assert_eq!(1, h.join().expect("Failed to Join Threads!").unwrap());
+        match o.recv() {
+            Err(PingError::UsedRecvChanError) => println!(),
+            _ => panic!("Recieved twice on a Oneshot"),
+        }
+
+        // Waiting for pickup is opt in.
+        let mut o = Oneshot::<u8>::new();
+        let mut q = o.clone();
+        let h = thread::spawn(move || q.send_and_wait(3));
+        assert_eq!(3, o.recv().unwrap());
+        h.join().expect("Failed to Join Threads!").unwrap();
+        match o.state() {
+            PingState::Used => println!(),
+            st => panic!("Finished Oneshot is {}", st),
+        }
+    }
+
+    #[test]
+    fn test_ping_affine() {
+        let (tx, rx) = Ping::<u8>::affine();
+        let h = thread::spawn(move || tx.send(1));
+        assert_eq!(1, rx.recv().unwrap());
+        h.join().expect("Failed to Join Threads!").unwrap();
+
+        // Dropping an unused half still disconnects the other.
+        let (tx, rx) = Ping::<u8>::affine();
+        drop(rx);
+        match tx.send(2) {
+            Err(SendError(PingError::Disconnected, 2)) => println!(),
+            _ => panic!("Sent to a dropped reciever"),
+        }
+        let (tx, rx) = Ping::<u8>::affine();
+        drop(tx);
+        match rx.recv() {
+            Err(PingError::Disconnected) => println!(),
+            _ => panic!("Recieved from a dropped sender"),
+        }
+    }
 }
@@ -0,0 +1,583 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use crate::handler_pool::HandlerPool;
+use crate::lattice::{Flat, Lattice};
+use crate::once_cell::OnceCell;
+
+// Propagator networks, after Radul and Sussman.
+// Cells accumulate partial information about a value: every addition is joined into
+// what the cell already holds, so a cell only ever learns more. A failed join is a contradiction.
+// Propagators are functions from some cells to others. Each runs once when attached and again
+// whenever one of its input cells learns something, until nothing new is learnt: the fixpoint.
+// Since cells only grow, and propagators should only add what follows from their inputs,
+// the fixpoint reached does not depend on the order propagators happened to run in.
+//
+// Cells and propagators live in the Network; Cell<L> is only an index into it.
+// A propagator is handed the network it runs in, so it captures nothing but indices,
+// which is what lets fork copy a network wholesale, e.g. to explore the branches of a search.
+// Each index is tagged with the network that made it, so a cell is only valid in that network
+// and in forks taken after it was made.
+pub struct Network(Arc<NetInner>);
+
+impl Clone for Network {
+    fn clone(&self) -> Network {
+        Network(self.0.clone())
+    }
+}
+
+impl Default for Network {
+    fn default() -> Network {
+        Network::new()
+    }
+}
+
+// Each network gets a distinct id to tag its cells with.
+static NEXT_NET: AtomicUsize = AtomicUsize::new(0);
+
+struct NetInner {
+    id: usize,
+    // The networks this one was forked from, each with the number of its cells copied here.
+    lineage: Vec<(usize, usize)>,
+    cells: RwLock<Vec<Arc<dyn AnyCell>>>,
+    props: RwLock<Vec<Arc<Propagator>>>,
+    // None runs propagators on the thread calling run, in the order they were scheduled.
+    // Forks share their parent's pool.
+    pool: Option<HandlerPool>,
+    agenda: Mutex<VecDeque<Arc<Propagator>>>,
+    // The first contradiction stops all further scheduling.
+    failed: AtomicBool,
+    error: Mutex<Option<PropagatorError>>,
+}
+
+type PropFn = Arc<dyn Fn(&Network) -> Result<(), PropagatorError> + Send + Sync>;
+
+struct Propagator {
+    f: PropFn,
+    // Set while waiting to run, so that several inputs growing at once run it only once.
+    queued: AtomicBool,
+}
+
+// A typed index of a cell holding an L.
+pub struct Cell<L> {
+    id: CellId,
+    _l: PhantomData<fn() -> L>,
+}
+
+impl<L> Clone for Cell<L> {
+    fn clone(&self) -> Cell<L> {
+        *self
+    }
+}
+
+impl<L> Copy for Cell<L> {}
+
+impl<L> fmt::Debug for Cell<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cell({})", self.id.index)
+    }
+}
+
+impl<L> Cell<L> {
+    pub fn id(&self) -> CellId {
+        self.id
+    }
+}
+
+// An untyped cell index, naming inputs and contradictions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CellId {
+    net: usize,
+    index: usize,
+}
+
+impl CellId {
+    // The position of the cell in its network.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+// A cell with its content type erased.
+trait AnyCell: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn add_neighbour(&self, p: usize) -> Result<(), PropagatorError>;
+    fn snapshot(&self) -> Arc<dyn AnyCell>;
+}
+
+struct CellState<L> {
+    content: Option<L>,
+    // The propagators reading this cell.
+    neighbours: Vec<usize>,
+}
+
+struct CellBox<L>(Mutex<CellState<L>>);
+
+impl<L: Lattice + Clone + Send + Sync + 'static> AnyCell for CellBox<L> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn add_neighbour(&self, p: usize) -> Result<(), PropagatorError> {
+        let mut st = self.0.lock().map_err(|_| PropagatorError::PoisonGuard)?;
+        st.neighbours.push(p);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Arc<dyn AnyCell> {
+        let st = match self.0.lock() {
+            Ok(x) => x,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        Arc::new(CellBox::<L>(Mutex::new(CellState {
+            content: st.content.clone(),
+            neighbours: st.neighbours.clone(),
+        })))
+    }
+}
+
+impl Network {
+    // A network run entirely on the thread calling run.
+    pub fn new() -> Network {
+        Network::build(None, Vec::new(), Vec::new(), Vec::new(), None)
+    }
+
+    // A network whose propagators run on a pool of the given number of workers.
+    pub fn with_workers(workers: usize) -> Network {
+        let pool = HandlerPool::new(workers.max(1));
+        Network::build(Some(pool), Vec::new(), Vec::new(), Vec::new(), None)
+    }
+
+    fn build(
+        pool: Option<HandlerPool>,
+        lineage: Vec<(usize, usize)>,
+        cells: Vec<Arc<dyn AnyCell>>,
+        props: Vec<Arc<Propagator>>,
+        error: Option<PropagatorError>,
+    ) -> Network {
+        Network(Arc::new(NetInner {
+            id: NEXT_NET.fetch_add(1, Ordering::SeqCst),
+            lineage,
+            cells: RwLock::new(cells),
+            props: RwLock::new(props),
+            pool,
+            agenda: Mutex::new(VecDeque::new()),
+            failed: AtomicBool::new(error.is_some()),
+            error: Mutex::new(error),
+        }))
+    }
+
+    // A new cell, knowing nothing.
+    pub fn cell<L: Lattice + Clone + Send + Sync + 'static>(&self) -> Cell<L> {
+        let mut cells = match self.0.cells.write() {
+            Ok(x) => x,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        cells.push(Arc::new(CellBox::<L>(Mutex::new(CellState {
+            content: None,
+            neighbours: Vec::new(),
+        }))));
+        Cell {
+            id: CellId {
+                net: self.0.id,
+                index: cells.len() - 1,
+            },
+            _l: PhantomData,
+        }
+    }
+
+    // A cell filled by an OnceCell, the simplest kind of partial information: nothing, or everything.
+    // Writes to the OnceCell are only seen by runs after they happen.
+    pub fn once_cell<T>(&self, oc: &mut OnceCell<T>) -> Result<Cell<Flat<T>>, PropagatorError>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+    {
+        let c = self.cell::<Flat<T>>();
+        // The OnceCell must not keep the network, and its workers, alive.
+        let net: Weak<NetInner> = Arc::downgrade(&self.0);
+        oc.add_handler(move |v| {
+            if let (Some(inner), Some(t)) = (net.upgrade(), &*v.read()) {
+                let _ = Network(inner).add(&c, Flat::Full(t.clone()));
+            }
+        })
+        .map_err(|_| PropagatorError::PoisonGuard)?;
+        Ok(c)
+    }
+
+    // Join l into the cell, alerting its propagators if it learnt anything.
+    pub fn add<L>(&self, c: &Cell<L>, l: L) -> Result<(), PropagatorError>
+    where
+        L: Lattice + Clone + Send + Sync + 'static,
+    {
+        let any = self.get(c.id)?;
+        let cell = any
+            .as_any()
+            .downcast_ref::<CellBox<L>>()
+            .ok_or(PropagatorError::ForeignCell(c.id))?;
+
+        let neighbours = {
+            let mut st = cell.0.lock().map_err(|_| PropagatorError::PoisonGuard)?;
+            match st.content.join(&Some(l)) {
+                Ok(true) => st.neighbours.clone(),
+                Ok(false) => Vec::new(),
+                Err(_) => {
+                    let err = PropagatorError::Contradiction(c.id);
+                    self.fail(err.clone());
+                    return Err(err);
+                }
+            }
+        };
+
+        for p in neighbours {
+            self.schedule(p);
+        }
+        Ok(())
+    }
+
+    // What the cell knows so far, None if nothing.
+    pub fn content<L>(&self, c: &Cell<L>) -> Result<Option<L>, PropagatorError>
+    where
+        L: Lattice + Clone + Send + Sync + 'static,
+    {
+        let any = self.get(c.id)?;
+        let cell = any
+            .as_any()
+            .downcast_ref::<CellBox<L>>()
+            .ok_or(PropagatorError::ForeignCell(c.id))?;
+        let st = cell.0.lock().map_err(|_| PropagatorError::PoisonGuard)?;
+        Ok(st.content.clone())
+    }
+
+    // Attach f, to run now and whenever a cell in inputs learns something.
+    // f should only read its inputs and add to its outputs.
+    pub fn propagator<F>(&self, inputs: &[CellId], f: F) -> Result<(), PropagatorError>
+    where
+        F: Fn(&Network) -> Result<(), PropagatorError> + Send + Sync + 'static,
+    {
+        // Check every input before attaching to any of them.
+        let cells = inputs
+            .iter()
+            .map(|id| self.get(*id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let p = {
+            let mut props = self
+                .0
+                .props
+                .write()
+                .map_err(|_| PropagatorError::PoisonGuard)?;
+            props.push(Arc::new(Propagator {
+                f: Arc::new(f),
+                queued: AtomicBool::new(false),
+            }));
+            props.len() - 1
+        };
+        for c in cells {
+            c.add_neighbour(p)?;
+        }
+        self.schedule(p);
+        Ok(())
+    }
+
+    // Propagate f from a to out, once a knows anything.
+    pub fn lift1<A, B, F>(&self, a: &Cell<A>, out: &Cell<B>, f: F) -> Result<(), PropagatorError>
+    where
+        A: Lattice + Clone + Send + Sync + 'static,
+        B: Lattice + Clone + Send + Sync + 'static,
+        F: Fn(&A) -> B + Send + Sync + 'static,
+    {
+        let (a, out) = (*a, *out);
+        self.propagator(&[a.id()], move |net| match net.content(&a)? {
+            Some(x) => net.add(&out, f(&x)),
+            None => Ok(()),
+        })
+    }
+
+    // Propagate f from a and b to out, once both know anything.
+    pub fn lift2<A, B, C, F>(
+        &self,
+        a: &Cell<A>,
+        b: &Cell<B>,
+        out: &Cell<C>,
+        f: F,
+    ) -> Result<(), PropagatorError>
+    where
+        A: Lattice + Clone + Send + Sync + 'static,
+        B: Lattice + Clone + Send + Sync + 'static,
+        C: Lattice + Clone + Send + Sync + 'static,
+        F: Fn(&A, &B) -> C + Send + Sync + 'static,
+    {
+        let (a, b, out) = (*a, *b, *out);
+        self.propagator(&[a.id(), b.id()], move |net| {
+            match (net.content(&a)?, net.content(&b)?) {
+                (Some(x), Some(y)) => net.add(&out, f(&x, &y)),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    // Run propagators until the fixpoint, or the first contradiction.
+    pub fn run(&self) -> Result<(), PropagatorError> {
+        match &self.0.pool {
+            // Panics are caught by fire, which fails the network they happened in,
+            // so those counted by a pool shared with forks are not this network's concern.
+            Some(pool) => {
+                let _ = pool.quiesce();
+            }
+            None => loop {
+                let next = match self.0.agenda.lock() {
+                    Ok(mut agenda) => agenda.pop_front(),
+                    Err(_) => return Err(PropagatorError::PoisonGuard),
+                };
+                match next {
+                    Some(p) => self.fire(&p),
+                    None => break,
+                }
+            },
+        }
+
+        match self.0.error.lock() {
+            Ok(x) => match &*x {
+                Some(err) => Err(err.clone()),
+                None => Ok(()),
+            },
+            Err(_) => Err(PropagatorError::PoisonGuard),
+        }
+    }
+
+    // Whether a contradiction has been found.
+    pub fn failed(&self) -> bool {
+        self.0.failed.load(Ordering::SeqCst)
+    }
+
+    // An independent copy of this network: the same cells with the same content,
+    // and the same propagators. Cell handles are valid in both.
+    // Fork quiescent networks, propagators still waiting to run are not copied.
+    // A fork runs on its parent's pool, if it has one, so running one waits for the other's work too.
+    pub fn fork(&self) -> Network {
+        let cells: Vec<_> = match self.0.cells.read() {
+            Ok(x) => x.iter().map(|c| c.snapshot()).collect(),
+            Err(poisoned) => poisoned.into_inner().iter().map(|c| c.snapshot()).collect(),
+        };
+        let mut lineage = self.0.lineage.clone();
+        lineage.push((self.0.id, cells.len()));
+        let copy = |p: &Arc<Propagator>| {
+            Arc::new(Propagator {
+                f: p.f.clone(),
+                queued: AtomicBool::new(false),
+            })
+        };
+        let props = match self.0.props.read() {
+            Ok(x) => x.iter().map(copy).collect(),
+            Err(poisoned) => poisoned.into_inner().iter().map(copy).collect(),
+        };
+        let error = match self.0.error.lock() {
+            Ok(x) => x.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        Network::build(self.0.pool.clone(), lineage, cells, props, error)
+    }
+
+    fn get(&self, id: CellId) -> Result<Arc<dyn AnyCell>, PropagatorError> {
+        let mine = id.net == self.0.id
+            || self
+                .0
+                .lineage
+                .iter()
+                .any(|&(net, n)| net == id.net && id.index < n);
+        if !mine {
+            return Err(PropagatorError::ForeignCell(id));
+        }
+        let cells = self
+            .0
+            .cells
+            .read()
+            .map_err(|_| PropagatorError::PoisonGuard)?;
+        cells
+            .get(id.index)
+            .cloned()
+            .ok_or(PropagatorError::ForeignCell(id))
+    }
+
+    fn schedule(&self, p: usize) {
+        if self.failed() {
+            return;
+        }
+        let prop = match self.0.props.read() {
+            Ok(props) => match props.get(p) {
+                Some(x) => x.clone(),
+                None => return,
+            },
+            Err(_) => return,
+        };
+        if prop.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        match &self.0.pool {
+            Some(pool) => {
+                let net = self.clone();
+                pool.spawn(move || net.fire(&prop));
+            }
+            None => {
+                if let Ok(mut agenda) = self.0.agenda.lock() {
+                    agenda.push_back(prop);
+                }
+            }
+        }
+    }
+
+    fn fire(&self, p: &Propagator) {
+        if self.failed() {
+            return;
+        }
+        // Cleared before running, so growth seen during the run queues it again.
+        p.queued.store(false, Ordering::SeqCst);
+        match panic::catch_unwind(AssertUnwindSafe(|| (p.f)(self))) {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => self.fail(err),
+            Err(_) => self.fail(PropagatorError::PropagatorPanicked),
+        }
+    }
+
+    // Only the first error is kept.
+    fn fail(&self, err: PropagatorError) {
+        self.0.failed.store(true, Ordering::SeqCst);
+        if let Ok(mut slot) = self.0.error.lock() {
+            if slot.is_none() {
+                *slot = Some(err);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropagatorError {
+    Contradiction(CellId),
+    ForeignCell(CellId),
+    PropagatorPanicked,
+    PoisonGuard,
+}
+
+impl fmt::Display for PropagatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PropagatorError::Contradiction(id) => {
+                write!(f, "Cell {} was given contradictory information", id.index)
+            }
+            PropagatorError::ForeignCell(id) => {
+                write!(f, "Cell {} does not belong to this network", id.index)
+            }
+            PropagatorError::PropagatorPanicked => write!(f, "A propagator panicked"),
+            PropagatorError::PoisonGuard => write!(f, "A network guard was poisoned"),
+        }
+    }
+}
+
+impl Error for PropagatorError {
+    fn description(&self) -> &str {
+        match self {
+            PropagatorError::Contradiction(_) => "A cell was given contradictory information",
+            PropagatorError::ForeignCell(_) => "A cell does not belong to this network",
+            PropagatorError::PropagatorPanicked => "A propagator panicked",
+            PropagatorError::PoisonGuard => "A network guard was poisoned",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lattice::Max;
+
+    // Radul's temperature converter, run in both directions.
+    fn converter(net: &Network) -> (Cell<Flat<i64>>, Cell<Flat<i64>>) {
+        let (c, f) = (net.cell(), net.cell());
+        let full = |g: fn(i64) -> i64| {
+            move |x: &Flat<i64>| match x {
+                Flat::Full(v) => Flat::Full(g(*v)),
+                Flat::Empty => Flat::Empty,
+            }
+        };
+        net.lift1(&c, &f, full(|c| c * 9 / 5 + 32)).unwrap();
+        net.lift1(&f, &c, full(|f| (f - 32) * 5 / 9)).unwrap();
+        (c, f)
+    }
+
+    #[test]
+    fn test_propagator_converter() {
+        let net = Network::new();
+        let (c, f) = converter(&net);
+        net.add(&f, Flat::Full(212)).unwrap();
+        net.run().unwrap();
+        assert_eq!(Some(Flat::Full(100)), net.content(&c).unwrap());
+
+        // Each fork goes its own way.
+        let hot = net.fork();
+        let cold = Network::new();
+        let (c2, _) = converter(&cold);
+        cold.add(&c2, Flat::Full(-40)).unwrap();
+        cold.run().unwrap();
+        assert_eq!(Some(Flat::Full(-40)), cold.content(&c2).unwrap());
+
+        let res = hot.add(&c, Flat::Full(0));
+        assert_eq!(Err(PropagatorError::Contradiction(c.id())), res);
+        assert_eq!(Err(PropagatorError::Contradiction(c.id())), hot.run());
+        assert!(!net.failed());
+
+        // An OnceCell as a cell.
+        let mut oc = OnceCell::<i64>::new();
+        let from_oc = net.once_cell(&mut oc).unwrap();
+        net.lift1(&from_oc, &f, |x| x.clone()).unwrap();
+        oc.write(212).unwrap();
+        net.run().unwrap();
+        assert_eq!(Some(Flat::Full(212)), net.content(&from_oc).unwrap());
+    }
+
+    #[test]
+    fn test_propagator_fixpoint() {
+        // A ring of cells, each at least one more than the last, capped at 1000.
+        let net = Network::with_workers(4);
+        let ring: Vec<Cell<Max<u32>>> = (0..8).map(|_| net.cell()).collect();
+        for i in 0..ring.len() {
+            let next = ring[(i + 1) % ring.len()];
+            net.lift1(&ring[i], &next, |x| Max((x.0 + 1).min(1000)))
+                .unwrap();
+        }
+        net.add(&ring[0], Max(0)).unwrap();
+        net.run().unwrap();
+        for c in ring.iter() {
+            assert_eq!(Some(Max(1000)), net.content(c).unwrap());
+        }
+
+        let other = Network::new();
+        let res = other.add(&ring[3], Max(1));
+        assert_eq!(Err(PropagatorError::ForeignCell(ring[3].id())), res);
+
+        // Forks share the pool, and a cell made after a fork is foreign to it, whatever its index.
+        let fork = net.fork();
+        let late: Cell<Max<u32>> = net.cell();
+        let twin: Cell<Max<u32>> = fork.cell();
+        assert_eq!(late.id().index(), twin.id().index());
+        assert_eq!(
+            Err(PropagatorError::ForeignCell(late.id())),
+            fork.add(&late, Max(1))
+        );
+        assert_eq!(
+            Err(PropagatorError::ForeignCell(twin.id())),
+            net.content(&twin)
+        );
+        fork.add(&ring[3], Max(1)).unwrap();
+        fork.run().unwrap();
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+// External choice, as in CSP and the pi calculus: wait on several channel operations
+// and commit to exactly one.
+// A Select scans its arms from a random starting point, completing the first one ready.
+// If none are, it leaves an offer on each arm's channel, and sleeps until one changes.
+// Every offer carries the select's Token, and whoever completes an offer, a peer or the select
+// itself, must first claim the token for that arm. A token is claimed only once, so only the
+// chosen arm ever consumes its channel, and the select withdraws the others' offers afterwards.
+// A peer which is itself selecting claims its own token and then the other's, or neither,
+// so two selects may meet on either side of a channel.
+
+const WAITING: usize = usize::MAX;
+const ABORTED: usize = usize::MAX - 1;
+
+struct Selector {
+    chosen: AtomicUsize,
+    // Set when a claim was given back, which peers may have seen and given up on.
+    released: AtomicBool,
+    thread: Thread,
+}
+
+impl Wake for Selector {
+    fn wake(self: Arc<Self>) {
+        self.thread.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.thread.unpark();
+    }
+}
+
+// One arm of one Select, left on a channel with an offer.
+pub struct Token {
+    sel: Arc<Selector>,
+    arm: usize,
+}
+
+impl Clone for Token {
+    fn clone(&self) -> Token {
+        Token {
+            sel: self.sel.clone(),
+            arm: self.arm,
+        }
+    }
+}
+
+impl Token {
+    // Choose this arm, if the select has not chosen already.
+    pub(crate) fn claim(&self) -> bool {
+        self.sel
+            .chosen
+            .compare_exchange(WAITING, self.arm, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.sel.chosen.store(WAITING, Ordering::SeqCst);
+        self.sel.released.store(true, Ordering::SeqCst);
+    }
+
+    // Whether both are arms of the same select.
+    pub(crate) fn same(&self, other: &Token) -> bool {
+        Arc::ptr_eq(&self.sel, &other.sel)
+    }
+
+    // Wakes the selecting thread, for the channel to keep with its waiting tasks.
+    pub(crate) fn waker(&self) -> Waker {
+        Waker::from(self.sel.clone())
+    }
+}
+
+pub(crate) enum Claim {
+    Won,
+    // Our own select has already chosen another arm.
+    Lost,
+    // The peer's select has chosen another arm, or given up.
+    Taken,
+}
+
+// Claim a peer's offer, and our own token first if we are selecting too.
+pub(crate) fn claim_pair(own: Option<&Token>, peer: &Token) -> Claim {
+    if let Some(o) = own {
+        if !o.claim() {
+            return Claim::Lost;
+        }
+    }
+    match peer.claim() {
+        true => Claim::Won,
+        false => {
+            if let Some(o) = own {
+                o.release();
+            }
+            Claim::Taken
+        }
+    }
+}
+
+pub(crate) mod private {
+    pub trait Sealed {}
+}
+
+// The outcome of trying an arm.
+pub enum Attempt<O> {
+    Ready(O),
+    Pending,
+    // The select chose another arm in the meantime.
+    Lost,
+}
+
+// A channel operation a Select can wait on, implemented by the channels of this crate.
+pub trait Selectable: private::Sealed {
+    type Output;
+
+    // Complete now if ready, claiming the token first.
+    // Otherwise, if offer is set, leave an offer a peer may complete, and register the token's waker.
+    fn attempt(&mut self, token: &Token, offer: bool) -> Attempt<Self::Output>;
+
+    // Complete after a peer claimed the token for this arm.
+    fn finish(&mut self, token: &Token) -> Self::Output;
+
+    // Take back any offer left for the token.
+    fn withdraw(&mut self, token: &Token);
+
+    // Wake the channel's waiters, after a claim on the token was given back.
+    fn poke(&self);
+}
+
+trait Arm<U> {
+    fn attempt(&mut self, token: &Token, offer: bool) -> Attempt<U>;
+    fn finish(&mut self, token: &Token) -> U;
+    fn withdraw(&mut self, token: &Token);
+    fn poke(&self);
+}
+
+struct Case<S, F> {
+    op: S,
+    f: Option<F>,
+}
+
+impl<U, S, F> Case<S, F>
+where
+    S: Selectable,
+    F: FnOnce(S::Output) -> U,
+{
+    fn handle(&mut self, out: S::Output) -> U {
+        match self.f.take() {
+            Some(f) => f(out),
+            None => unreachable!("A select completes one arm, once"),
+        }
+    }
+}
+
+impl<U, S, F> Arm<U> for Case<S, F>
+where
+    S: Selectable,
+    F: FnOnce(S::Output) -> U,
+{
+    fn attempt(&mut self, token: &Token, offer: bool) -> Attempt<U> {
+        match self.op.attempt(token, offer) {
+            Attempt::Ready(out) => Attempt::Ready(self.handle(out)),
+            Attempt::Pending => Attempt::Pending,
+            Attempt::Lost => Attempt::Lost,
+        }
+    }
+
+    fn finish(&mut self, token: &Token) -> U {
+        let out = self.op.finish(token);
+        self.handle(out)
+    }
+
+    fn withdraw(&mut self, token: &Token) {
+        self.op.withdraw(token)
+    }
+
+    fn poke(&self) {
+        self.op.poke()
+    }
+}
+
+type Fallback<'a, U> = Box<dyn FnOnce() -> U + 'a>;
+
+// A choice between channel operations, each with a handler for its outcome.
+// Operations come from the channels, e.g. ping.select_recv() or ping.select_send(t).
+pub struct Select<'a, U> {
+    arms: Vec<Box<dyn Arm<U> + 'a>>,
+    timeout: Option<(Instant, Fallback<'a, U>)>,
+    default: Option<Fallback<'a, U>>,
+}
+
+impl<'a, U> Default for Select<'a, U> {
+    fn default() -> Select<'a, U> {
+        Select::new()
+    }
+}
+
+impl<'a, U> Select<'a, U> {
+    pub fn new() -> Select<'a, U> {
+        Select {
+            arms: Vec::new(),
+            timeout: None,
+            default: None,
+        }
+    }
+
+    pub fn arm<S, F>(mut self, op: S, f: F) -> Select<'a, U>
+    where
+        S: Selectable + 'a,
+        F: FnOnce(S::Output) -> U + 'a,
+    {
+        self.arms.push(Box::new(Case { op, f: Some(f) }));
+        self
+    }
+
+    // Run f instead, if no arm is ready within the timeout.
+    pub fn timeout<F>(self, timeout: Duration, f: F) -> Select<'a, U>
+    where
+        F: FnOnce() -> U + 'a,
+    {
+        self.deadline(Instant::now() + timeout, f)
+    }
+
+    pub fn deadline<F>(mut self, deadline: Instant, f: F) -> Select<'a, U>
+    where
+        F: FnOnce() -> U + 'a,
+    {
+        self.timeout = Some((deadline, Box::new(f)));
+        self
+    }
+
+    // Run f instead, if no arm is ready now. The select then never waits, or offers.
+    pub fn default<F>(mut self, f: F) -> Select<'a, U>
+    where
+        F: FnOnce() -> U + 'a,
+    {
+        self.default = Some(Box::new(f));
+        self
+    }
+
+    // Block until one arm completes, and return what its handler made of it.
+    pub fn wait(mut self) -> U {
+        if self.arms.is_empty() && self.timeout.is_none() && self.default.is_none() {
+            panic!("A Select with no arms would wait forever");
+        }
+        let sel = Arc::new(Selector {
+            chosen: AtomicUsize::new(WAITING),
+            released: AtomicBool::new(false),
+            thread: thread::current(),
+        });
+        let tokens: Vec<Token> = (0..self.arms.len())
+            .map(|arm| Token {
+                sel: sel.clone(),
+                arm,
+            })
+            .collect();
+        let n = self.arms.len().max(1);
+        let start = RandomState::new().build_hasher().finish() as usize % n;
+        let offer = self.default.is_none();
+
+        loop {
+            let chosen = sel.chosen.load(Ordering::SeqCst);
+            if chosen < self.arms.len() {
+                return self.finish(&tokens, chosen);
+            }
+
+            for k in 0..self.arms.len() {
+                let i = (start + k) % n;
+                match self.arms[i].attempt(&tokens[i], offer) {
+                    Attempt::Ready(u) => {
+                        self.withdraw(&tokens, Some(i));
+                        return u;
+                    }
+                    Attempt::Pending => {}
+                    Attempt::Lost => break,
+                }
+            }
+            if sel.released.swap(false, Ordering::SeqCst) {
+                for arm in &self.arms {
+                    arm.poke();
+                }
+                continue;
+            }
+            if sel.chosen.load(Ordering::SeqCst) != WAITING {
+                continue;
+            }
+
+            if let Some(f) = self.default.take() {
+                match self.abort(&tokens) {
+                    true => return f(),
+                    false => continue,
+                }
+            }
+            match self.timeout.as_ref().map(|(d, _)| *d) {
+                None => thread::park(),
+                Some(d) => match d.checked_duration_since(Instant::now()) {
+                    Some(left) => thread::park_timeout(left),
+                    None => {
+                        if self.abort(&tokens) {
+                            if let Some((_, f)) = self.timeout.take() {
+                                return f();
+                            }
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    // Give up on every arm, unless a peer chose one first.
+    fn abort(&mut self, tokens: &[Token]) -> bool {
+        let aborted = match tokens.first() {
+            None => true,
+            Some(t) => t
+                .sel
+                .chosen
+                .compare_exchange(WAITING, ABORTED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok(),
+        };
+        if aborted {
+            self.withdraw(tokens, None);
+        }
+        aborted
+    }
+
+    fn finish(&mut self, tokens: &[Token], chosen: usize) -> U {
+        self.withdraw(tokens, Some(chosen));
+        self.arms[chosen].finish(&tokens[chosen])
+    }
+
+    fn withdraw(&mut self, tokens: &[Token], except: Option<usize>) {
+        for (i, arm) in self.arms.iter_mut().enumerate() {
+            if Some(i) != except {
+                arm.withdraw(&tokens[i]);
+            }
+        }
+    }
+}
+
+// Choose between channel operations, as Select does:
+//   select! {
+//       recv(a) -> r => println!("{:?}", r),
+//       send(b, 5) -> r => r.is_ok(),
+//       timeout(Duration::from_secs(1)) => false,
+//       default => false,
+//   }
+// Handlers are closures over their pattern, returning one type.
+#[macro_export]
+macro_rules! select {
+    (@build $sel:expr;) => {
+        $sel.wait()
+    };
+    (@build $sel:expr; recv($ch:expr) -> $res:pat => $body:expr $(, $($rest:tt)*)?) => {
+        $crate::select!(@build $sel.arm(($ch).select_recv(), |$res| $body); $($($rest)*)?)
+    };
+    (@build $sel:expr; send($ch:expr, $val:expr) -> $res:pat => $body:expr $(, $($rest:tt)*)?) => {
+        $crate::select!(@build $sel.arm(($ch).select_send($val), |$res| $body); $($($rest)*)?)
+    };
+    (@build $sel:expr; timeout($d:expr) => $body:expr $(, $($rest:tt)*)?) => {
+        $crate::select!(@build $sel.timeout($d, || $body); $($($rest)*)?)
+    };
+    (@build $sel:expr; default => $body:expr $(, $($rest:tt)*)?) => {
+        $crate::select!(@build $sel.default(|| $body); $($($rest)*)?)
+    };
+    ($($arms:tt)+) => {
+        $crate::select!(@build $crate::select::Select::new(); $($arms)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ping::{Ping, PingError, PingState};
+
+    fn await_state(p: &Ping<u32>, want: fn(&PingState) -> bool) {
+        while !want(&p.state()) {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_select_arms() {
+        let (mut a, mut b) = (Ping::<u32>::new(), Ping::<u32>::new());
+        let mut b2 = b.clone();
+        let h = thread::spawn(move || b2.send(7));
+        let got = select! {
+            recv(a) -> r => (0, r.ok()),
+            recv(b) -> r => (1, r.ok()),
+        };
+        assert_eq!((1, Some(7)), got);
+        h.join().expect("Failed to Join Threads!").unwrap();
+        // The other arm left its channel as it was.
+        match a.state() {
+            PingState::Open => println!(),
+            st => panic!("An unchosen arm left its channel {}", st),
+        }
+
+        let r = select! {
+            recv(a) -> _ => "recv",
+            timeout(Duration::from_millis(10)) => "timeout",
+        };
+        assert_eq!("timeout", r);
+        let r = Select::new()
+            .arm(a.select_send(1), |_| "send")
+            .default(|| "default")
+            .wait();
+        assert_eq!("default", r);
+
+        // A used channel completes its arm with the error.
+        match select! { recv(b) -> r => r } {
+            Err(PingError::UsedRecvChanError) => println!(),
+            _ => panic!("Recieved twice from one Ping"),
+        }
+    }
+
+    #[test]
+    fn test_select_meets_select() {
+        for _ in 0..200 {
+            let (a, b) = (Ping::<u32>::new(), Ping::<u32>::new());
+            let (mut a2, mut b2) = (a.clone(), b.clone());
+            let h = thread::spawn(move || {
+                select! {
+                    send(a2, 1) -> r => r.map(|_| 1).map_err(|e| e.0),
+                    send(b2, 2) -> r => r.map(|_| 2).map_err(|e| e.0),
+                }
+            });
+            let (mut a3, mut b3) = (a.clone(), b.clone());
+            let got = select! {
+                recv(a3) -> r => r,
+                recv(b3) -> r => r,
+            };
+            let sent = h.join().expect("Failed to Join Threads!");
+            assert_eq!(sent.unwrap(), got.unwrap());
+
+            // Exactly one Ping was used, and the other is still Open.
+            let used = [&a, &b]
+                .iter()
+                .filter(|p| matches!(p.state(), PingState::Used))
+                .count();
+            let open = [&a, &b]
+                .iter()
+                .filter(|p| matches!(p.state(), PingState::Open))
+                .count();
+            assert_eq!((1, 1), (used, open));
+        }
+    }
+
+    #[test]
+    fn test_select_fair() {
+        let mut counts = [0; 2];
+        for _ in 0..200 {
+            let (mut a, mut b) = (Ping::<u32>::new(), Ping::<u32>::new());
+            let (mut a2, mut b2) = (a.clone(), b.clone());
+            let ha = thread::spawn(move || a2.send_timeout(0, Duration::from_secs(10)));
+            let hb = thread::spawn(move || b2.send_timeout(1, Duration::from_secs(10)));
+            await_state(&a, |s| matches!(s, PingState::AwaitRecv));
+            await_state(&b, |s| matches!(s, PingState::AwaitRecv));
+            let got = select! {
+                recv(a) -> r => r.unwrap(),
+                recv(b) -> r => r.unwrap(),
+            };
+            counts[got as usize] += 1;
+            // Collect the other sender.
+            let _ = a.try_recv();
+            let _ = b.try_recv();
+            ha.join().expect("Failed to Join Threads!").unwrap();
+            hb.join().expect("Failed to Join Threads!").unwrap();
+        }
+        assert!(
+            counts[0] > 40 && counts[1] > 40,
+            "Unfair choice {:?}",
+            counts
+        );
+    }
+}
@@ -0,0 +1,742 @@
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::once_cell::OnceCellState;
+use crate::ping::PingState;
+
+// Cross-process variants of OnceCell and Ping.
+// Both live in a shared memory region, either named (shm_open, visible under /dev/shm)
+// or anonymous (memfd_create, shared with children through fork).
+// Blocking is done on futexes living inside the region, so no process-local
+// Mutex, Barrier, or WaitGroup is involved.
+// Values are copied bit for bit into the region, thus the SharedPod bound.
+// Serialized payloads can travel as [u8; N].
+
+// How often a blocked process checks that its peer still exists.
+const PEER_POLL: Duration = Duration::from_millis(10);
+
+const ONCE_MAGIC: u32 = 0x5154_4f43; // "QTOC"
+const PING_MAGIC: u32 = 0x5154_5047; // "QTPG"
+
+// OnceCell states, stored in the low bits of the futex word.
+// A writer claims the cell with its pid in the high bits, so the claim and the
+// pid are published together, and a writer dying at any point can be detected.
+// Linux pids stay below 2^22, well clear of the state bits.
+const EMPTY: u32 = 0;
+const WRITING: u32 = 1;
+const FILLED: u32 = 2;
+const STATE_BITS: u32 = 2;
+
+// Ping flags, stored in the futex word.
+// A side is claimed by swapping its pid into the sender or receiver word,
+// and the claimed flag only follows to wake whoever waits on the futex.
+const SEND_CLAIMED: u32 = 1;
+const RECV_CLAIMED: u32 = 2;
+const VALUE_READY: u32 = 4;
+const TAKEN: u32 = 8;
+
+/// Marker for types which may be copied bit for bit into memory shared between processes.
+///
+/// # Safety
+///
+/// Implementors must not contain pointers, references, or anything else whose meaning
+/// is local to one address space.
+pub unsafe trait SharedPod: Copy + 'static {}
+
+unsafe impl SharedPod for () {}
+unsafe impl SharedPod for bool {}
+unsafe impl SharedPod for char {}
+unsafe impl SharedPod for u8 {}
+unsafe impl SharedPod for u16 {}
+unsafe impl SharedPod for u32 {}
+unsafe impl SharedPod for u64 {}
+unsafe impl SharedPod for u128 {}
+unsafe impl SharedPod for usize {}
+unsafe impl SharedPod for i8 {}
+unsafe impl SharedPod for i16 {}
+unsafe impl SharedPod for i32 {}
+unsafe impl SharedPod for i64 {}
+unsafe impl SharedPod for i128 {}
+unsafe impl SharedPod for isize {}
+unsafe impl SharedPod for f32 {}
+unsafe impl SharedPod for f64 {}
+unsafe impl<T: SharedPod, const N: usize> SharedPod for [T; N] {}
+
+// Identifies T in a region's header, so a region is only opened as the type it was created as.
+// FNV-1a over the type's name, which unlike TypeId is the same in every process.
+fn type_tag<T>() -> u64 {
+    std::any::type_name::<T>()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+// The owner of a single mapping. Unmapped (but not unlinked) on drop.
+struct ShmRegion {
+    ptr: *mut u8,
+    len: usize,
+    fd: libc::c_int,
+}
+
+// The region is only accessed through atomics and the protocols below.
+unsafe impl Send for ShmRegion {}
+unsafe impl Sync for ShmRegion {}
+
+impl ShmRegion {
+    fn create(name: &str, len: usize) -> Result<ShmRegion, ShmError> {
+        let name = shm_name(name)?;
+        let fd = unsafe {
+            libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o600,
+            )
+        };
+        if fd < 0 {
+            return Err(ShmError::Os(io::Error::last_os_error()));
+        }
+
+        match ShmRegion::map(fd, len, true) {
+            Ok(r) => Ok(r),
+            Err(err) => {
+                unsafe { libc::shm_unlink(name.as_ptr()) };
+                Err(err)
+            }
+        }
+    }
+
+    fn open(name: &str, len: usize) -> Result<ShmRegion, ShmError> {
+        let name = shm_name(name)?;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) };
+        if fd < 0 {
+            return Err(ShmError::Os(io::Error::last_os_error()));
+        }
+
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(ShmError::Os(err));
+        }
+
+        // Either the creator has not sized the region yet, or it holds something else.
+        if unsafe { stat.assume_init() }.st_size as usize != len {
+            unsafe { libc::close(fd) };
+            return Err(ShmError::LayoutMismatch);
+        }
+
+        ShmRegion::map(fd, len, false)
+    }
+
+    fn anonymous(len: usize) -> Result<ShmRegion, ShmError> {
+        let fd = unsafe {
+            libc::memfd_create(
+                b"quartz\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(ShmError::Os(io::Error::last_os_error()));
+        }
+
+        ShmRegion::map(fd, len, true)
+    }
+
+    // Takes ownership of fd, closing it on failure.
+    fn map(fd: libc::c_int, len: usize, truncate: bool) -> Result<ShmRegion, ShmError> {
+        // ftruncate zero fills, which is every state's EMPTY/Open value.
+        if truncate && unsafe { libc::ftruncate(fd, len as libc::off_t) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(ShmError::Os(err));
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(ShmError::Os(err));
+        }
+
+        Ok(ShmRegion {
+            ptr: ptr as *mut u8,
+            len,
+            fd,
+        })
+    }
+}
+
+impl Drop for ShmRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Remove a named region. Processes which already opened it keep their mapping.
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+    let name = shm_name(name)?;
+    match unsafe { libc::shm_unlink(name.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(ShmError::Os(io::Error::last_os_error())),
+    }
+}
+
+fn shm_name(name: &str) -> Result<CString, ShmError> {
+    let name = match name.starts_with('/') {
+        true => name.to_string(),
+        false => format!("/{}", name),
+    };
+
+    match name[1..].contains('/') {
+        true => Err(ShmError::InvalidName),
+        false => CString::new(name).map_err(|_| ShmError::InvalidName),
+    }
+}
+
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    let ts = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+
+    // Shared (non-private) futex, the word may be mapped at different addresses.
+    // Spurious wakeups, EAGAIN and ETIMEDOUT are all handled by the caller's loop.
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            libc::FUTEX_WAIT,
+            expected,
+            &ts as *const libc::timespec,
+        );
+    }
+}
+
+fn futex_wake_all(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            libc::FUTEX_WAKE,
+            i32::MAX,
+        );
+    }
+}
+
+// Callers only ask about a pid they found in a claim, never 0.
+// Zombies count as dead, since they will never touch the region again.
+// Does not allocate, so it is safe to call from a freshly forked child.
+fn peer_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return true;
+    }
+
+    if unsafe { libc::kill(pid, 0) } < 0
+        && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+    {
+        return false;
+    }
+
+    // "/proc/<pid>/stat", built on the stack.
+    let mut path = [0u8; 32];
+    let mut digits = [0u8; 10];
+    let mut n = pid as u32;
+    let mut d = 0;
+    while n > 0 || d == 0 {
+        digits[d] = b'0' + (n % 10) as u8;
+        n /= 10;
+        d += 1;
+    }
+    path[..6].copy_from_slice(b"/proc/");
+    for i in 0..d {
+        path[6 + i] = digits[d - 1 - i];
+    }
+    path[6 + d..6 + d + 5].copy_from_slice(b"/stat");
+
+    let fd = unsafe { libc::open(path.as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+    if fd < 0 {
+        // kill already vouched for it, perhaps /proc is not mounted.
+        return true;
+    }
+    let mut buf = [0u8; 512];
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    unsafe { libc::close(fd) };
+    if n <= 0 {
+        return true;
+    }
+
+    // The state follows the parenthesized command name, which may itself contain spaces.
+    let stat = &buf[..n as usize];
+    match stat.iter().rposition(|b| *b == b')') {
+        Some(i) => !matches!(stat.get(i + 2), Some(b'Z') | Some(b'X')),
+        None => true,
+    }
+}
+
+fn own_pid() -> i32 {
+    unsafe { libc::getpid() }
+}
+
+#[repr(C)]
+struct OnceHeader<T> {
+    magic: AtomicU32,
+    size: u32,
+    tag: u64,
+    state: AtomicU32,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The cross-process OnceCell.
+// Like OnceCell, any number of processes may write, provided they write the same value,
+// and any number may read, blocking until the first write concludes.
+// If a writer dies between claiming the cell and filling it, everyone else gets PeerDied.
+pub struct ShmOnceCell<T>(Arc<ShmRegion>, PhantomData<T>)
+where
+    T: SharedPod + PartialEq;
+
+impl<T: SharedPod + PartialEq> Clone for ShmOnceCell<T> {
+    fn clone(&self) -> ShmOnceCell<T> {
+        ShmOnceCell::<T>(self.0.clone(), PhantomData)
+    }
+}
+
+impl<T: SharedPod + PartialEq> ShmOnceCell<T> {
+    // Create a new, named, empty cell. Fails if the name is taken.
+    pub fn create(name: &str) -> Result<ShmOnceCell<T>, ShmError> {
+        let region = ShmRegion::create(name, mem::size_of::<OnceHeader<T>>())?;
+        Ok(ShmOnceCell::<T>::init(region))
+    }
+
+    // Open a cell created by another process.
+    pub fn open(name: &str) -> Result<ShmOnceCell<T>, ShmError> {
+        let region = ShmRegion::open(name, mem::size_of::<OnceHeader<T>>())?;
+        let c = ShmOnceCell::<T>(Arc::new(region), PhantomData);
+        match c.header().magic.load(Ordering::Acquire) {
+            ONCE_MAGIC
+                if c.header().size as usize == mem::size_of::<T>()
+                    && c.header().tag == type_tag::<T>() =>
+            {
+                Ok(c)
+            }
+            0 => Err(ShmError::Uninitialized),
+            _ => Err(ShmError::LayoutMismatch),
+        }
+    }
+
+    // Create an unnamed cell, shared with any children forked after this call.
+    pub fn anonymous() -> Result<ShmOnceCell<T>, ShmError> {
+        let region = ShmRegion::anonymous(mem::size_of::<OnceHeader<T>>())?;
+        Ok(ShmOnceCell::<T>::init(region))
+    }
+
+    fn init(region: ShmRegion) -> ShmOnceCell<T> {
+        let c = ShmOnceCell::<T>(Arc::new(region), PhantomData);
+        let h = c.header_ptr();
+        unsafe {
+            ptr::addr_of_mut!((*h).size).write(mem::size_of::<T>() as u32);
+            ptr::addr_of_mut!((*h).tag).write(type_tag::<T>());
+        }
+        c.header().magic.store(ONCE_MAGIC, Ordering::Release);
+        c
+    }
+
+    pub fn state(&self) -> OnceCellState {
+        match self.header().state.load(Ordering::Acquire) {
+            FILLED => OnceCellState::Filled,
+            _ => OnceCellState::Empty,
+        }
+    }
+
+    // Attempt to deposit a value into the cell.
+    // Subsequent writes of the same value succeed, differing values are an error.
+    pub fn write(&mut self, t: T) -> Result<(), ShmError> {
+        let h = self.header();
+        let claim = ((own_pid() as u32) << STATE_BITS) | WRITING;
+        match h
+            .state
+            .compare_exchange(EMPTY, claim, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*h.val.get()).write(t) };
+                h.state.store(FILLED, Ordering::Release);
+                futex_wake_all(&h.state);
+                Ok(())
+            }
+            // Lost the race, turn the write into a read.
+            Err(_) => match self.read()? == t {
+                true => Ok(()),
+                false => Err(ShmError::ValueMismatch),
+            },
+        }
+    }
+
+    // Blocks until the cell is filled.
+    pub fn read(&self) -> Result<T, ShmError> {
+        let h = self.header();
+        loop {
+            let s = h.state.load(Ordering::Acquire);
+            match s {
+                FILLED => return Ok(unsafe { (*h.val.get()).assume_init() }),
+                EMPTY => {}
+                _ if !peer_alive((s >> STATE_BITS) as i32) => return Err(ShmError::PeerDied),
+                _ => {}
+            }
+            futex_wait(&h.state, s, PEER_POLL);
+        }
+    }
+
+    // Non-blocking, None until the first write concludes.
+    pub fn sample(&self) -> Result<Option<T>, ShmError> {
+        let h = self.header();
+        let s = h.state.load(Ordering::Acquire);
+        match s {
+            FILLED => Ok(Some(unsafe { (*h.val.get()).assume_init() })),
+            EMPTY => Ok(None),
+            _ if !peer_alive((s >> STATE_BITS) as i32) => Err(ShmError::PeerDied),
+            _ => Ok(None),
+        }
+    }
+
+    fn header_ptr(&self) -> *mut OnceHeader<T> {
+        self.0.ptr as *mut OnceHeader<T>
+    }
+
+    fn header(&self) -> &OnceHeader<T> {
+        unsafe { &*self.header_ptr() }
+    }
+}
+
+#[repr(C)]
+struct PingHeader<T> {
+    magic: AtomicU32,
+    size: u32,
+    tag: u64,
+    flags: AtomicU32,
+    sender: AtomicI32,
+    receiver: AtomicI32,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The cross-process Ping.
+// A single-use rendezvous: send blocks until the value has been taken, recv blocks until it is given.
+// Only the first sender and reciever are admitted, as with Ping.
+// A process blocked on a peer which has died gets PeerDied instead of hanging.
+pub struct ShmPing<T>(Arc<ShmRegion>, PhantomData<T>)
+where
+    T: SharedPod;
+
+impl<T: SharedPod> Clone for ShmPing<T> {
+    fn clone(&self) -> ShmPing<T> {
+        ShmPing::<T>(self.0.clone(), PhantomData)
+    }
+}
+
+impl<T: SharedPod> ShmPing<T> {
+    // Create a new, named, open channel. Fails if the name is taken.
+    pub fn create(name: &str) -> Result<ShmPing<T>, ShmError> {
+        let region = ShmRegion::create(name, mem::size_of::<PingHeader<T>>())?;
+        Ok(ShmPing::<T>::init(region))
+    }
+
+    // Open a channel created by another process.
+    pub fn open(name: &str) -> Result<ShmPing<T>, ShmError> {
+        let region = ShmRegion::open(name, mem::size_of::<PingHeader<T>>())?;
+        let p = ShmPing::<T>(Arc::new(region), PhantomData);
+        match p.header().magic.load(Ordering::Acquire) {
+            PING_MAGIC
+                if p.header().size as usize == mem::size_of::<T>()
+                    && p.header().tag == type_tag::<T>() =>
+            {
+                Ok(p)
+            }
+            0 => Err(ShmError::Uninitialized),
+            _ => Err(ShmError::LayoutMismatch),
+        }
+    }
+
+    // Create an unnamed channel, shared with any children forked after this call.
+    pub fn anonymous() -> Result<ShmPing<T>, ShmError> {
+        let region = ShmRegion::anonymous(mem::size_of::<PingHeader<T>>())?;
+        Ok(ShmPing::<T>::init(region))
+    }
+
+    fn init(region: ShmRegion) -> ShmPing<T> {
+        let p = ShmPing::<T>(Arc::new(region), PhantomData);
+        let h = p.header_ptr();
+        unsafe {
+            ptr::addr_of_mut!((*h).size).write(mem::size_of::<T>() as u32);
+            ptr::addr_of_mut!((*h).tag).write(type_tag::<T>());
+        }
+        p.header().magic.store(PING_MAGIC, Ordering::Release);
+        p
+    }
+
+    pub fn state(&self) -> PingState {
+        let h = self.header();
+        let sent = h.sender.load(Ordering::Acquire) != 0;
+        match (sent, h.receiver.load(Ordering::Acquire) != 0) {
+            (true, true) => PingState::Used,
+            (true, false) => PingState::AwaitRecv,
+            (false, true) => PingState::AwaitSend,
+            (false, false) => PingState::Open,
+        }
+    }
+
+    pub fn send(&mut self, t: T) -> Result<(), ShmError> {
+        let h = self.header();
+        if h.sender
+            .compare_exchange(0, own_pid(), Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(ShmError::UsedSendChanError);
+        }
+        h.flags.fetch_or(SEND_CLAIMED, Ordering::AcqRel);
+
+        // The slot is ours alone, the reciever only looks after VALUE_READY.
+        unsafe { (*h.val.get()).write(t) };
+        h.flags.fetch_or(VALUE_READY, Ordering::AcqRel);
+        futex_wake_all(&h.flags);
+
+        // Rendezvous, await the pickup.
+        loop {
+            let flags = h.flags.load(Ordering::Acquire);
+            if flags & TAKEN != 0 {
+                return Ok(());
+            }
+            let receiver = h.receiver.load(Ordering::Acquire);
+            if receiver != 0 && !peer_alive(receiver) {
+                return Err(ShmError::PeerDied);
+            }
+            futex_wait(&h.flags, flags, PEER_POLL);
+        }
+    }
+
+    pub fn recv(&mut self) -> Result<T, ShmError> {
+        let h = self.header();
+        if h.receiver
+            .compare_exchange(0, own_pid(), Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(ShmError::UsedRecvChanError);
+        }
+        h.flags.fetch_or(RECV_CLAIMED, Ordering::AcqRel);
+        futex_wake_all(&h.flags);
+
+        loop {
+            let flags = h.flags.load(Ordering::Acquire);
+            if flags & VALUE_READY != 0 {
+                let t = unsafe { (*h.val.get()).assume_init() };
+                h.flags.fetch_or(TAKEN, Ordering::AcqRel);
+                futex_wake_all(&h.flags);
+                return Ok(t);
+            }
+            let sender = h.sender.load(Ordering::Acquire);
+            if sender != 0 && !peer_alive(sender) {
+                return Err(ShmError::PeerDied);
+            }
+            futex_wait(&h.flags, flags, PEER_POLL);
+        }
+    }
+
+    fn header_ptr(&self) -> *mut PingHeader<T> {
+        self.0.ptr as *mut PingHeader<T>
+    }
+
+    fn header(&self) -> &PingHeader<T> {
+        unsafe { &*self.header_ptr() }
+    }
+}
+
+#[derive(Debug)]
+pub enum ShmError {
+    Os(io::Error),
+    InvalidName,
+    LayoutMismatch,
+    Uninitialized,
+    ValueMismatch,
+    UsedSendChanError,
+    UsedRecvChanError,
+    PeerDied,
+}
+
+impl fmt::Display for ShmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShmError::Os(err) => write!(f, "Shared memory system call failed: {}", err),
+            ShmError::InvalidName => write!(f, "Shared memory names may not contain '/' or NUL past the leading '/'"),
+            ShmError::LayoutMismatch => write!(f, "Shared memory region does not hold a structure of the requested type"),
+            ShmError::Uninitialized => write!(f, "Shared memory region has not been initialized by its creator"),
+            ShmError::ValueMismatch => write!(f, "ShmOnceCell recieved differing values on write, only one value may be written to a give ShmOnceCell"),
+            ShmError::UsedSendChanError => write!(f, "This instance of ShmPing already has a sender"),
+            ShmError::UsedRecvChanError => write!(f, "This instance of ShmPing already has a reciever"),
+            ShmError::PeerDied => write!(f, "The peer process exited without completing its side of the exchange"),
+        }
+    }
+}
+
+impl Error for ShmError {
+    fn description(&self) -> &str {
+        match self {
+            ShmError::Os(_) => "Shared memory system call failed",
+            ShmError::InvalidName => "Shared memory names may not contain '/' or NUL past the leading '/'",
+            ShmError::LayoutMismatch => "Shared memory region does not hold a structure of the requested type",
+            ShmError::Uninitialized => "Shared memory region has not been initialized by its creator",
+            ShmError::ValueMismatch => "ShmOnceCell recieved differing values on write, only one value may be written to a give ShmOnceCell",
+            ShmError::UsedSendChanError => "This instance of ShmPing already has a sender",
+            ShmError::UsedRecvChanError => "This instance of ShmPing already has a reciever",
+            ShmError::PeerDied => "The peer process exited without completing its side of the exchange",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ShmError::Os(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // The child must not allocate, another test thread may hold the allocator's lock.
+    fn fork<F: FnOnce() -> i32>(f: F) -> libc::pid_t {
+        match unsafe { libc::fork() } {
+            0 => {
+                let code = f();
+                unsafe { libc::_exit(code) }
+            }
+            pid => {
+                assert!(pid > 0, "fork failed");
+                pid
+            }
+        }
+    }
+
+    fn reap(pid: libc::pid_t) -> i32 {
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        status
+    }
+
+    #[test]
+    fn test_shm_once_cell() {
+        let name = format!("/quartz-test-once-{}", own_pid());
+        let mut p1 = ShmOnceCell::<[u8; 4]>::create(&name).unwrap();
+        let mut q1 = ShmOnceCell::<[u8; 4]>::open(&name).unwrap();
+        // Same size, different type.
+        match ShmOnceCell::<u32>::open(&name) {
+            Err(ShmError::LayoutMismatch) => println!(),
+            _ => panic!("Opened a [u8; 4] region as u32"),
+        };
+        unlink(&name).unwrap();
+
+        match p1.state() {
+            OnceCellState::Empty => println!(),
+            other => panic!("Unexpected state in fresh cell {}", other),
+        };
+        assert_eq!(None, p1.sample().unwrap());
+
+        let child = fork(|| match q1.write([1, 2, 3, 4]) {
+            Ok(_) => 0,
+            Err(_) => 1,
+        });
+
+        assert_eq!([1, 2, 3, 4], p1.read().unwrap());
+        assert_eq!(0, reap(child));
+
+        p1.write([1, 2, 3, 4]).expect("Matching write was refused");
+        match p1.write([4, 3, 2, 1]) {
+            Err(ShmError::ValueMismatch) => println!(),
+            other => panic!("Mismatched write was accepted {:?}", other),
+        };
+
+        match ShmPing::<u64>::open(&name) {
+            Err(ShmError::Os(_)) => println!(),
+            _ => panic!("Opened an unlinked region"),
+        };
+    }
+
+    #[test]
+    fn test_shm_ping() {
+        let mut p1 = ShmPing::<u64>::anonymous().unwrap();
+        let mut q1 = p1.clone();
+        let mut p2 = ShmPing::<u64>::anonymous().unwrap();
+        let mut q2 = p2.clone();
+
+        let child = fork(|| {
+            let x = match q1.recv() {
+                Ok(x) => x,
+                Err(_) => return 1,
+            };
+            match q2.send(x * 2) {
+                Ok(_) => 0,
+                Err(_) => 2,
+            }
+        });
+
+        p1.send(21).unwrap();
+        assert_eq!(42, p2.recv().unwrap());
+        assert_eq!(0, reap(child));
+
+        match p1.state() {
+            PingState::Used => println!(),
+            other => panic!("Unexpected state in used ping {}", other),
+        };
+        match p1.send(1) {
+            Err(ShmError::UsedSendChanError) => println!(),
+            other => panic!("Send allowed on used channel {:?}", other),
+        };
+        match p2.recv() {
+            Err(ShmError::UsedRecvChanError) => println!(),
+            other => panic!("Recv allowed on used channel {:?}", other),
+        };
+    }
+
+    #[test]
+    fn test_shm_ping_peer_died() {
+        let mut p = ShmPing::<u64>::anonymous().unwrap();
+        let mut q = p.clone();
+
+        let child = fork(|| match q.recv() {
+            Ok(_) => 0,
+            Err(_) => 1,
+        });
+
+        // Wait for the child to park as the reciever, then kill it.
+        while let PingState::Open = p.state() {
+            thread::sleep(PEER_POLL);
+        }
+        unsafe { libc::kill(child, libc::SIGKILL) };
+
+        match p.send(7) {
+            Err(ShmError::PeerDied) => println!(),
+            other => panic!("Send to a dead reciever returned {:?}", other),
+        };
+        reap(child);
+    }
+}
@@ -0,0 +1,754 @@
+use std::any::Any;
+use std::collections::{BTreeSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+
+use crate::once_cell::OnceCell;
+
+// Functional reactive signals.
+// A Signal always has a value, and an Events stream has occurrences. Both live in a Graph,
+// and derived ones are recomputed whenever what they derive from changes.
+// Each node sits one higher than the highest of its inputs, and a change is propagated
+// lowest node first, so every node runs once per change, after all its inputs have settled:
+// no node ever sees one input updated and another not (a glitch).
+// A derived signal whose value comes out equal to its last one does not propagate further.
+// Subscribers are told of changes after they settle, and may block or await them.
+// A change propagates under the graph's lock, so the functions given to map, combine, filter
+// and fold run under it too: they must not get, set or emit anything in the same graph,
+// which would deadlock.
+// Nodes are never removed: a node, its value and its function live as long as the graph,
+// even once every handle to it is dropped, and go on being recomputed with their inputs.
+// Only subscribers are reclaimed, on the next change after they are dropped. So a graph suits
+// a fixed or growing network; one deriving short-lived signals should be dropped in turn.
+
+type Value = Box<dyn Any + Send>;
+// Given every node's value, a node's new value, or None if it did not change or fire.
+type Compute = Box<dyn FnMut(&[Value]) -> Option<Value> + Send>;
+// Given a node's value, passes it on, returning false once the subscriber has gone.
+type Sink = Box<dyn FnMut(&Value) -> bool + Send>;
+// Returns an Events node to no occurrence, after a round.
+type Clear = Box<dyn Fn() -> Value + Send>;
+
+pub struct Graph(Arc<GraphInner>);
+
+impl Clone for Graph {
+    fn clone(&self) -> Graph {
+        Graph(self.0.clone())
+    }
+}
+
+impl Default for Graph {
+    fn default() -> Graph {
+        Graph::new()
+    }
+}
+
+struct GraphInner {
+    state: Mutex<GraphState>,
+}
+
+#[derive(Default)]
+struct GraphState {
+    values: Vec<Value>,
+    computes: Vec<Option<Compute>>,
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    height: usize,
+    children: Vec<usize>,
+    sinks: Vec<Sink>,
+    clear: Option<Clear>,
+}
+
+// A value which changes over time.
+pub struct Signal<T> {
+    graph: Graph,
+    id: usize,
+    _t: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Signal<T> {
+    fn clone(&self) -> Signal<T> {
+        Signal {
+            graph: self.graph.clone(),
+            id: self.id,
+            _t: PhantomData,
+        }
+    }
+}
+
+// A signal set from outside the graph.
+pub struct Source<T>(Signal<T>);
+
+impl<T> Clone for Source<T> {
+    fn clone(&self) -> Source<T> {
+        Source(self.0.clone())
+    }
+}
+
+// Occurrences at moments in time.
+pub struct Events<T> {
+    graph: Graph,
+    id: usize,
+    _t: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Events<T> {
+    fn clone(&self) -> Events<T> {
+        Events {
+            graph: self.graph.clone(),
+            id: self.id,
+            _t: PhantomData,
+        }
+    }
+}
+
+// An event stream emitted into from outside the graph.
+pub struct EventSource<T>(Events<T>);
+
+impl<T> Clone for EventSource<T> {
+    fn clone(&self) -> EventSource<T> {
+        EventSource(self.0.clone())
+    }
+}
+
+fn value<T: 'static>(values: &[Value], id: usize) -> &T {
+    values[id]
+        .downcast_ref()
+        .expect("Signal nodes are created with the type of their handle")
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph(Arc::new(GraphInner {
+            state: Mutex::new(GraphState::default()),
+        }))
+    }
+
+    pub fn source<T>(&self, t: T) -> Result<Source<T>, SignalError>
+    where
+        T: PartialEq + Clone + Send + 'static,
+    {
+        let id = self.node(&[], |_, _| (Box::new(t), None), None)?;
+        Ok(Source(self.signal(id)))
+    }
+
+    pub fn event_source<T>(&self) -> Result<EventSource<T>, SignalError>
+    where
+        T: Clone + Send + 'static,
+    {
+        let id = self.node(&[], |_, _| (Box::new(None::<T>), None), Some(clear::<T>()))?;
+        Ok(EventSource(self.events(id)))
+    }
+
+    // A signal which is None until the cell is written, and then its value.
+    // The signal is set on the thread of the cell's first writer.
+    pub fn once_cell<T>(&self, cell: &mut OnceCell<T>) -> Result<Signal<Option<T>>, SignalError>
+    where
+        T: PartialEq + Clone + Send + Sync + 'static,
+    {
+        let id = self.node(&[], |_, _| (Box::new(None::<T>), None), None)?;
+        // A weak handle, as the cell may outlive every use of the graph.
+        let weak = Arc::downgrade(&self.0);
+        cell.add_handler(move |val| {
+            let v = val.read().clone();
+            if let Some(g) = Weak::upgrade(&weak) {
+                let _ = Graph(g).update(id, Box::new(v));
+            }
+        })
+        .map_err(|_| SignalError::PoisonGuard)?;
+        Ok(self.signal(id))
+    }
+
+    fn signal<T>(&self, id: usize) -> Signal<T> {
+        Signal {
+            graph: self.clone(),
+            id,
+            _t: PhantomData,
+        }
+    }
+
+    fn events<T>(&self, id: usize) -> Events<T> {
+        Events {
+            graph: self.clone(),
+            id,
+            _t: PhantomData,
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, GraphState>, SignalError> {
+        self.0.state.lock().map_err(|_| SignalError::PoisonGuard)
+    }
+
+    fn check(&self, other: &Graph) -> Result<(), SignalError> {
+        match Arc::ptr_eq(&self.0, &other.0) {
+            true => Ok(()),
+            false => Err(SignalError::ForeignSignal),
+        }
+    }
+
+    // Add a node above its inputs. Its initial value and compute are built knowing the node's
+    // own id and every value, under the same lock, so no change can slip in between.
+    fn node<F>(
+        &self,
+        inputs: &[usize],
+        build: F,
+        clear: Option<Clear>,
+    ) -> Result<usize, SignalError>
+    where
+        F: FnOnce(usize, &[Value]) -> (Value, Option<Compute>),
+    {
+        let mut state = self.lock()?;
+        let id = state.nodes.len();
+        let (init, compute) = build(id, &state.values);
+        let height = inputs
+            .iter()
+            .map(|i| state.nodes[*i].height + 1)
+            .max()
+            .unwrap_or(0);
+        for i in inputs {
+            state.nodes[*i].children.push(id);
+        }
+        state.values.push(init);
+        state.computes.push(compute);
+        state.nodes.push(Node {
+            height,
+            children: Vec::new(),
+            sinks: Vec::new(),
+            clear,
+        });
+        Ok(id)
+    }
+
+    fn subscribe<T>(&self, id: usize, latest: bool) -> Result<Subscriber<T>, SignalError>
+    where
+        T: Clone + Send + 'static,
+    {
+        let sub = Arc::new(SubInner {
+            state: Mutex::new(SubState {
+                queue: VecDeque::new(),
+                latest,
+                closed: false,
+                wakers: Vec::new(),
+            }),
+            cv: Condvar::new(),
+        });
+        let sink = SinkHandle(sub.clone());
+        let mut state = self.lock()?;
+        state.nodes[id].sinks.push(Box::new(move |v: &Value| {
+            let v = match latest {
+                true => v.downcast_ref::<T>().cloned(),
+                false => v.downcast_ref::<Option<T>>().cloned().flatten(),
+            };
+            if let Some(v) = v {
+                sink.push(v);
+            }
+            Arc::strong_count(&sink.0) > 1
+        }));
+        Ok(Subscriber(sub))
+    }
+
+    // Set a source node and propagate, one round under the lock.
+    fn update(&self, id: usize, v: Value) -> Result<(), SignalError> {
+        let mut state = self.lock()?;
+        state.values[id] = v;
+        state.propagate(id);
+        Ok(())
+    }
+}
+
+impl GraphState {
+    fn propagate(&mut self, start: usize) {
+        let mut fired = vec![start];
+        let mut agenda = BTreeSet::new();
+        for c in &self.nodes[start].children {
+            agenda.insert((self.nodes[*c].height, *c));
+        }
+
+        // Lowest first, so every input of a node has settled before it runs.
+        while let Some((_, i)) = agenda.pop_first() {
+            let mut compute = self.computes[i].take();
+            let out = compute.as_mut().and_then(|f| f(&self.values));
+            self.computes[i] = compute;
+            if let Some(v) = out {
+                self.values[i] = v;
+                fired.push(i);
+                for c in &self.nodes[i].children {
+                    agenda.insert((self.nodes[*c].height, *c));
+                }
+            }
+        }
+
+        for i in fired {
+            let (values, nodes) = (&mut self.values, &mut self.nodes);
+            let node = &mut nodes[i];
+            node.sinks.retain_mut(|s| s(&values[i]));
+            if let Some(clear) = &node.clear {
+                values[i] = clear();
+            }
+        }
+    }
+}
+
+fn clear<T: Send + 'static>() -> Clear {
+    Box::new(|| Box::new(None::<T>))
+}
+
+impl<T> Source<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    // Set the value, updating everything derived from it before returning.
+    // Setting an equal value changes nothing.
+    pub fn set(&self, t: T) -> Result<(), SignalError> {
+        let s = &self.0;
+        let mut state = s.graph.lock()?;
+        if *value::<T>(&state.values, s.id) != t {
+            state.values[s.id] = Box::new(t);
+            state.propagate(s.id);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self) -> Result<T, SignalError> {
+        self.0.get()
+    }
+
+    pub fn signal(&self) -> Signal<T> {
+        self.0.clone()
+    }
+}
+
+impl<T> Signal<T>
+where
+    T: PartialEq + Clone + Send + 'static,
+{
+    pub fn get(&self) -> Result<T, SignalError> {
+        let state = self.graph.lock()?;
+        Ok(value::<T>(&state.values, self.id).clone())
+    }
+
+    pub fn map<U, F>(&self, f: F) -> Result<Signal<U>, SignalError>
+    where
+        U: PartialEq + Clone + Send + 'static,
+        F: Fn(&T) -> U + Send + 'static,
+    {
+        let a = self.id;
+        let id = self.graph.node(
+            &[a],
+            |me, vs| {
+                let init = Box::new(f(value(vs, a)));
+                (init, Some(derive(me, move |vs| f(value(vs, a)))))
+            },
+            None,
+        )?;
+        Ok(self.graph.signal(id))
+    }
+
+    pub fn combine<U, V, F>(&self, other: &Signal<U>, f: F) -> Result<Signal<V>, SignalError>
+    where
+        U: PartialEq + Clone + Send + 'static,
+        V: PartialEq + Clone + Send + 'static,
+        F: Fn(&T, &U) -> V + Send + 'static,
+    {
+        self.graph.check(&other.graph)?;
+        let (a, b) = (self.id, other.id);
+        let id = self.graph.node(
+            &[a, b],
+            |me, vs| {
+                let init = Box::new(f(value(vs, a), value(vs, b)));
+                (
+                    init,
+                    Some(derive(me, move |vs| f(value(vs, a), value(vs, b)))),
+                )
+            },
+            None,
+        )?;
+        Ok(self.graph.signal(id))
+    }
+
+    // An occurrence carrying the new value whenever the signal changes.
+    pub fn changes(&self) -> Result<Events<T>, SignalError> {
+        let a = self.id;
+        let id = self.graph.node(
+            &[a],
+            |_, _| {
+                let compute = move |vs: &[Value]| fire(Some(value::<T>(vs, a).clone()));
+                (Box::new(None::<T>), Some(Box::new(compute)))
+            },
+            Some(clear::<T>()),
+        )?;
+        Ok(self.graph.events(id))
+    }
+
+    // Receive each value the signal settles on from now on.
+    // A slow subscriber skips to the latest value rather than queueing every one.
+    pub fn subscribe(&self) -> Result<Subscriber<T>, SignalError> {
+        self.graph.subscribe(self.id, true)
+    }
+}
+
+// A derived signal's compute, which only reports a value unequal to its last.
+fn derive<T, F>(me: usize, f: F) -> Compute
+where
+    T: PartialEq + Send + 'static,
+    F: Fn(&[Value]) -> T + Send + 'static,
+{
+    Box::new(move |vs: &[Value]| {
+        let v = f(vs);
+        match *value::<T>(vs, me) == v {
+            true => None,
+            false => Some(Box::new(v) as Value),
+        }
+    })
+}
+
+fn fire<T: Send + 'static>(t: Option<T>) -> Option<Value> {
+    t.map(|t| Box::new(Some(t)) as Value)
+}
+
+impl<T> EventSource<T>
+where
+    T: Clone + Send + 'static,
+{
+    // Emit an occurrence, updating everything derived from it before returning.
+    pub fn emit(&self, t: T) -> Result<(), SignalError> {
+        self.0.graph.update(self.0.id, Box::new(Some(t)))
+    }
+
+    pub fn events(&self) -> Events<T> {
+        self.0.clone()
+    }
+}
+
+impl<T> Events<T>
+where
+    T: Clone + Send + 'static,
+{
+    pub fn map<U, F>(&self, f: F) -> Result<Events<U>, SignalError>
+    where
+        U: Clone + Send + 'static,
+        F: Fn(&T) -> U + Send + 'static,
+    {
+        let a = self.id;
+        let id = self.graph.node(
+            &[a],
+            |_, _| {
+                let compute = move |vs: &[Value]| fire(value::<Option<T>>(vs, a).as_ref().map(&f));
+                (Box::new(None::<U>), Some(Box::new(compute)))
+            },
+            Some(clear::<U>()),
+        )?;
+        Ok(self.graph.events(id))
+    }
+
+    pub fn filter<F>(&self, f: F) -> Result<Events<T>, SignalError>
+    where
+        F: Fn(&T) -> bool + Send + 'static,
+    {
+        let a = self.id;
+        let id = self.graph.node(
+            &[a],
+            |_, _| {
+                let compute =
+                    move |vs: &[Value]| fire(value::<Option<T>>(vs, a).clone().filter(|t| f(t)));
+                (Box::new(None::<T>), Some(Box::new(compute)))
+            },
+            Some(clear::<T>()),
+        )?;
+        Ok(self.graph.events(id))
+    }
+
+    // A signal accumulating every occurrence from now on, starting from init.
+    pub fn fold<A, F>(&self, init: A, f: F) -> Result<Signal<A>, SignalError>
+    where
+        A: PartialEq + Clone + Send + 'static,
+        F: Fn(&A, &T) -> A + Send + 'static,
+    {
+        let a = self.id;
+        let id = self.graph.node(
+            &[a],
+            |me, _| {
+                let compute = move |vs: &[Value]| {
+                    let acc = value::<A>(vs, me);
+                    let next = value::<Option<T>>(vs, a).as_ref().map(|t| f(acc, t));
+                    next.filter(|n| n != acc).map(|n| Box::new(n) as Value)
+                };
+                (Box::new(init), Some(Box::new(compute)))
+            },
+            None,
+        )?;
+        Ok(self.graph.signal(id))
+    }
+
+    // Receive every occurrence from now on, queued until received.
+    pub fn subscribe(&self) -> Result<Subscriber<T>, SignalError> {
+        self.graph.subscribe(self.id, false)
+    }
+}
+
+// Receives values from a signal or event stream, blocking or awaiting them.
+pub struct Subscriber<T>(Arc<SubInner<T>>);
+
+struct SubInner<T> {
+    state: Mutex<SubState<T>>,
+    cv: Condvar,
+}
+
+struct SubState<T> {
+    queue: VecDeque<T>,
+    // Keep only the latest value, as for signals.
+    latest: bool,
+    closed: bool,
+    // Every task awaiting a value, as any number may await the same subscriber.
+    wakers: Vec<Waker>,
+}
+
+// Held by the graph. Dropping it, with the graph, closes the subscriber.
+struct SinkHandle<T>(Arc<SubInner<T>>);
+
+impl<T> SinkHandle<T> {
+    fn push(&self, t: T) {
+        if let Ok(mut state) = self.0.state.lock() {
+            if state.latest {
+                state.queue.clear();
+            }
+            state.queue.push_back(t);
+            for w in state.wakers.drain(..) {
+                w.wake();
+            }
+            self.0.cv.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for SinkHandle<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.state.lock() {
+            state.closed = true;
+            for w in state.wakers.drain(..) {
+                w.wake();
+            }
+            self.0.cv.notify_all();
+        }
+    }
+}
+
+impl<T> Subscriber<T> {
+    // Block until the next value, or SignalError::Closed once the graph has gone.
+    pub fn recv(&self) -> Result<T, SignalError> {
+        let mut state = self.0.state.lock().map_err(|_| SignalError::PoisonGuard)?;
+        loop {
+            if let Some(t) = state.queue.pop_front() {
+                return Ok(t);
+            }
+            if state.closed {
+                return Err(SignalError::Closed);
+            }
+            state = self
+                .0
+                .cv
+                .wait(state)
+                .map_err(|_| SignalError::PoisonGuard)?;
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<Option<T>, SignalError> {
+        let mut state = self.0.state.lock().map_err(|_| SignalError::PoisonGuard)?;
+        match state.queue.pop_front() {
+            Some(t) => Ok(Some(t)),
+            None => match state.closed {
+                true => Err(SignalError::Closed),
+                false => Ok(None),
+            },
+        }
+    }
+
+    // As recv, awaited on any executor.
+    pub fn recv_async(&self) -> Recv<'_, T> {
+        Recv(self)
+    }
+}
+
+pub struct Recv<'a, T>(&'a Subscriber<T>);
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Result<T, SignalError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = match (self.0).0.state.lock() {
+            Err(_) => return Poll::Ready(Err(SignalError::PoisonGuard)),
+            Ok(x) => x,
+        };
+        match state.queue.pop_front() {
+            Some(t) => Poll::Ready(Ok(t)),
+            None => match state.closed {
+                true => Poll::Ready(Err(SignalError::Closed)),
+                false => {
+                    if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                        state.wakers.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SignalError {
+    Closed,
+    ForeignSignal,
+    PoisonGuard,
+}
+
+impl fmt::Display for SignalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignalError::Closed => write!(f, "The signal graph has been dropped"),
+            SignalError::ForeignSignal => write!(f, "The signals belong to different graphs"),
+            SignalError::PoisonGuard => write!(f, "A signal guard was poisoned"),
+        }
+    }
+}
+
+impl Error for SignalError {
+    fn description(&self) -> &str {
+        match self {
+            SignalError::Closed => "The signal graph has been dropped",
+            SignalError::ForeignSignal => "The signals belong to different graphs",
+            SignalError::PoisonGuard => "A signal guard was poisoned",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_signal_glitch_free() {
+        let g = Graph::new();
+        let a = g.source(1).unwrap();
+
+        // A diamond: a feeds b and c, which both feed d. Each run of d is recorded.
+        let runs = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let b = a.signal().map(|x| x * 10).unwrap();
+        let c = a.signal().map(|x| x + 1).unwrap();
+        let (r, s) = (runs.clone(), seen.clone());
+        let d = b
+            .combine(&c, move |x, y| {
+                r.fetch_add(1, Ordering::SeqCst);
+                s.lock().unwrap().push((*x, *y));
+                x + y
+            })
+            .unwrap();
+        let sub = d.subscribe().unwrap();
+
+        a.set(2).unwrap();
+        assert_eq!(Ok(23), d.get());
+        // d ran once on creation and once for the change, never on a half-updated pair.
+        assert_eq!(2, runs.load(Ordering::SeqCst));
+        assert_eq!(vec![(10, 2), (20, 3)], *seen.lock().unwrap());
+        assert_eq!(Ok(23), sub.recv());
+
+        // Equal values stop propagating.
+        let parity = a.signal().map(|x| x % 2).unwrap();
+        let flips = parity.subscribe().unwrap();
+        a.set(4).unwrap();
+        assert_eq!(Ok(None), flips.try_recv());
+        a.set(5).unwrap();
+        assert_eq!(Ok(Some(1)), flips.try_recv());
+
+        let other = Graph::new().source(0).unwrap();
+        match a.signal().combine(&other.signal(), |x, y| x + y) {
+            Err(SignalError::ForeignSignal) => println!(),
+            _ => panic!("Combined signals from different graphs"),
+        }
+    }
+
+    #[test]
+    fn test_signal_events() {
+        let g = Graph::new();
+        let clicks = g.event_source::<i32>().unwrap();
+        let big = clicks.events().filter(|x| *x > 2).unwrap();
+        let total = big.fold(0, |acc, x| acc + x).unwrap();
+        let doubled = total.changes().unwrap().map(|x| x * 2).unwrap();
+        let sub = big.subscribe().unwrap();
+        let dsub = doubled.subscribe().unwrap();
+
+        let emitter = {
+            let clicks = clicks.clone();
+            thread::spawn(move || {
+                for x in 1..=5 {
+                    clicks.emit(x).unwrap();
+                }
+            })
+        };
+        // Events queue, every one delivered in order.
+        assert_eq!(Ok(3), block_on(sub.recv_async()));
+        assert_eq!(Ok(4), sub.recv());
+        assert_eq!(Ok(5), block_on(sub.recv_async()));
+        emitter.join().expect("Failed to Join Threads!");
+        assert_eq!(Ok(12), total.get());
+        assert_eq!(Ok(Some(6)), dsub.try_recv());
+
+        // Several tasks may await one subscriber, and each is woken.
+        let shared = Arc::new(clicks.events().subscribe().unwrap());
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let s = shared.clone();
+                thread::spawn(move || block_on(s.recv_async()))
+            })
+            .collect();
+        thread::sleep(std::time::Duration::from_millis(50));
+        clicks.emit(10).unwrap();
+        clicks.emit(20).unwrap();
+        let mut got: Vec<i32> = waiters
+            .into_iter()
+            .map(|w| w.join().expect("Failed to Join Threads!").unwrap())
+            .collect();
+        got.sort();
+        assert_eq!(vec![10, 20], got);
+        assert_eq!(Ok(Some(10)), sub.try_recv());
+        assert_eq!(Ok(Some(20)), sub.try_recv());
+
+        // Dropping the graph closes its subscribers.
+        drop((g, clicks, big, total, doubled));
+        assert_eq!(Ok(Some(14)), dsub.try_recv());
+        assert_eq!(Ok(Some(24)), dsub.try_recv());
+        assert_eq!(Ok(Some(44)), dsub.try_recv());
+        assert_eq!(Ok(Some(84)), dsub.try_recv());
+        assert_eq!(Err(SignalError::Closed), dsub.recv());
+    }
+
+    #[test]
+    fn test_signal_once_cell() {
+        let g = Graph::new();
+        let mut cell = OnceCell::new();
+        let s = g.once_cell(&mut cell).unwrap();
+        let label = s.map(|v| format!("{:?}", v)).unwrap();
+        let sub = label.subscribe().unwrap();
+        assert_eq!(Ok(None), s.get());
+
+        let mut writer = cell.clone();
+        let t = thread::spawn(move || writer.write(7).unwrap());
+        assert_eq!(Ok("Some(7)".to_string()), block_on(sub.recv_async()));
+        t.join().expect("Failed to Join Threads!");
+        assert_eq!(Ok(Some(7)), s.get());
+    }
+}
@@ -0,0 +1,30 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+// The smallest executor, for awaiting in tests: poll on this thread, and park until woken.
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// A waker which unparks this thread, for polling by hand.
+pub(crate) fn waker() -> Waker {
+    Waker::from(Arc::new(Unpark(thread::current())))
+}
+
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    let waker = waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut f = Box::pin(f);
+    loop {
+        match f.as_mut().poll(&mut cx) {
+            Poll::Ready(x) => return x,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
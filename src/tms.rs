@@ -0,0 +1,416 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::lattice::{Lattice, LatticeError};
+use crate::propagator::{Cell, Network, PropagatorError};
+
+// Truth maintenance for propagator networks, after Radul's thesis.
+// Every piece of information is told to a cell along with the premises it rests on,
+// and everything derived from it carries the union of the premises it was derived from.
+// Cells keep all of it, whatever is currently believed, so the network never has to
+// run again when beliefs change: kicking a premise out, or bringing it back in, only
+// changes which of the stored values a query joins together.
+// A query whose believed values conflict reports a nogood: a set of premises which cannot
+// all be believed at once, shrunk until dropping any one of them removes the conflict.
+
+// An assumption that may be believed or not. Premises start out believed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Premise(usize);
+
+// A value, and the premises it holds under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Supported<L> {
+    pub value: L,
+    pub support: BTreeSet<Premise>,
+}
+
+// Everything a cell has been told, under every set of premises.
+// Joining only adds values, and never conflicts: conflicts are found when querying.
+// A value is dropped when another at least as informative needs no more premises.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Supports<L>(Vec<Supported<L>>);
+
+impl<L: Lattice + Clone> Supports<L> {
+    fn insert(&mut self, s: &Supported<L>) -> bool {
+        let subsumed = self
+            .0
+            .iter()
+            .any(|t| s.value.leq(&t.value) && t.support.is_subset(&s.support));
+        if subsumed {
+            return false;
+        }
+        self.0
+            .retain(|t| !(t.value.leq(&s.value) && s.support.is_subset(&t.support)));
+        self.0.push(s.clone());
+        true
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Supported<L>> {
+        self.0.iter()
+    }
+}
+
+impl<L: Lattice + Clone> Lattice for Supports<L> {
+    fn join(&mut self, other: &Self) -> Result<bool, LatticeError> {
+        let mut grew = false;
+        for s in other.0.iter() {
+            grew |= self.insert(s);
+        }
+        Ok(grew)
+    }
+}
+
+// A propagator cell holding supported values.
+pub struct SupportedCell<L>(Cell<Supports<L>>);
+
+impl<L> Clone for SupportedCell<L> {
+    fn clone(&self) -> SupportedCell<L> {
+        *self
+    }
+}
+
+impl<L> Copy for SupportedCell<L> {}
+
+impl<L> SupportedCell<L> {
+    pub fn cell(&self) -> Cell<Supports<L>> {
+        self.0
+    }
+}
+
+// A network together with the premises its values rest on, and which of them are believed.
+pub struct Tms {
+    net: Network,
+    names: Arc<RwLock<Vec<String>>>,
+    believed: Arc<RwLock<BTreeSet<Premise>>>,
+    nogoods: Arc<Mutex<Vec<BTreeSet<Premise>>>>,
+}
+
+impl Clone for Tms {
+    fn clone(&self) -> Tms {
+        Tms {
+            net: self.net.clone(),
+            names: self.names.clone(),
+            believed: self.believed.clone(),
+            nogoods: self.nogoods.clone(),
+        }
+    }
+}
+
+impl Default for Tms {
+    fn default() -> Tms {
+        Tms::new()
+    }
+}
+
+impl Tms {
+    pub fn new() -> Tms {
+        Tms::with_network(Network::new())
+    }
+
+    pub fn with_network(net: Network) -> Tms {
+        Tms {
+            net,
+            names: Arc::new(RwLock::new(Vec::new())),
+            believed: Arc::new(RwLock::new(BTreeSet::new())),
+            nogoods: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.net
+    }
+
+    // A new premise, believed.
+    pub fn premise(&self, name: &str) -> Result<Premise, TmsError> {
+        let mut names = self.names.write().map_err(|_| TmsError::PoisonGuard)?;
+        names.push(name.to_string());
+        let p = Premise(names.len() - 1);
+        self.believed
+            .write()
+            .map_err(|_| TmsError::PoisonGuard)?
+            .insert(p);
+        Ok(p)
+    }
+
+    pub fn name(&self, p: Premise) -> Result<String, TmsError> {
+        let names = self.names.read().map_err(|_| TmsError::PoisonGuard)?;
+        Ok(names.get(p.0).cloned().unwrap_or_default())
+    }
+
+    pub fn bring_in(&self, p: Premise) -> Result<(), TmsError> {
+        let mut believed = self.believed.write().map_err(|_| TmsError::PoisonGuard)?;
+        believed.insert(p);
+        Ok(())
+    }
+
+    pub fn kick_out(&self, p: Premise) -> Result<(), TmsError> {
+        let mut believed = self.believed.write().map_err(|_| TmsError::PoisonGuard)?;
+        believed.remove(&p);
+        Ok(())
+    }
+
+    pub fn is_in(&self, p: Premise) -> Result<bool, TmsError> {
+        let believed = self.believed.read().map_err(|_| TmsError::PoisonGuard)?;
+        Ok(believed.contains(&p))
+    }
+
+    pub fn cell<L>(&self) -> SupportedCell<L>
+    where
+        L: Lattice + Clone + Send + Sync + 'static,
+    {
+        SupportedCell(self.net.cell())
+    }
+
+    // Tell the cell l, holding whenever every premise in support is believed.
+    pub fn tell<L>(&self, c: &SupportedCell<L>, l: L, support: &[Premise]) -> Result<(), TmsError>
+    where
+        L: Lattice + Clone + Send + Sync + 'static,
+    {
+        let s = Supported {
+            value: l,
+            support: support.iter().cloned().collect(),
+        };
+        self.net
+            .add(&c.0, Supports(vec![s]))
+            .map_err(TmsError::Propagator)
+    }
+
+    // Propagate f from a to out, for every value of a.
+    pub fn lift1<A, B, F>(
+        &self,
+        a: &SupportedCell<A>,
+        out: &SupportedCell<B>,
+        f: F,
+    ) -> Result<(), TmsError>
+    where
+        A: Lattice + Clone + Send + Sync + 'static,
+        B: Lattice + Clone + Send + Sync + 'static,
+        F: Fn(&A) -> B + Send + Sync + 'static,
+    {
+        self.net
+            .lift1(&a.0, &out.0, move |xs| {
+                Supports(
+                    xs.0.iter()
+                        .map(|x| Supported {
+                            value: f(&x.value),
+                            support: x.support.clone(),
+                        })
+                        .collect(),
+                )
+            })
+            .map_err(TmsError::Propagator)
+    }
+
+    // Propagate f from a and b to out, for every pair of their values,
+    // each result resting on the premises of both.
+    pub fn lift2<A, B, C, F>(
+        &self,
+        a: &SupportedCell<A>,
+        b: &SupportedCell<B>,
+        out: &SupportedCell<C>,
+        f: F,
+    ) -> Result<(), TmsError>
+    where
+        A: Lattice + Clone + Send + Sync + 'static,
+        B: Lattice + Clone + Send + Sync + 'static,
+        C: Lattice + Clone + Send + Sync + 'static,
+        F: Fn(&A, &B) -> C + Send + Sync + 'static,
+    {
+        self.net
+            .lift2(&a.0, &b.0, &out.0, move |xs, ys| {
+                let mut out = Supports(Vec::new());
+                for x in xs.0.iter() {
+                    for y in ys.0.iter() {
+                        out.insert(&Supported {
+                            value: f(&x.value, &y.value),
+                            support: x.support.union(&y.support).cloned().collect(),
+                        });
+                    }
+                }
+                out
+            })
+            .map_err(TmsError::Propagator)
+    }
+
+    pub fn run(&self) -> Result<(), TmsError> {
+        self.net.run().map_err(TmsError::Propagator)
+    }
+
+    // What the cell holds under the premises believed now, and the premises that rests on.
+    // None if nothing believed is known. If the believed values conflict the minimal nogood
+    // is recorded and returned as the error.
+    pub fn belief<L>(&self, c: &SupportedCell<L>) -> Result<Option<Supported<L>>, TmsError>
+    where
+        L: Lattice + Clone + Send + Sync + 'static,
+    {
+        let all = match self.net.content(&c.0).map_err(TmsError::Propagator)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let believed = self
+            .believed
+            .read()
+            .map_err(|_| TmsError::PoisonGuard)?
+            .clone();
+
+        match join_under(&all, &believed) {
+            Ok(x) => Ok(x),
+            Err(_) => {
+                let nogood = minimise(&all, &believed);
+                if let Ok(mut nogoods) = self.nogoods.lock() {
+                    if !nogoods.contains(&nogood) {
+                        nogoods.push(nogood.clone());
+                    }
+                }
+                Err(TmsError::Nogood(nogood.into_iter().collect()))
+            }
+        }
+    }
+
+    // Every nogood found so far.
+    pub fn nogoods(&self) -> Result<Vec<Vec<Premise>>, TmsError> {
+        let nogoods = self.nogoods.lock().map_err(|_| TmsError::PoisonGuard)?;
+        Ok(nogoods
+            .iter()
+            .map(|n| n.iter().cloned().collect())
+            .collect())
+    }
+}
+
+// The join of every value whose premises are all in world.
+fn join_under<L: Lattice + Clone>(
+    all: &Supports<L>,
+    world: &BTreeSet<Premise>,
+) -> Result<Option<Supported<L>>, LatticeError> {
+    let mut acc: Option<Supported<L>> = None;
+    for s in all.0.iter().filter(|s| s.support.is_subset(world)) {
+        match acc.as_mut() {
+            None => acc = Some(s.clone()),
+            Some(a) => {
+                if a.value.join(&s.value)? {
+                    a.support.extend(s.support.iter().cloned());
+                }
+            }
+        }
+    }
+    Ok(acc)
+}
+
+// Drop premises one at a time, keeping each drop that leaves a conflict.
+fn minimise<L: Lattice + Clone>(all: &Supports<L>, world: &BTreeSet<Premise>) -> BTreeSet<Premise> {
+    let mut nogood: BTreeSet<Premise> = all
+        .0
+        .iter()
+        .filter(|s| s.support.is_subset(world))
+        .flat_map(|s| s.support.iter().cloned())
+        .collect();
+    let candidates: Vec<Premise> = nogood.iter().cloned().collect();
+    for p in candidates {
+        let mut smaller = nogood.clone();
+        smaller.remove(&p);
+        if join_under(all, &smaller).is_err() {
+            nogood = smaller;
+        }
+    }
+    nogood
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TmsError {
+    Nogood(Vec<Premise>),
+    Propagator(PropagatorError),
+    PoisonGuard,
+}
+
+impl fmt::Display for TmsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TmsError::Nogood(ps) => {
+                let ids: Vec<String> = ps.iter().map(|p| p.0.to_string()).collect();
+                write!(f, "Premises {{{}}} cannot all be believed", ids.join(", "))
+            }
+            TmsError::Propagator(err) => write!(f, "{}", err),
+            TmsError::PoisonGuard => write!(f, "A premise guard was poisoned"),
+        }
+    }
+}
+
+impl Error for TmsError {
+    fn description(&self) -> &str {
+        match self {
+            TmsError::Nogood(_) => "Some premises cannot all be believed",
+            TmsError::Propagator(_) => "The underlying network failed",
+            TmsError::PoisonGuard => "A premise guard was poisoned",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::Interval;
+
+    #[test]
+    fn test_tms_retraction() {
+        let tms = Tms::new();
+        let [a, b, c] = ["a", "b", "c"].map(|n| tms.premise(n).unwrap());
+        let x = tms.cell::<Interval>();
+        tms.tell(&x, Interval::new(0.0, 10.0), &[a]).unwrap();
+        tms.tell(&x, Interval::new(5.0, 15.0), &[b]).unwrap();
+        tms.tell(&x, Interval::new(20.0, 30.0), &[c]).unwrap();
+        tms.run().unwrap();
+
+        // c disagrees with both, but one of them suffices for a minimal nogood.
+        assert_eq!(Err(TmsError::Nogood(vec![b, c])), tms.belief(&x));
+
+        tms.kick_out(c).unwrap();
+        let fused = tms.belief(&x).unwrap().unwrap();
+        assert_eq!(Interval::new(5.0, 10.0), fused.value);
+        assert_eq!(vec![a, b], fused.support.into_iter().collect::<Vec<_>>());
+
+        tms.bring_in(c).unwrap();
+        tms.kick_out(b).unwrap();
+        assert_eq!(Err(TmsError::Nogood(vec![a, c])), tms.belief(&x));
+        assert_eq!(2, tms.nogoods().unwrap().len());
+
+        tms.kick_out(a).unwrap();
+        let alone = tms.belief(&x).unwrap().unwrap();
+        assert_eq!(Interval::new(20.0, 30.0), alone.value);
+        assert!(!tms.is_in(a).unwrap());
+        assert_eq!("c", tms.name(c).unwrap());
+    }
+
+    #[test]
+    fn test_tms_derived() {
+        // z = x + y, where x and y rest on a and b, and d says something else of z.
+        let tms = Tms::new();
+        let [a, b, d, e] = ["a", "b", "d", "e"].map(|n| tms.premise(n).unwrap());
+        let (x, y, z) = (tms.cell(), tms.cell(), tms.cell());
+        tms.lift2(&x, &y, &z, |p: &Interval, q: &Interval| *p + *q)
+            .unwrap();
+        tms.tell(&x, Interval::new(1.0, 2.0), &[a]).unwrap();
+        tms.tell(&y, Interval::new(3.0, 4.0), &[b]).unwrap();
+        tms.tell(&z, Interval::new(10.0, 11.0), &[d]).unwrap();
+        // An unrelated value, which the nogood should leave out.
+        tms.tell(&z, Interval::new(0.0, 100.0), &[e]).unwrap();
+        tms.run().unwrap();
+
+        assert_eq!(Err(TmsError::Nogood(vec![a, b, d])), tms.belief(&z));
+
+        // Retracting any one of them resolves it, without running the network again.
+        tms.kick_out(a).unwrap();
+        let zs = tms.belief(&z).unwrap().unwrap();
+        assert_eq!(Interval::new(10.0, 11.0), zs.value);
+        tms.bring_in(a).unwrap();
+        tms.kick_out(d).unwrap();
+        let zs = tms.belief(&z).unwrap().unwrap();
+        assert!(zs.value.contains(4.0) && zs.value.contains(6.0));
+        assert!(zs.support.contains(&a) && zs.support.contains(&b));
+    }
+}